@@ -0,0 +1,280 @@
+//! Configuration file.
+//!
+//! Settings that are unwieldy on the command line can live in a TOML file
+//! passed with `--config <path>`, or `--config -` to read it from stdin.
+//! Without `--config`, `~/.config/autotiling-rs/config.toml` is used if it
+//! exists:
+//!
+//! ```toml
+//! enable_balance = true
+//! master_percent = 0.6
+//! # Same syntax as --split-expr
+//! split_expr = "splitv if height > 1.3 * width else splith"
+//!
+//! [master_apps]
+//! firefox = 0.65
+//! emacs = 0.55
+//!
+//! # Apps that always split the same way, whatever their shape
+//! [app_split]
+//! foot = "splitv"
+//!
+//! # Overrides for workspace 2: no master sizing, no balancing
+//! [workspace.2]
+//! layout = "autotile"
+//! enable_balance = false
+//!
+//! # Same as --master-rule / --exclude-rule
+//! [[rules]]
+//! match = "class=firefox,output=DP-1"
+//! action = "master"
+//! ```
+//!
+//! Master shares, global or per app, are fractions between 0.5 and 0.7 or the
+//! same as percentages (`65` for 0.65); anything else is an error.
+//!
+//! A `[workspace.<num>]` section may set `enable_balance`, its own
+//! `master_apps` (replacing the global ones there) and a `layout`:
+//! `master-stack` sizes masters as configured, `autotile` only splits. Keys
+//! it leaves out fall back to the global settings.
+//!
+//! Flags given on the command line (or through the environment) win over the
+//! file. On SIGHUP the file is read again; if it no longer parses, the
+//! daemon keeps running with its previous configuration.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::expr::SplitExpr;
+use crate::rules::MatchRule;
+use crate::{master_share, normalize_app_name};
+
+/// Settings read from a configuration file. Unset keys are `None`/empty.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub enable_balance: Option<bool>,
+    pub master_percent: Option<f32>,
+    /// Replaces the built-in split rule, see `expr`
+    pub split_expr: Option<SplitExpr>,
+    /// Master share per app, by class or app_id
+    #[serde(default)]
+    pub master_apps: HashMap<String, f32>,
+    /// Split direction per app, by class or app_id
+    #[serde(default)]
+    pub app_split: HashMap<String, SplitDirection>,
+    /// Overrides by workspace number
+    #[serde(default)]
+    pub workspace: HashMap<i32, WorkspaceOverride>,
+    /// Match rules, added to those given on the command line
+    #[serde(default)]
+    pub rules: Vec<RuleEntry>,
+}
+
+/// One `[[rules]]` entry
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleEntry {
+    /// Conditions in the `--master-rule` syntax
+    #[serde(rename = "match")]
+    pub rule: MatchRule,
+    pub action: RuleAction,
+}
+
+/// What a `[[rules]]` entry does to the windows it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Treat them as masters
+    Master,
+    /// Never autotile them
+    Exclude,
+}
+
+/// A split an app is always given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitDirection {
+    Splith,
+    Splitv,
+}
+
+impl SplitDirection {
+    /// The sway command setting this split
+    pub fn command(self) -> &'static str {
+        match self {
+            SplitDirection::Splith => "splith",
+            SplitDirection::Splitv => "splitv",
+        }
+    }
+}
+
+impl FromStr for SplitDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "splith" => Ok(SplitDirection::Splith),
+            "splitv" => Ok(SplitDirection::Splitv),
+            other => Err(format!("unknown split '{}', expected splith or splitv", other)),
+        }
+    }
+}
+
+/// How a workspace with an override is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkspaceLayout {
+    /// Splits and master sizing, as everywhere else
+    MasterStack,
+    /// Splits only: no window is sized as a master
+    Autotile,
+}
+
+/// Settings of one `[workspace.<num>]` section
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkspaceOverride {
+    pub enable_balance: Option<bool>,
+    /// Master share per app on this workspace, instead of the global list
+    pub master_apps: Option<HashMap<String, f32>>,
+    pub layout: Option<WorkspaceLayout>,
+}
+
+/// Check every share of an app table, naming the app of a bad one
+fn validate_shares(apps: &mut HashMap<String, f32>) -> Result<()> {
+    for (app, pct) in apps.iter_mut() {
+        *pct = master_share(*pct).map_err(|err| anyhow!("{} for '{}'", err, app))?;
+    }
+    Ok(())
+}
+
+/// Keys of an app table in their canonical form
+fn normalize_apps<T>(apps: HashMap<String, T>) -> HashMap<String, T> {
+    apps.into_iter()
+        .map(|(app, pct)| (normalize_app_name(&app), pct))
+        .collect()
+}
+
+impl FileConfig {
+    /// Parse a configuration from any reader
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        let mut text = String::new();
+        reader
+            .read_to_string(&mut text)
+            .context("Failed to read configuration")?;
+        Self::parse(&text)
+    }
+
+    /// Parse and check the text of a configuration file
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut config: FileConfig = toml::from_str(text).context("Invalid configuration")?;
+        config.master_apps = normalize_apps(config.master_apps);
+        config.app_split = normalize_apps(config.app_split);
+        for over in config.workspace.values_mut() {
+            over.master_apps = over.master_apps.take().map(normalize_apps);
+        }
+        if let Some(pct) = config.master_percent {
+            config.master_percent = Some(master_share(pct).map_err(|err| anyhow!(err))?);
+        }
+        validate_shares(&mut config.master_apps)?;
+        for apps in config.workspace.values_mut().filter_map(|o| o.master_apps.as_mut()) {
+            validate_shares(apps)?;
+        }
+        Ok(config)
+    }
+
+    /// Load `path` if given, else the default file if there is one. A file that
+    /// was asked for but can't be read is an error, never silently skipped.
+    pub fn discover(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::load(path),
+            None => match default_path().filter(|p| p.is_file()) {
+                Some(path) => Self::load(&path),
+                None => Ok(Self::default()),
+            },
+        }
+    }
+
+    /// Load the configuration at `path`, where `-` stands for stdin
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::load_or_read(path, io::stdin().lock())
+    }
+
+    /// `load`, reading `stdin` when `path` is `-`
+    pub fn load_or_read<R: Read>(path: &Path, stdin: R) -> Result<Self> {
+        if path == Path::new("-") {
+            return Self::from_reader(stdin).context("Failed to load config from stdin");
+        }
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open config file {}", path.display()))?;
+        Self::from_reader(file).with_context(|| format!("Failed to load {}", path.display()))
+    }
+}
+
+/// `$XDG_CONFIG_HOME/autotiling-rs/config.toml`, with `~/.config` when
+/// `XDG_CONFIG_HOME` is unset
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("autotiling-rs").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The whole chain of `err`, as the daemon would log it
+    fn message(err: anyhow::Error) -> String {
+        format!("{:#}", err)
+    }
+
+    #[test]
+    fn settings_are_parsed_and_normalized() {
+        let config = FileConfig::parse(
+            "enable_balance = false\nmaster_percent = 65\n\
+             [master_apps]\n\"Firefox \" = 0.55\n\
+             [workspace.2]\nlayout = \"autotile\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.enable_balance, Some(false));
+        assert_eq!(config.master_percent, Some(0.65));
+        assert_eq!(config.master_apps["firefox"], 0.55);
+        assert_eq!(config.workspace[&2].layout, Some(WorkspaceLayout::Autotile));
+        assert!(FileConfig::parse("").unwrap().master_apps.is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let err = message(FileConfig::parse("master_precent = 0.6").unwrap_err());
+        assert!(err.contains("unknown field `master_precent`"), "{}", err);
+        let err = message(FileConfig::parse("[workspace.2]\nbalance = true").unwrap_err());
+        assert!(err.contains("unknown field `balance`"), "{}", err);
+        let rule = "[[rules]]\nmatch = \"class=foot\"\naction = \"master\"\nwhen = 1";
+        let err = message(FileConfig::parse(rule).unwrap_err());
+        assert!(err.contains("unknown field `when`"), "{}", err);
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        let err = message(FileConfig::parse("[master_apps]\nfirefox = 0.2").unwrap_err());
+        assert!(err.contains("'firefox'"), "{}", err);
+        assert!(FileConfig::parse("master_percent = 0.9").is_err());
+        assert!(FileConfig::parse("[app_split]\nfoot = \"diagonal\"").is_err());
+        assert!(FileConfig::parse("enable_balance = ").is_err());
+    }
+
+    #[test]
+    fn a_missing_file_is_named_in_the_error() {
+        let err = message(FileConfig::load(Path::new("/nonexistent/config.toml")).unwrap_err());
+        assert!(err.contains("/nonexistent/config.toml"), "{}", err);
+    }
+}
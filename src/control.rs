@@ -0,0 +1,435 @@
+//! Control socket: lets keybinds and scripts send commands to the running daemon.
+//!
+//! The socket lives at `$XDG_RUNTIME_DIR/autotiling-rs.sock` (the system's
+//! temporary directory when `XDG_RUNTIME_DIR` is unset) and is removed again
+//! when the daemon shuts down. Clients write one command per line and get
+//! exactly one reply line back, either `ok ...` or `error: ...`; a malformed
+//! line only earns an error reply. For example:
+//!
+//! ```text
+//! echo promote | socat - UNIX-CONNECT:$XDG_RUNTIME_DIR/autotiling-rs.sock
+//! ```
+//!
+//! Commands, surrounding whitespace ignored:
+//!
+//! ```text
+//! promote    make the focused window master, whatever its app
+//! demote     return the focused window to normal tiling
+//! tidy       even out the focused workspace, then size its masters again
+//! rotate     cycle the stack next to the focused workspace's master
+//! swap-master
+//!            trade places between that master and the top of its stack
+//! focus-master
+//!            focus the first master window of the focused workspace
+//! next-split splith|splitv|auto
+//!            force the next split decision (a focus change first cancels
+//!            it), or go back to deciding automatically
+//! stats      counters of this session, as a JSON object after `ok`
+//! subscribe  stream layout changes, see below
+//! ```
+//!
+//! A client that sends `subscribe` gets `ok subscribed` and from then on one
+//! JSON line per layout change the daemon makes, until it disconnects:
+//!
+//! ```text
+//! {"con_id":42,"old":"splith","new":"splitv","reason":"focus"}
+//! ```
+//!
+//! Every client is served on its own, so a subscriber never holds up other
+//! commands; one that stops reading its stream is dropped.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use serde_json::json;
+
+/// Log target for the control socket
+pub const LOG_TARGET: &str = "autotile::control";
+
+/// How long a client waits for the event loop to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a layout change may take to reach a subscriber before it is
+/// dropped, so one that stops reading can't hold up the event loop
+const SUBSCRIBER_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A command understood by the control socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Treat the focused window as master for the rest of its lifetime
+    Promote,
+    /// Return the focused window to normal tiling
+    Demote,
+    /// Even out the focused workspace, then re-apply master sizing on it
+    Tidy,
+    /// Move every stack window one position up, the top one to the bottom
+    Rotate,
+    /// Exchange the master with the top stack window
+    SwapMaster,
+    /// Focus the first master window of the focused workspace
+    FocusMaster,
+    /// Split the focused container with this layout once; `None` cancels
+    NextSplit(Option<&'static str>),
+    /// Report what the daemon did so far
+    Stats,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "promote" => Ok(ControlCommand::Promote),
+            "demote" => Ok(ControlCommand::Demote),
+            "tidy" => Ok(ControlCommand::Tidy),
+            "rotate" => Ok(ControlCommand::Rotate),
+            "swap-master" => Ok(ControlCommand::SwapMaster),
+            "focus-master" => Ok(ControlCommand::FocusMaster),
+            "stats" => Ok(ControlCommand::Stats),
+            "" => Err("empty command".to_string()),
+            other => match other.split_once(char::is_whitespace) {
+                Some(("next-split", layout)) => match layout.trim() {
+                    "splith" => Ok(ControlCommand::NextSplit(Some("splith"))),
+                    "splitv" => Ok(ControlCommand::NextSplit(Some("splitv"))),
+                    "auto" => Ok(ControlCommand::NextSplit(None)),
+                    layout => Err(format!(
+                        "unknown split '{}', expected splith, splitv or auto",
+                        layout
+                    )),
+                },
+                _ if other == "next-split" => Err("next-split needs a split".to_string()),
+                _ => Err(format!("unknown command '{}'", other)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ControlCommand::Promote => "promote",
+            ControlCommand::Demote => "demote",
+            ControlCommand::Tidy => "tidy",
+            ControlCommand::Rotate => "rotate",
+            ControlCommand::SwapMaster => "swap-master",
+            ControlCommand::FocusMaster => "focus-master",
+            ControlCommand::Stats => "stats",
+            ControlCommand::NextSplit(layout) => {
+                return write!(f, "next-split {}", layout.unwrap_or("auto"))
+            }
+        };
+        f.write_str(name)
+    }
+}
+
+/// A parsed command waiting for the event loop, with the channel to answer on
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub reply: Sender<String>,
+}
+
+/// A layout change made by the daemon, as streamed to subscribers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutEvent {
+    pub con_id: i64,
+    /// Layout before the change, when known
+    pub old: Option<String>,
+    pub new: String,
+    /// What prompted the change, e.g. `focus` or `resweep`
+    pub reason: String,
+}
+
+impl LayoutEvent {
+    /// The JSON line sent to subscribers
+    pub fn to_json(&self) -> String {
+        json!({
+            "con_id": self.con_id,
+            "old": self.old,
+            "new": self.new,
+            "reason": self.reason,
+        })
+        .to_string()
+    }
+}
+
+/// Clients that subscribed to layout changes. Cloning shares the list, so the
+/// event loop can publish to clients the listener thread accepted.
+#[derive(Debug, Clone, Default)]
+pub struct Subscribers {
+    clients: Arc<Mutex<Clients>>,
+    next_id: Arc<AtomicU64>,
+}
+
+type Clients = Vec<(u64, UnixStream)>;
+
+impl Subscribers {
+    /// The client list. A thread that panicked while holding it can't have
+    /// left it half-changed, so a poisoned lock is taken over as is.
+    fn clients(&self) -> MutexGuard<'_, Clients> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn add(&self, stream: UnixStream) -> u64 {
+        if let Err(err) = stream.set_write_timeout(Some(SUBSCRIBER_WRITE_TIMEOUT)) {
+            debug!(target: LOG_TARGET, "Failed to set subscriber write timeout: {}", err);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.clients().push((id, stream));
+        id
+    }
+
+    fn remove(&self, id: u64) {
+        self.clients().retain(|(client, _)| *client != id);
+    }
+
+    /// Send `event` to every subscriber, dropping those that went away or
+    /// stopped reading
+    pub fn publish(&self, event: &LayoutEvent) {
+        let mut clients = self.clients();
+        if clients.is_empty() {
+            return;
+        }
+        let line = event.to_json();
+        clients.retain_mut(|(id, stream)| match writeln!(stream, "{}", line) {
+            Ok(()) => true,
+            Err(err) => {
+                debug!(target: LOG_TARGET, "Dropping subscriber {}: {}", id, err);
+                false
+            }
+        });
+    }
+}
+
+/// Path of the control socket
+pub fn socket_path() -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    dir.join("autotiling-rs.sock")
+}
+
+/// Bind the control socket and serve it on a background thread.
+/// Every parsed request is handed to `forward` for the event loop to execute;
+/// clients that subscribe are added to `subscribers`.
+pub fn spawn_listener<F>(forward: F, subscribers: Subscribers) -> Result<PathBuf>
+where
+    F: Fn(ControlRequest) -> bool + Send + Sync + 'static,
+{
+    let path = socket_path();
+    // A previous instance that died without cleaning up leaves the file behind
+    if path.exists() {
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    info!(target: LOG_TARGET, "Control socket listening on {}", path.display());
+    serve(listener, forward, subscribers);
+    Ok(path)
+}
+
+/// Accept clients of `listener` on a background thread, each served on a
+/// thread of its own so a subscriber or an idle client never holds up others
+fn serve<F>(listener: UnixListener, forward: F, subscribers: Subscribers)
+where
+    F: Fn(ControlRequest) -> bool + Send + Sync + 'static,
+{
+    let forward = Arc::new(forward);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!(target: LOG_TARGET, "Control socket accept failed: {}", err);
+                    continue;
+                }
+            };
+            let forward = Arc::clone(&forward);
+            let subscribers = subscribers.clone();
+            thread::spawn(move || {
+                if let Err(err) = serve_client(stream, &*forward, &subscribers) {
+                    debug!(target: LOG_TARGET, "Control client error: {}", err);
+                }
+            });
+        }
+    });
+}
+
+/// Remove the socket file on shutdown. Clients still connected keep their
+/// stream until the process exits.
+pub fn remove_socket(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => debug!(target: LOG_TARGET, "Removed control socket {}", path.display()),
+        Err(err) => error!(target: LOG_TARGET, "Failed to remove {}: {}", path.display(), err),
+    }
+}
+
+/// Answer every command line of a single client until it hangs up
+fn serve_client<F>(stream: UnixStream, forward: &F, subscribers: &Subscribers) -> Result<()>
+where
+    F: Fn(ControlRequest) -> bool,
+{
+    let mut writer = stream.try_clone()?;
+    let mut subscription = None;
+    let mut result = Ok(());
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                result = Err(err.into());
+                break;
+            }
+        };
+        let response = if line.trim() == "subscribe" {
+            if subscription.is_none() {
+                writeln!(writer, "ok subscribed")?;
+                subscription = Some(subscribers.add(writer.try_clone()?));
+                continue;
+            }
+            "ok already subscribed".to_string()
+        } else {
+            match line.parse::<ControlCommand>() {
+                Ok(command) => dispatch(command, forward),
+                Err(err) => format!("error: {}", err),
+            }
+        };
+        if let Err(err) = writeln!(writer, "{}", response) {
+            result = Err(err.into());
+            break;
+        }
+    }
+    if let Some(id) = subscription {
+        subscribers.remove(id);
+        debug!(target: LOG_TARGET, "Subscriber {} disconnected", id);
+    }
+    result
+}
+
+/// Hand a command to the event loop and wait for its answer
+fn dispatch<F>(command: ControlCommand, forward: &F) -> String
+where
+    F: Fn(ControlRequest) -> bool,
+{
+    let (reply, answer) = mpsc::channel();
+    if !forward(ControlRequest { command, reply }) {
+        return "error: daemon is shutting down".to_string();
+    }
+    answer
+        .recv_timeout(REPLY_TIMEOUT)
+        .unwrap_or_else(|_| format!("error: no reply to '{}'", command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(con_id: i64) -> LayoutEvent {
+        LayoutEvent {
+            con_id,
+            old: Some("splith".into()),
+            new: "splitv".into(),
+            reason: "focus".into(),
+        }
+    }
+
+    fn read_line(stream: &UnixStream) -> String {
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        line
+    }
+
+    #[test]
+    fn layout_changes_reach_every_subscriber() {
+        let subscribers = Subscribers::default();
+        let (first, first_peer) = UnixStream::pair().unwrap();
+        let (second, second_peer) = UnixStream::pair().unwrap();
+        subscribers.add(first);
+        let second_id = subscribers.add(second);
+
+        subscribers.publish(&change(10));
+        let expected = r#"{"con_id":10,"new":"splitv","old":"splith","reason":"focus"}"#;
+        assert_eq!(read_line(&first_peer).trim_end(), expected);
+        assert_eq!(read_line(&second_peer).trim_end(), expected);
+
+        // A subscriber that went away is dropped on the next publish
+        drop(first_peer);
+        subscribers.publish(&change(11));
+        assert_eq!(subscribers.clients().len(), 1);
+        assert!(read_line(&second_peer).contains("\"con_id\":11"));
+        subscribers.remove(second_id);
+        assert!(subscribers.clients().is_empty());
+    }
+
+    #[test]
+    fn subscribers_that_stop_reading_are_dropped() {
+        let subscribers = Subscribers::default();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        subscribers.add(stream);
+        // Nobody reads `_peer`, so its buffer fills and a write times out
+        let mut published = 0;
+        while !subscribers.clients().is_empty() {
+            subscribers.publish(&change(published));
+            published += 1;
+            assert!(published < 1_000_000, "subscriber never dropped");
+        }
+    }
+
+    #[test]
+    fn commands_are_answered_while_a_subscriber_is_connected() {
+        let name = format!("autotiling-test-{}.sock", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let forward = |req: ControlRequest| req.reply.send(format!("ok {}", req.command)).is_ok();
+        let subscribers = Subscribers::default();
+        serve(listener, forward, subscribers.clone());
+
+        // A client that never sends anything, and a subscriber that stays
+        let _idle = UnixStream::connect(&path).unwrap();
+        let mut subscriber = UnixStream::connect(&path).unwrap();
+        writeln!(subscriber, "subscribe").unwrap();
+        let mut events = BufReader::new(subscriber.try_clone().unwrap());
+        let mut line = String::new();
+        events.read_line(&mut line).unwrap();
+        assert_eq!(line, "ok subscribed\n");
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        writeln!(client, "tidy").unwrap();
+        assert_eq!(read_line(&client), "ok tidy\n");
+
+        subscribers.publish(&change(10));
+        line.clear();
+        events.read_line(&mut line).unwrap();
+        assert!(line.contains("\"con_id\":10"), "{}", line);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_poisoned_subscriber_list_is_still_served() {
+        let subscribers = Subscribers::default();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        subscribers.add(stream);
+        let poisoner = subscribers.clone();
+        let panicked = thread::spawn(move || {
+            let _clients = poisoner.clients.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(subscribers.clients.is_poisoned());
+
+        subscribers.publish(&change(10));
+        assert!(read_line(&peer).contains("\"con_id\":10"));
+    }
+}
@@ -0,0 +1,454 @@
+//! A tiny expression language for custom split decisions.
+//!
+//! An expression picks an action, optionally guarded by conditions, e.g.
+//! `splitv if height > 1.3 * width else splith if count < 3 else keep`:
+//!
+//! ```text
+//! decision   := action [ "if" condition "else" decision ]
+//! action     := "splith" | "splitv" | "keep"
+//! condition  := and { "or" and }
+//! and        := not { "and" not }
+//! not        := "not" not | comparison
+//! comparison := sum [ ("<" | "<=" | ">" | ">=" | "==" | "!=") sum ]
+//! sum        := product { ("+" | "-") product }
+//! product    := unary { ("*" | "/") unary }
+//! unary      := "-" unary | number | variable | "(" condition ")"
+//! ```
+//!
+//! Variables are `width`, `height`, `aspect` (width / height), `depth` (how
+//! far below its workspace the window sits) and `count` (windows in its
+//! container). Everything is a number; comparisons yield 1 or 0 and any
+//! non-zero value counts as true. There is no I/O and there are no loops, so
+//! evaluation always terminates. Expressions are parsed once, at startup (or
+//! when the configuration file is read), and malformed ones are rejected there.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// What a split expression decided
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    SplitH,
+    SplitV,
+    /// Leave the current split alone
+    Keep,
+}
+
+/// Values an expression can refer to
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Vars {
+    pub width: f64,
+    pub height: f64,
+    pub aspect: f64,
+    pub depth: f64,
+    pub count: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    Width,
+    Height,
+    Aspect,
+    Depth,
+    Count,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Num(f64),
+    Var(Var),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Decision {
+    Action(Verdict),
+    If(Expr, Verdict, Box<Decision>),
+}
+
+/// A parsed split expression
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SplitExpr {
+    source: String,
+    decision: Decision,
+}
+
+impl SplitExpr {
+    /// Evaluate the expression for one window
+    pub fn evaluate(&self, vars: &Vars) -> Verdict {
+        let mut decision = &self.decision;
+        loop {
+            match decision {
+                Decision::Action(verdict) => return *verdict,
+                Decision::If(cond, verdict, otherwise) => {
+                    if truthy(eval(cond, vars)) {
+                        return *verdict;
+                    }
+                    decision = otherwise;
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for SplitExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for SplitExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let decision = parser.decision()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected '{}' after the expression", token));
+        }
+        Ok(SplitExpr {
+            source: s.trim().to_string(),
+            decision,
+        })
+    }
+}
+
+impl TryFrom<String> for SplitExpr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn truthy(value: f64) -> bool {
+    value != 0.0 && !value.is_nan()
+}
+
+fn flag(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn eval(expr: &Expr, vars: &Vars) -> f64 {
+    match expr {
+        Expr::Num(n) => *n,
+        Expr::Var(var) => match var {
+            Var::Width => vars.width,
+            Var::Height => vars.height,
+            Var::Aspect => vars.aspect,
+            Var::Depth => vars.depth,
+            Var::Count => vars.count,
+        },
+        Expr::Neg(inner) => -eval(inner, vars),
+        Expr::Not(inner) => flag(!truthy(eval(inner, vars))),
+        Expr::Bin(BinOp::And, a, b) => flag(truthy(eval(a, vars)) && truthy(eval(b, vars))),
+        Expr::Bin(BinOp::Or, a, b) => flag(truthy(eval(a, vars)) || truthy(eval(b, vars))),
+        Expr::Bin(op, a, b) => {
+            let (a, b) = (eval(a, vars), eval(b, vars));
+            match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => a / b,
+                BinOp::Lt => flag(a < b),
+                BinOp::Le => flag(a <= b),
+                BinOp::Gt => flag(a > b),
+                BinOp::Ge => flag(a >= b),
+                BinOp::Eq => flag(a == b),
+                BinOp::Ne => flag(a != b),
+                BinOp::And | BinOp::Or => unreachable!("handled above"),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f64),
+    Word(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Word(w) => f.write_str(w),
+            Token::Op(op) => f.write_str(op),
+        }
+    }
+}
+
+/// Operators, longest first so `<=` wins over `<`
+const OPERATORS: &[&str] = &["<=", ">=", "==", "!=", "<", ">", "+", "-", "*", "/", "(", ")"];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() || c == '.' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let number = rest[..len]
+                .parse()
+                .map_err(|_| format!("invalid number '{}'", &rest[..len]))?;
+            tokens.push(Token::Num(number));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..len].to_string()));
+            len
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            op.len()
+        } else {
+            return Err(format!("unexpected character '{}'", c));
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Consume the next token if it is the keyword `word`
+    fn eat_word(&mut self, word: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Word(w)) if w == word) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consume the next token if it is one of `ops`
+    fn eat_op(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn decision(&mut self) -> Result<Decision, String> {
+        let verdict = match self.next() {
+            Some(Token::Word(w)) if w == "splith" => Verdict::SplitH,
+            Some(Token::Word(w)) if w == "splitv" => Verdict::SplitV,
+            Some(Token::Word(w)) if w == "keep" => Verdict::Keep,
+            Some(other) => {
+                return Err(format!("expected splith, splitv or keep, got '{}'", other))
+            }
+            None => return Err("expected splith, splitv or keep".to_string()),
+        };
+        if !self.eat_word("if") {
+            return Ok(Decision::Action(verdict));
+        }
+        let cond = self.condition()?;
+        if !self.eat_word("else") {
+            return Err("expected 'else' after the condition".to_string());
+        }
+        let otherwise = self.decision()?;
+        Ok(Decision::If(cond, verdict, Box::new(otherwise)))
+    }
+
+    fn condition(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat_word("or") {
+            expr = Expr::Bin(BinOp::Or, Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.eat_word("and") {
+            expr = Expr::Bin(BinOp::And, Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat_word("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let lhs = self.sum()?;
+        let op = match self.eat_op(&["<", "<=", ">", ">=", "==", "!="]) {
+            Some("<") => BinOp::Lt,
+            Some("<=") => BinOp::Le,
+            Some(">") => BinOp::Gt,
+            Some(">=") => BinOp::Ge,
+            Some("==") => BinOp::Eq,
+            Some(_) => BinOp::Ne,
+            None => return Ok(lhs),
+        };
+        Ok(Expr::Bin(op, Box::new(lhs), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.eat_op(&["+", "-"]) {
+            let op = if op == "+" { BinOp::Add } else { BinOp::Sub };
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while let Some(op) = self.eat_op(&["*", "/"]) {
+            let op = if op == "*" { BinOp::Mul } else { BinOp::Div };
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Op("-")) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(Token::Op("(")) => {
+                let expr = self.condition()?;
+                match self.next() {
+                    Some(Token::Op(")")) => Ok(expr),
+                    _ => Err("expected ')'".to_string()),
+                }
+            }
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Word(w)) => match w.as_str() {
+                "width" => Ok(Expr::Var(Var::Width)),
+                "height" => Ok(Expr::Var(Var::Height)),
+                "aspect" => Ok(Expr::Var(Var::Aspect)),
+                "depth" => Ok(Expr::Var(Var::Depth)),
+                "count" => Ok(Expr::Var(Var::Count)),
+                other => Err(format!(
+                    "unknown variable '{}', expected width, height, aspect, depth or count",
+                    other
+                )),
+            },
+            Some(other) => Err(format!("unexpected '{}'", other)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(width: f64, height: f64, count: f64) -> Vars {
+        Vars {
+            width,
+            height,
+            aspect: width / height,
+            depth: 1.0,
+            count,
+        }
+    }
+
+    fn parse(s: &str) -> SplitExpr {
+        s.parse().unwrap_or_else(|err| panic!("{}: {}", s, err))
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        let value = |cond: &str| {
+            let expr = parse(&format!("splith if {} else splitv", cond));
+            expr.evaluate(&Vars::default()) == Verdict::SplitH
+        };
+        // `*` before `+`, `+` before comparisons, comparisons before `and`/`or`
+        assert!(value("1 + 2 * 3 == 7"));
+        assert!(value("(1 + 2) * 3 == 9"));
+        assert!(value("10 - 4 - 3 == 3"));
+        assert!(value("8 / 4 / 2 == 1"));
+        assert!(value("-2 * -3 == 6"));
+        assert!(value("1 or 0 and 0"));
+        assert!(!value("(1 or 0) and 0"));
+        assert!(value("not 0 and 1"));
+        assert!(!value("not (1 and 1)"));
+        assert!(value("(1 < 2) == 1"));
+    }
+
+    #[test]
+    fn decisions_chain_until_a_condition_holds() {
+        let expr = parse("splitv if height > 1.3 * width else splith if count < 3 else keep");
+        assert_eq!(expr.evaluate(&window(500.0, 1000.0, 5.0)), Verdict::SplitV);
+        assert_eq!(expr.evaluate(&window(1000.0, 1000.0, 2.0)), Verdict::SplitH);
+        assert_eq!(expr.evaluate(&window(1000.0, 1000.0, 3.0)), Verdict::Keep);
+        assert_eq!(parse("keep").evaluate(&Vars::default()), Verdict::Keep);
+        let landscape = parse("splith if aspect >= 1 and depth != 0 else splitv");
+        assert_eq!(landscape.evaluate(&window(4.0, 3.0, 1.0)), Verdict::SplitH);
+        // Dividing zero by zero yields no number, which is false
+        let nan = parse("splith if 0 / 0 else splitv");
+        assert_eq!(nan.evaluate(&Vars::default()), Verdict::SplitV);
+        let spaced = parse(" splitv  if width<2 else keep ");
+        assert_eq!(spaced.to_string(), "splitv  if width<2 else keep");
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        let error = |s: &str| s.parse::<SplitExpr>().unwrap_err();
+        assert_eq!(error(""), "expected splith, splitv or keep");
+        assert_eq!(error("tabbed"), "expected splith, splitv or keep, got 'tabbed'");
+        assert_eq!(error("splith if width > 1"), "expected 'else' after the condition");
+        assert_eq!(error("splith splitv"), "unexpected 'splitv' after the expression");
+        assert_eq!(error("splith if (width > 1 else keep"), "expected ')'");
+        // Comparisons don't chain
+        assert_eq!(error("splith if 1 < 2 == 1 else keep"), "expected 'else' after the condition");
+        assert_eq!(error("splith if width >"), "unexpected end of expression");
+        assert_eq!(error("splith if 1.2.3 else keep"), "invalid number '1.2.3'");
+        assert_eq!(error("splith if width % 2 else keep"), "unexpected character '%'");
+        assert!(error("splith if size > 1 else keep").starts_with("unknown variable 'size'"));
+    }
+}
@@ -0,0 +1,279 @@
+//! Reading the tree when swayipc can't.
+//!
+//! A sway release that adds a field value or changes a type swayipc doesn't
+//! know makes `get_tree` fail to deserialize as a whole. When that happens
+//! the tree is fetched again as raw JSON over a socket of our own and cut
+//! down to the fields the daemon actually uses; everything else gets a
+//! neutral default. That keeps autotiling working across schema drift.
+
+use std::env;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde_json::{json, Map, Value};
+use swayipc::{Connection, Node};
+
+use crate::LOG_IPC;
+
+/// i3-ipc message type of GET_TREE
+const GET_TREE: u32 = 4;
+const MAGIC: &[u8; 6] = b"i3-ipc";
+
+/// Layouts and node types swayipc understands; anything else is mapped to a
+/// safe stand-in
+const LAYOUTS: &[&str] = &["splith", "splitv", "stacked", "tabbed", "output", "dockarea", "none"];
+const NODE_TYPES: &[&str] = &["root", "output", "workspace", "con", "floating_con", "dockarea"];
+const SHELLS: &[&str] = &["xdg_shell", "xwayland", "unknown"];
+
+/// Set once the fallback was needed, so the warning is logged a single time
+static FALLBACK_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// `conn.get_tree()`, falling back to the raw JSON reply when it fails to
+/// deserialize
+pub fn get_tree(conn: &mut Connection) -> Result<Node> {
+    match conn.get_tree() {
+        Ok(tree) => Ok(tree),
+        Err(swayipc::Error::SerdeJson(err)) => {
+            if !FALLBACK_WARNED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    target: LOG_IPC,
+                    "Tree doesn't match the expected schema ({}), using the minimal reader", err
+                );
+            }
+            minimal_tree(&fetch_raw_tree()?)
+        }
+        Err(err) => Err(err).context("get_tree() failed"),
+    }
+}
+
+/// Path of the sway (or i3) IPC socket
+fn socket_path() -> Result<String> {
+    if let Some(path) = env::var_os("SWAYSOCK").or_else(|| env::var_os("I3SOCK")) {
+        return Ok(path.to_string_lossy().into_owned());
+    }
+    let output = Command::new("sway")
+        .arg("--get-socketpath")
+        .output()
+        .context("Failed to ask sway for its socket")?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Fetch the tree as plain JSON, without swayipc's typed deserialization
+pub fn fetch_raw_tree() -> Result<Value> {
+    let mut stream =
+        UnixStream::connect(socket_path()?).context("Failed to connect to IPC socket")?;
+    let mut request = MAGIC.to_vec();
+    request.extend_from_slice(&0u32.to_le_bytes());
+    request.extend_from_slice(&GET_TREE.to_le_bytes());
+    stream.write_all(&request).context("Failed to send GET_TREE")?;
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).context("Failed to read reply header")?;
+    if &header[..6] != MAGIC {
+        bail!("Unexpected reply magic {:?}", &header[..6]);
+    }
+    let len = u32::from_le_bytes([header[6], header[7], header[8], header[9]]) as usize;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).context("Failed to read reply")?;
+    serde_json::from_slice(&payload).context("GET_TREE reply is not JSON")
+}
+
+/// Build a `Node` from raw tree JSON, keeping only what the daemon needs:
+/// ids, names, types, layout, geometry, focus, class/app_id, marks, and the
+/// shell, window type and transient owner that tell XWayland popups apart
+pub fn minimal_tree(raw: &Value) -> Result<Node> {
+    serde_json::from_value(minimal_node(raw)?).context("Failed to build minimal tree")
+}
+
+fn minimal_node(raw: &Value) -> Result<Value> {
+    let obj = raw.as_object().context("Tree node is not an object")?;
+    let id = obj
+        .get("id")
+        .and_then(Value::as_i64)
+        .context("Tree node without an id")?;
+    let children = |key: &str| -> Result<Vec<Value>> {
+        match obj.get(key).and_then(Value::as_array) {
+            Some(nodes) => nodes.iter().map(minimal_node).collect(),
+            None => Ok(Vec::new()),
+        }
+    };
+
+    let window_properties = obj.get("window_properties").and_then(window_properties);
+    // A shell swayipc doesn't know becomes `unknown`, a missing one stays unset
+    let shell = match obj.get("shell") {
+        Some(Value::String(_)) => known(obj.get("shell"), SHELLS, "unknown").into(),
+        _ => Value::Null,
+    };
+
+    let mut node = Map::new();
+    node.insert("id".into(), id.into());
+    node.insert("name".into(), string_or_null(obj.get("name")));
+    node.insert("type".into(), known(obj.get("type"), NODE_TYPES, "con").into());
+    node.insert("border".into(), "none".into());
+    node.insert("current_border_width".into(), 0.into());
+    node.insert("layout".into(), known(obj.get("layout"), LAYOUTS, "none").into());
+    node.insert("percent".into(), number_or_null(obj.get("percent")));
+    for key in ["rect", "window_rect", "deco_rect", "geometry"] {
+        node.insert(key.into(), rect(obj.get(key)));
+    }
+    node.insert("urgent".into(), false.into());
+    node.insert("focused".into(), bool_field(obj.get("focused")).into());
+    node.insert("focus".into(), int_list(obj.get("focus")));
+    node.insert("floating".into(), Value::Null);
+    node.insert("nodes".into(), children("nodes")?.into());
+    node.insert("floating_nodes".into(), children("floating_nodes")?.into());
+    node.insert("sticky".into(), false.into());
+    node.insert("representation".into(), Value::Null);
+    node.insert("fullscreen_mode".into(), number_or_null(obj.get("fullscreen_mode")));
+    node.insert("app_id".into(), string_or_null(obj.get("app_id")));
+    node.insert("shell".into(), shell);
+    node.insert("window_properties".into(), window_properties.unwrap_or(Value::Null));
+    node.insert("marks".into(), string_list(obj.get("marks")));
+    node.insert("num".into(), number_or_null(obj.get("num")));
+    Ok(Value::Object(node))
+}
+
+/// The X11 window properties the daemon uses, or `None` without any of them
+fn window_properties(raw: &Value) -> Option<Value> {
+    let mut props = Map::new();
+    for key in ["class", "instance", "title", "window_role", "window_type"] {
+        if let Some(value) = raw.get(key).filter(|v| v.is_string()) {
+            props.insert(key.into(), value.clone());
+        }
+    }
+    if let Some(owner) = raw.get("transient_for").and_then(Value::as_i64) {
+        props.insert("transient_for".into(), owner.into());
+    }
+    (!props.is_empty()).then_some(Value::Object(props))
+}
+
+/// The value if it is one of `allowed`, `fallback` otherwise
+fn known(value: Option<&Value>, allowed: &[&str], fallback: &'static str) -> String {
+    value
+        .and_then(Value::as_str)
+        .filter(|s| allowed.contains(s))
+        .unwrap_or(fallback)
+        .to_string()
+}
+
+fn string_or_null(value: Option<&Value>) -> Value {
+    value.filter(|v| v.is_string()).cloned().unwrap_or(Value::Null)
+}
+
+fn number_or_null(value: Option<&Value>) -> Value {
+    value.filter(|v| v.is_number()).cloned().unwrap_or(Value::Null)
+}
+
+fn bool_field(value: Option<&Value>) -> bool {
+    value.and_then(Value::as_bool).unwrap_or(false)
+}
+
+fn int_list(value: Option<&Value>) -> Value {
+    let ids = value.and_then(Value::as_array).map(|ids| {
+        ids.iter()
+            .filter_map(Value::as_i64)
+            .map(Value::from)
+            .collect::<Vec<_>>()
+    });
+    Value::Array(ids.unwrap_or_default())
+}
+
+fn string_list(value: Option<&Value>) -> Value {
+    let strings = value
+        .and_then(Value::as_array)
+        .map(|items| items.iter().filter(|v| v.is_string()).cloned().collect::<Vec<_>>());
+    Value::Array(strings.unwrap_or_default())
+}
+
+/// A rect with integer fields, zeroed where the input is missing or odd
+fn rect(value: Option<&Value>) -> Value {
+    let field = |key: &str| {
+        value
+            .and_then(|r| r.get(key))
+            .and_then(Value::as_i64)
+            .unwrap_or(0)
+    };
+    json!({
+        "x": field("x"),
+        "y": field("y"),
+        "width": field("width"),
+        "height": field("height"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use swayipc::{NodeLayout, NodeType, ShellType};
+
+    #[test]
+    fn a_drifted_tree_still_yields_what_the_daemon_uses() {
+        let raw = json!({
+            "id": 1,
+            "type": "root",
+            "focus": [2],
+            "future_field": { "anything": [1, 2] },
+            "nodes": [{
+                "id": 2,
+                "type": "workspace",
+                "num": 3,
+                "layout": "columns",
+                "rect": { "x": 0, "y": 0, "width": 1920, "height": "1080" },
+                "focus": [10, "eleven"],
+                "nodes": [{
+                    "id": 10,
+                    "type": "window",
+                    "layout": "none",
+                    "border": "rounded",
+                    "focused": true,
+                    "rect": { "x": 0, "y": 0, "width": 960, "height": 1080 },
+                    "shell": "xwayland",
+                    "marks": ["_no_autotile", 7],
+                    "window_properties": {
+                        "class": "Gimp",
+                        "window_type": "popup_menu",
+                        "transient_for": 10,
+                        "opacity": 0.9,
+                    },
+                }, {
+                    "id": 11,
+                    "type": "con",
+                    "shell": "web_shell",
+                }],
+            }],
+        });
+        // swayipc itself gives up on the whole tree
+        assert!(serde_json::from_value::<Node>(raw.clone()).is_err());
+
+        let tree = minimal_tree(&raw).unwrap();
+        let workspace = &tree.nodes[0];
+        assert_eq!((workspace.num, workspace.layout), (Some(3), NodeLayout::None));
+        assert_eq!((workspace.rect.width, workspace.rect.height), (1920, 0));
+        assert_eq!(workspace.focus, [10]);
+
+        let window = &workspace.nodes[0];
+        assert_eq!(window.node_type, NodeType::Con);
+        assert!(window.focused);
+        assert_eq!(window.shell, Some(ShellType::Xwayland));
+        assert_eq!(window.marks, ["_no_autotile"]);
+        let props = window.window_properties.as_ref().unwrap();
+        assert_eq!(props.class.as_deref(), Some("Gimp"));
+        assert_eq!(props.window_type.as_deref(), Some("popup_menu"));
+        assert_eq!(props.transient_for, Some(10));
+
+        // An unknown shell is kept as such rather than dropped
+        assert_eq!(workspace.nodes[1].shell, Some(ShellType::Unknown));
+        assert!(workspace.nodes[1].window_properties.is_none());
+    }
+
+    #[test]
+    fn nodes_without_an_id_are_rejected() {
+        assert!(minimal_tree(&json!({ "type": "root" })).is_err());
+        assert!(minimal_tree(&json!({ "id": 1, "nodes": [{ "type": "con" }] })).is_err());
+        assert!(minimal_tree(&json!([1, 2])).is_err());
+    }
+}
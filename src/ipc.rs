@@ -0,0 +1,77 @@
+//! What the daemon needs from its compositor connection.
+//!
+//! Decisions only ever read the tree and send commands, so they are written
+//! against `IpcCommand` rather than `swayipc::Connection`. Tests drive them
+//! with `MockIpc`, which serves a canned tree and records every command.
+
+#[cfg(test)]
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use swayipc::{Connection, Fallible, Node};
+
+/// Reading the tree and running commands
+pub trait IpcCommand {
+    /// Run `cmd`, with one outcome per command it contains
+    fn run_command(&mut self, cmd: &str) -> Fallible<Vec<Fallible<()>>>;
+    /// The current layout tree
+    fn get_tree(&mut self) -> Result<Node>;
+}
+
+impl IpcCommand for Connection {
+    fn run_command(&mut self, cmd: &str) -> Fallible<Vec<Fallible<()>>> {
+        Connection::run_command(self, cmd)
+    }
+
+    fn get_tree(&mut self) -> Result<Node> {
+        crate::fallback::get_tree(self)
+    }
+}
+
+/// Serves `tree` and records commands instead of running them. Trees queued
+/// in `upcoming` are served first, one per read, as sway moving things around.
+/// Commands ending in one of `unknown` fail to parse, as on a compositor
+/// without them.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct MockIpc {
+    pub tree: Node,
+    pub upcoming: VecDeque<Node>,
+    pub commands: Vec<String>,
+    pub unknown: Vec<&'static str>,
+    /// How often the tree was read
+    pub reads: usize,
+}
+
+#[cfg(test)]
+impl MockIpc {
+    pub fn new(tree: Node) -> Self {
+        MockIpc {
+            tree,
+            upcoming: VecDeque::new(),
+            commands: Vec::new(),
+            unknown: Vec::new(),
+            reads: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl IpcCommand for MockIpc {
+    fn run_command(&mut self, cmd: &str) -> Fallible<Vec<Fallible<()>>> {
+        self.commands.push(cmd.to_string());
+        let outcomes = cmd
+            .split("; ")
+            .map(|c| match self.unknown.iter().find(|u| c.ends_with(*u)) {
+                Some(u) => Err(swayipc::Error::CommandParse(format!("Unknown command '{}'", u))),
+                None => Ok(()),
+            })
+            .collect();
+        Ok(outcomes)
+    }
+
+    fn get_tree(&mut self) -> Result<Node> {
+        self.reads += 1;
+        Ok(self.upcoming.pop_front().unwrap_or_else(|| self.tree.clone()))
+    }
+}
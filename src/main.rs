@@ -1,179 +1,6378 @@
-use std::collections::{HashMap, HashSet};
+mod config;
+mod control;
+mod expr;
+mod fallback;
+mod ipc;
+mod notify;
+mod record;
+mod rules;
+mod stats;
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use log::{debug, error, info};
-use swayipc::{Connection, Event, EventType, Node, NodeLayout, NodeType, WindowChange};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use config::{FileConfig, RuleAction, SplitDirection, WorkspaceLayout, WorkspaceOverride};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use log::{debug, error, info, warn};
+use control::{ControlCommand, ControlRequest, LayoutEvent, Subscribers};
+use expr::{SplitExpr, Verdict};
+use fallback::get_tree;
+use ipc::IpcCommand;
+use record::SessionRecorder;
+use rules::{AssignRule, MatchRule, WindowContext};
+use serde_json::Value;
+use stats::{CountingLogger, SessionStats};
+use swayipc::{
+    BindingEvent, Connection, Event, EventType, Node, NodeLayout, NodeType, Rect, ShellType,
+    Version, WindowChange, WindowEvent, WorkspaceChange,
+};
+
+/// Log target for split decisions: `RUST_LOG=autotile::decision=debug`
+const LOG_DECISION: &str = "autotile::decision";
+/// Log target for balancing
+const LOG_BALANCE: &str = "autotile::balance";
+/// Log target for master sizing
+const LOG_MASTER: &str = "autotile::master";
+/// Log target for the connection to sway
+const LOG_IPC: &str = "autotile::ipc";
 
 /// Configuration for the autotiler
 #[derive(Debug, Clone)]
 struct AutoTileConfig {
     workspaces: HashSet<i32>,
     enable_balance: bool,
+    /// Apps (class or app_id) whose containers are never balanced
+    no_balance_apps: HashSet<String>,
+    /// How long the event loop waits before waking up without an event
+    event_timeout: Duration,
+    /// Arrange floating windows on the workspace into a grid
+    float_grid: bool,
+    /// Let a new window's own initial geometry decide its first split
+    new_window_aspect_bias: bool,
+    /// Share of the container given to a master window (0.5 - 0.7)
+    master_percent: f32,
+    /// Apps (normalized class or app_id) whose windows become master on focus
+    master_apps: HashMap<String, f32>,
+    /// If non-empty, only windows of these apps (class or app_id) are autotiled
+    only_apps: HashSet<String>,
+    /// Ignore transient XWayland menus, tooltips and other popups
+    skip_override_redirect: bool,
+    /// Balance on every focus change, not only on new/closed windows
+    balance_on_every_focus: bool,
+    /// Where new windows are placed relative to their predecessor
+    new_window_side: NewWindowSide,
+    /// Periodically re-check every managed workspace and fix drifted splits
+    resweep_interval: Option<Duration>,
+    /// Binding modes in which autotiling is suspended
+    pause_modes: HashSet<String>,
+    /// Focus-triggered balances only run above this largest/smallest child ratio
+    balance_threshold: f64,
+    /// Rules sending new windows to fixed workspaces
+    assignments: Vec<AssignRule>,
+    /// Windows matching any of these rules are masters
+    master_rules: Vec<MatchRule>,
+    /// Windows matching any of these rules are never autotiled
+    exclude_rules: Vec<MatchRule>,
+    /// Only act on focus changes within this long after a key/mouse binding ran
+    focus_guard: Option<Duration>,
+    /// Stop making decisions on workspaces holding more windows than this
+    max_managed_windows: Option<usize>,
+    /// Demo mode: flip the focused container's layout every interval
+    demo_interval: Option<Duration>,
+    /// Dead zone around the split switch point in which the current split is kept
+    split_threshold: f32,
+    /// Per-app overrides of `split_threshold`
+    app_split_thresholds: HashMap<String, f32>,
+    /// Ignore focus changes; only decide splits when a window is created
+    conservative: bool,
+    /// Geometry used for aspect ratios
+    aspect_geometry: AspectGeometry,
+    /// What happens to the stack when its master window closes
+    master_close: MasterCloseAction,
+    /// Back off while more windows than this open or close within `churn_window`
+    churn_threshold: Option<usize>,
+    /// Window over which open/close churn is measured
+    churn_window: Duration,
+    /// Longest wait for sway to settle before acting anyway
+    settle_max_wait: Duration,
+    /// Window changes on which master sizing is re-applied
+    master_triggers: Vec<MasterTrigger>,
+    /// Only the oldest window of a master app on a workspace is master; later ones stack
+    master_first_window: bool,
+    /// Whether new master-app windows take the master position
+    master_on_open: MasterOpenPolicy,
+    /// User-supplied split rule replacing the aspect ratio rule
+    split_expr: Option<SplitExpr>,
+    /// Most split commands sent per second
+    max_splits_per_sec: Option<u32>,
+    /// Most balance commands sent per second
+    max_balances_per_sec: Option<u32>,
+    /// Layout issued when the first window opens on a workspace
+    first_window_layout: FirstWindowLayout,
+    /// How long containers the user arranged through a binding are left alone
+    manual_grace: Option<Duration>,
+    /// Which IPC dialect the compositor speaks, resolved once at startup
+    ipc_flavor: IpcFlavor,
+    /// Stop splitting windows nested deeper than this below their workspace
+    max_depth: Option<usize>,
+    /// Share of its container a new window is given, instead of an even split
+    new_window_ratio: Option<f32>,
+    /// Apps (class or app_id) that are never autotiled or balanced for
+    exclude_apps: HashSet<String>,
+    /// `[workspace.<num>]` sections of the configuration file
+    workspace_overrides: HashMap<i32, WorkspaceOverride>,
+    /// Focus events this close together are coalesced into the last one
+    focus_debounce: Option<Duration>,
+    /// Log commands instead of sending them
+    dry_run: bool,
+    /// Split inside a focused tabbed or stacked container instead of skipping it
+    tile_in_tabbed: bool,
+    /// Inner gap between windows in pixels, left out of aspect ratios
+    gap: i32,
+    /// Balance only on these workspaces; everywhere when empty
+    balance_workspaces: HashSet<i32>,
+    /// Never balance on these workspaces
+    no_balance_workspaces: HashSet<i32>,
+    /// Windows carrying this mark are masters, whatever their app
+    master_mark: Option<String>,
+    /// Whether splits keep windows square or long
+    split_mode: SplitMode,
+    /// Balance this long after a window opens rather than once its geometry
+    /// settles; either way from the event loop, while other events go on
+    balance_delay: Option<Duration>,
+    /// Whether splits follow window shapes or spiral
+    layout: TilingLayout,
+    /// Stack instead of splitting side by side once a container has this many
+    /// columns
+    max_columns: Option<usize>,
+    /// Split for every window of these apps, by class or app_id
+    app_splits: HashMap<String, &'static str>,
+    /// What the compositor supports; detected at startup
+    features: Features,
+    /// How many master windows share the master region of a container
+    master_count: usize,
+    /// How long an identical split is not sent to the same container again
+    command_cooldown: Option<Duration>,
+    /// Inner gap restored once a workspace has more than one tiled window
+    smart_gaps: Option<i32>,
 }
 
-/// Calculate the aspect ratio of a container (width / height)
-fn calculate_aspect_ratio(node: &Node) -> f32 {
-    let width = node.rect.width as f32;
-    let height = node.rect.height as f32;
-    if height == 0.0 {
-        1.0 // Avoid division by zero, though physics usually prevents 0 height windows
-    } else {
-        width / height
+/// Which side of the focused window a new window opens on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum NewWindowSide {
+    /// Sway's default: right of / below the previously focused window
+    After,
+    /// Left of / above the previously focused window
+    Before,
+}
+
+/// Which geometry aspect ratios are computed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum AspectGeometry {
+    /// The whole container, title bar included
+    Rect,
+    /// Only the part below the title bar, where the content is drawn
+    Content,
+}
+
+/// What to do when a master window closes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum MasterCloseAction {
+    /// Make the next window of the container master, with the same share
+    Promote,
+    /// Leave the remaining windows as they are
+    Keep,
+}
+
+/// A window change after which master sizing is re-applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum MasterTrigger {
+    Focus,
+    New,
+    Close,
+    Move,
+    Floating,
+}
+
+impl MasterTrigger {
+    fn matches(self, change: WindowChange) -> bool {
+        matches!(
+            (self, change),
+            (MasterTrigger::Focus, WindowChange::Focus)
+                | (MasterTrigger::New, WindowChange::New)
+                | (MasterTrigger::Close, WindowChange::Close)
+                | (MasterTrigger::Move, WindowChange::Move)
+                | (MasterTrigger::Floating, WindowChange::Floating)
+        )
     }
 }
 
-/// The actual brains of the operation.
-/// Determines if we should split Horizontally or Vertically based on the *Focused* node.
-fn update_split_direction(conn: &mut Connection, config: &AutoTileConfig) -> Result<()> {
-    // 1. Get the tree to find what we are looking at
-    let tree = conn.get_tree().context("get_tree() failed")?;
-    
-    // 2. Find the focused node
-    let focused_node = match tree.find_focused_as_ref(|n| n.focused) {
-        Some(node) => node,
-        None => return Ok(()), // No focus, nothing to do
+/// Whether `change` is one of the configured `--master-triggers`
+fn is_master_trigger(config: &AutoTileConfig, change: WindowChange) -> bool {
+    config.master_triggers.iter().any(|t| t.matches(change))
+}
+
+/// Whether a newly opened master-app window takes the master position
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum MasterOpenPolicy {
+    /// Leave new windows where they opened
+    Off,
+    /// The new window becomes master, the old master joins the stack
+    Swap,
+    /// The new window becomes master only if its container has none yet
+    Keep,
+}
+
+/// The master an output currently has
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MasterSlot {
+    window: i64,
+    container: i64,
+    percent: f32,
+}
+
+/// Layout given to a workspace when its first window opens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum FirstWindowLayout {
+    /// Issue nothing, decide as for any other window
+    None,
+    #[value(name = "splith")]
+    SplitH,
+    #[value(name = "splitv")]
+    SplitV,
+}
+
+impl FirstWindowLayout {
+    fn layout(self) -> Option<&'static str> {
+        match self {
+            FirstWindowLayout::None => None,
+            FirstWindowLayout::SplitH => Some("splith"),
+            FirstWindowLayout::SplitV => Some("splitv"),
+        }
+    }
+}
+
+/// Which compositor is on the other end of the IPC socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum IpcFlavor {
+    /// Ask the compositor for its version at startup
+    Auto,
+    Sway,
+    /// i3 has no `balance`; it is emulated with per-child resizes
+    I3,
+}
+
+/// Resolve `requested` to a concrete flavor. `Auto` looks at the version
+/// reply: sway reports itself as 1.x, i3 as 4.x.
+fn detect_ipc_flavor(version: Option<&Version>, requested: IpcFlavor) -> IpcFlavor {
+    if requested != IpcFlavor::Auto {
+        return requested;
+    }
+    match version {
+        Some(version) if version.human_readable.contains("sway") => IpcFlavor::Sway,
+        Some(version) if version.major >= 4 => IpcFlavor::I3,
+        Some(_) => IpcFlavor::Sway,
+        None => {
+            warn!(target: LOG_IPC, "No version reply, assuming sway");
+            IpcFlavor::Sway
+        }
+    }
+}
+
+/// Oldest sway release (major, minor) known to work with the daemon
+const MIN_SWAY_VERSION: (i32, i32) = (1, 5);
+
+/// What the compositor can do, settled once at startup so every feature
+/// gate lives here instead of next to the commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Features {
+    /// Major and minor version, when the compositor reported one
+    version: Option<(i32, i32)>,
+    /// `balance` may be sent; otherwise it is emulated with per-child resizes
+    native_balance: bool,
+}
+
+impl Features {
+    /// Features of `flavor` at `version`, warning about a sway older than
+    /// `MIN_SWAY_VERSION`. Without a version, sway is taken as current.
+    fn new(flavor: IpcFlavor, version: Option<(i32, i32)>) -> Self {
+        let outdated = flavor != IpcFlavor::I3 && version.is_some_and(|v| v < MIN_SWAY_VERSION);
+        if let (true, Some((major, minor))) = (outdated, version) {
+            warn!(
+                target: LOG_IPC,
+                "sway {}.{} is older than {}.{}: balancing is emulated and other features may \
+                 misbehave",
+                major,
+                minor,
+                MIN_SWAY_VERSION.0,
+                MIN_SWAY_VERSION.1
+            );
+        }
+        Features {
+            version,
+            native_balance: flavor != IpcFlavor::I3 && !outdated,
+        }
+    }
+}
+
+/// A balance waiting out `--balance-delay-ms`
+#[derive(Debug)]
+struct PendingBalance {
+    due: Instant,
+    /// Windows opened before it is due, oldest first
+    windows: Vec<i64>,
+    /// Without `--balance-delay-ms` the balance waits for the newest window
+    /// to settle instead: its rect at every poll so far
+    rects: Option<Vec<Option<Rect>>>,
+}
+
+/// Runtime state carried between events
+#[derive(Debug, Default)]
+struct DaemonState {
+    /// Containers that hold a no-balance app and must never be balanced
+    balance_exempt: HashSet<i64>,
+    /// Windows promoted to master, with their share
+    promoted: HashMap<i64, f32>,
+    /// When a focus change last triggered a balance
+    last_focus_balance: Option<Instant>,
+    /// When the last periodic re-sweep ran
+    last_resweep: Option<Instant>,
+    /// Sway's active binding mode
+    current_mode: String,
+    /// Set once the compositor rejected `balance`; no further attempts are made
+    balance_unsupported: bool,
+    /// When sway last reported a binding being run
+    last_binding: Option<Instant>,
+    /// Workspaces already reported as over `--max-managed-windows`
+    crowded_workspaces: HashSet<i64>,
+    /// When demo mode last flipped the layout
+    last_demo_flip: Option<Instant>,
+    /// Layout demo mode applied last
+    last_demo_layout: Option<&'static str>,
+    /// Output of the window the last split decision was made for
+    last_output: Option<String>,
+    /// Master each output keeps, by output name
+    output_masters: HashMap<String, MasterSlot>,
+    /// Recent window open and close events, oldest first
+    churn_events: VecDeque<Instant>,
+    /// Set while open/close churn is over `--churn-threshold`
+    churning: bool,
+    /// A balance was skipped during churn and is due once it subsides
+    churn_balance_pending: bool,
+    /// Control socket clients following layout changes
+    subscribers: Subscribers,
+    /// Counters for the summary logged on exit
+    stats: SessionStats,
+    /// Recent commands of every rate limited kind
+    commands_sent: HashMap<CommandKind, VecDeque<Instant>>,
+    /// Containers the user just arranged by hand, left alone until the instant given
+    manual_grace: HashMap<i64, Instant>,
+    /// Split forced through the control socket, used up by the next split
+    /// decision and dropped on the next focus change if nothing used it
+    forced_split: Option<&'static str>,
+    /// Windows opened since the delayed balance was scheduled, and when it is due
+    pending_balance: Option<PendingBalance>,
+    /// Last split sent to each container and when, for `--command-cooldown-ms`
+    last_splits: HashMap<i64, (&'static str, Instant)>,
+    /// Workspaces by id and whether `--smart-gaps` turned their gaps off
+    smart_gaps_off: HashMap<i64, bool>,
+}
+
+/// `conn.run_command(cmd)`, or with `--dry-run` only log the command. Every
+/// command the daemon sends goes through here; reads are never affected.
+fn run_or_log<T: AsRef<str>>(
+    conn: &mut impl IpcCommand,
+    cmd: T,
+    dry_run: bool,
+) -> swayipc::Fallible<Vec<swayipc::Fallible<()>>> {
+    if dry_run {
+        info!(target: LOG_IPC, "Dry run: {}", cmd.as_ref());
+        return Ok(Vec::new());
+    }
+    conn.run_command(cmd.as_ref())
+}
+
+/// Commands collected to go out as a single message. sway runs the
+/// `;`-separated commands of one message in order and answers each, so a
+/// series of resizes costs one round trip and lands without flicker.
+#[derive(Debug, Default)]
+struct CommandBatch {
+    commands: Vec<String>,
+}
+
+impl CommandBatch {
+    fn push(&mut self, cmd: impl Into<String>) {
+        self.commands.push(cmd.into());
+    }
+
+    fn extend(&mut self, cmds: impl IntoIterator<Item = String>) {
+        self.commands.extend(cmds);
+    }
+
+    /// Send the collected commands through `run_or_log` and start over. A
+    /// command the compositor rejects is logged; the others still ran.
+    fn flush(&mut self, conn: &mut impl IpcCommand, dry_run: bool) -> swayipc::Fallible<()> {
+        if self.commands.is_empty() {
+            return Ok(());
+        }
+        let outcomes = run_or_log(conn, self.commands.join("; "), dry_run)?;
+        for (cmd, outcome) in self.commands.iter().zip(outcomes) {
+            if let Err(err) = outcome {
+                warn!(target: LOG_IPC, "Command '{}' failed: {}", cmd, err);
+            }
+        }
+        self.commands.clear();
+        Ok(())
+    }
+}
+
+/// Reads the tree from `conn` but queues every command into `batch`, so
+/// code that sends its commands one by one can share a single message.
+/// Queued commands report success; failures are logged when it is flushed.
+struct Queueing<'a, C> {
+    conn: &'a mut C,
+    batch: &'a mut CommandBatch,
+}
+
+impl<C: IpcCommand> IpcCommand for Queueing<'_, C> {
+    fn run_command(&mut self, cmd: &str) -> swayipc::Fallible<Vec<swayipc::Fallible<()>>> {
+        self.batch.push(cmd);
+        Ok(cmd.split("; ").map(|_| Ok(())).collect())
+    }
+
+    fn get_tree(&mut self) -> Result<Node> {
+        self.conn.get_tree()
+    }
+}
+
+/// Tell control socket subscribers that the daemon set `new` on `con_id`
+fn publish_layout_change(
+    state: &DaemonState,
+    con_id: i64,
+    old: Option<&str>,
+    new: &str,
+    reason: &str,
+) {
+    state.subscribers.publish(&LayoutEvent {
+        con_id,
+        old: old.map(str::to_string),
+        new: new.to_string(),
+        reason: reason.to_string(),
+    });
+}
+
+/// `command` scoped to the container `con_id`, so it still hits the window it
+/// was decided for when focus has moved on in the meantime
+fn scoped_command(con_id: i64, command: &str) -> String {
+    format!("[con_id={}] {}", con_id, command)
+}
+
+/// Kinds of command that are rate limited separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum CommandKind {
+    Split,
+    Balance,
+}
+
+/// Window over which the per-kind command limits are counted
+const COMMAND_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Whether a command of `kind` may be sent now under its per-second limit.
+/// Counts the command when it is allowed.
+fn command_allowed(config: &AutoTileConfig, state: &mut DaemonState, kind: CommandKind) -> bool {
+    let limit = match kind {
+        CommandKind::Split => config.max_splits_per_sec,
+        CommandKind::Balance => config.max_balances_per_sec,
+    };
+    let limit = match limit {
+        Some(limit) => limit as usize,
+        None => return true,
+    };
+    let now = Instant::now();
+    let sent = state.commands_sent.entry(kind).or_default();
+    prune_events(sent, now, COMMAND_LIMIT_WINDOW);
+    if sent.len() >= limit {
+        debug!(target: LOG_IPC, "{:?} commands over {} per second, throttling", kind, limit);
+        return false;
+    }
+    sent.push_back(now);
+    true
+}
+
+/// Whether `layout` was sent to `con_id` within `--command-cooldown-ms`.
+/// Entries past the cooldown are dropped on the way.
+fn split_cooling_down(
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    con_id: i64,
+    layout: &str,
+) -> bool {
+    let cooldown = match config.command_cooldown {
+        Some(cooldown) => cooldown,
+        None => return false,
     };
+    state.last_splits.retain(|_, (_, sent)| sent.elapsed() < cooldown);
+    match state.last_splits.get(&con_id) {
+        Some((last, _)) if *last == layout => {
+            debug!(target: LOG_IPC, "{} was just sent to {}, skipping", layout, con_id);
+            true
+        }
+        _ => false,
+    }
+}
 
-    // 3. Check workspace filter
-    if !config.workspaces.is_empty() {
-        // We have to walk up to find the workspace or check current workspace
-        // Simpler approach: verify current workspace
-        let workspaces = conn.get_workspaces()?;
-        if let Some(focused_ws) = workspaces.iter().find(|w| w.focused) {
-            if !config.workspaces.contains(&focused_ws.num) {
-                return Ok(());
+/// Send `layout` to `con_id`, remembering it for `--command-cooldown-ms`
+fn send_split(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    con_id: i64,
+    layout: &'static str,
+) -> swayipc::Fallible<Vec<swayipc::Fallible<()>>> {
+    let outcome = run_or_log(conn, scoped_command(con_id, layout), config.dry_run)?;
+    if config.command_cooldown.is_some() {
+        state.last_splits.insert(con_id, (layout, Instant::now()));
+    }
+    Ok(outcome)
+}
+
+/// Commands that change a layout by hand and start `--manual-grace`
+const LAYOUT_COMMANDS: &[&str] = &[
+    "split", "splith", "splitv", "splitt", "layout", "resize", "move",
+];
+
+/// Whether a binding's command list contains a layout-affecting command
+fn is_layout_command(command: &str) -> bool {
+    command.split([';', ',']).any(|cmd| {
+        let mut cmd = cmd.trim();
+        // Skip criteria such as `[app_id="foot"]`
+        if cmd.starts_with('[') {
+            cmd = cmd.split_once(']').map_or("", |(_, rest)| rest.trim_start());
+        }
+        let word = cmd.split_whitespace().next().unwrap_or_default();
+        LAYOUT_COMMANDS.contains(&word)
+    })
+}
+
+/// Note a binding that ran; a hand-made layout change puts the focused window
+/// and its container under `--manual-grace`
+fn note_binding(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    event: &BindingEvent,
+) -> Result<()> {
+    let now = Instant::now();
+    state.last_binding = Some(now);
+    let grace = match config.manual_grace {
+        Some(grace) if is_layout_command(&event.binding.command) => grace,
+        _ => return Ok(()),
+    };
+    state.manual_grace.retain(|_, until| *until > now);
+
+    let tree = conn.get_tree()?;
+    if let Some(focused) = tree.find_focused_as_ref(|n| n.focused) {
+        debug!(
+            target: LOG_DECISION,
+            "'{}' changed {} by hand, holding off for {:?}",
+            event.binding.command,
+            focused.id,
+            grace
+        );
+        state.manual_grace.insert(focused.id, now + grace);
+        if let Some(parent) = find_parent(&tree, focused.id) {
+            state.manual_grace.insert(parent.id, now + grace);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `con_id` is still in the grace period of a manual layout change
+fn in_manual_grace(state: &DaemonState, con_id: i64) -> bool {
+    state
+        .manual_grace
+        .get(&con_id)
+        .is_some_and(|until| *until > Instant::now())
+}
+
+/// Whether autotiling is currently suspended
+fn is_paused(config: &AutoTileConfig, state: &DaemonState) -> bool {
+    config.pause_modes.contains(&state.current_mode)
+}
+
+/// Everything the event loop can be woken up by
+enum LoopMessage {
+    Sway(swayipc::Fallible<Event>),
+    Control(ControlRequest),
+    /// SIGTERM or SIGINT arrived
+    Shutdown(i32),
+    /// SIGHUP arrived: re-read the configuration file
+    Reload,
+}
+
+/// Canonical form of an app name, so `Firefox ` in the config matches `firefox`
+fn normalize_app_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Normalize a list of app names from the command line into a lookup set
+fn app_set(names: Vec<String>) -> HashSet<String> {
+    names.iter().map(|n| normalize_app_name(n)).collect()
+}
+
+/// Normalized app_id and class of a window, whichever are present
+fn window_app_names(node: &Node) -> Vec<String> {
+    let class = node
+        .window_properties
+        .as_ref()
+        .and_then(|p| p.class.as_deref());
+    [node.app_id.as_deref(), class]
+        .iter()
+        .flatten()
+        .map(|name| normalize_app_name(name))
+        .collect()
+}
+
+/// Check whether a window's class or app_id is in the given set
+fn node_matches_app(node: &Node, apps: &HashSet<String>) -> bool {
+    window_app_names(node).iter().any(|name| apps.contains(name))
+}
+
+/// Master share for the window if it belongs to a master app or matches a
+/// master rule
+fn master_percent_for(tree: &Node, node: &Node, config: &AutoTileConfig) -> Option<f32> {
+    let over = workspace_override(tree, config, node.id);
+    if over.is_some_and(|o| o.layout == Some(WorkspaceLayout::Autotile)) {
+        return None;
+    }
+    let marked = config.master_mark.as_ref().is_some_and(|mark| node.marks.contains(mark));
+    if marked {
+        return Some(config.master_percent);
+    }
+    let master_apps = over
+        .and_then(|o| o.master_apps.as_ref())
+        .unwrap_or(&config.master_apps);
+    let by_app = window_app_names(node).iter().find_map(|name| {
+        let pct = master_apps.get(name).copied()?;
+        if config.master_first_window && !is_first_instance(tree, node, name) {
+            return None;
+        }
+        Some(pct)
+    });
+    by_app.or_else(|| {
+        let ctx = WindowContext::locate(tree, node);
+        rules::any_rule_matches(&config.master_rules, &ctx).then_some(config.master_percent)
+    })
+}
+
+/// Whether `node` is the oldest window of `app` on its workspace. Sway hands
+/// out container ids in increasing order, so that is the one with the lowest
+/// id. Masters are sized within a workspace, so windows of the app elsewhere
+/// (or in the scratchpad) don't keep this one from being master.
+fn is_first_instance(tree: &Node, node: &Node, app: &str) -> bool {
+    !workspace_of(tree, node.id)
+        .unwrap_or(tree)
+        .iter()
+        .any(|n| n.id < node.id && window_app_names(n).iter().any(|name| name == app))
+}
+
+/// Whether any master apps or rules are configured at all
+fn has_master_config(config: &AutoTileConfig) -> bool {
+    !config.master_apps.is_empty()
+        || !config.master_rules.is_empty()
+        || config.master_mark.is_some()
+        || config
+            .workspace_overrides
+            .values()
+            .any(|o| o.master_apps.as_ref().is_some_and(|apps| !apps.is_empty()))
+}
+
+/// Nodes on the path from `root` down to the node `id`, both included
+fn ancestors(root: &Node, id: i64) -> Vec<&Node> {
+    fn walk<'a>(node: &'a Node, id: i64, path: &mut Vec<&'a Node>) -> bool {
+        path.push(node);
+        if node.id == id {
+            return true;
+        }
+        for child in node.nodes.iter().chain(&node.floating_nodes) {
+            if walk(child, id, path) {
+                return true;
             }
         }
+        path.pop();
+        false
     }
 
-    // 4. Skip floating, tabbed, stacked, or fullscreen windows
-    // We don't want to mess with manual layouts
-    if focused_node.node_type == NodeType::FloatingCon
-        || focused_node.layout == NodeLayout::Stacked
-        || focused_node.layout == NodeLayout::Tabbed
-        || focused_node.percent.unwrap_or(0.0) > 1.0 
+    let mut path = Vec::new();
+    walk(root, id, &mut path);
+    path
+}
+
+/// X11 window types used by menus, tooltips and other short-lived popups
+const POPUP_WINDOW_TYPES: &[&str] = &[
+    "menu",
+    "popup_menu",
+    "dropdown_menu",
+    "tooltip",
+    "notification",
+    "combo",
+    "dnd",
+];
+
+/// Transient XWayland windows at most this many pixels wide/tall count as popups
+const POPUP_MAX_SIZE: i32 = 400;
+
+/// Heuristic for XWayland override-redirect windows (menus, tooltips) that
+/// sometimes show up in the tree but must never be tiled.
+fn is_xwayland_popup(node: &Node) -> bool {
+    if node.shell != Some(ShellType::Xwayland) {
+        return false;
+    }
+    let props = match &node.window_properties {
+        Some(props) => props,
+        None => return false,
+    };
+
+    if props
+        .window_type
+        .as_deref()
+        .is_some_and(|t| POPUP_WINDOW_TYPES.contains(&t))
     {
-        return Ok(());
+        return true;
     }
 
-    // 5. Calculate Aspect Ratio of the FOCUSED node (not the parent!)
-    // If we are Wide (>1.0), we want the NEXT window to be to the side -> SplitH
-    // If we are Tall (<1.0), we want the NEXT window to be below -> SplitV
-    let ratio = calculate_aspect_ratio(focused_node);
-    
+    // Small windows owned by another window are dialogs' dropdowns and the like
+    let small = node.geometry.width <= POPUP_MAX_SIZE && node.geometry.height <= POPUP_MAX_SIZE;
+    props.transient_for.is_some() && small
+}
+
+/// Find the direct parent of the node with the given id
+fn find_parent(root: &Node, id: i64) -> Option<&Node> {
+    root.find_as_ref(|n| {
+        n.nodes.iter().any(|c| c.id == id) || n.floating_nodes.iter().any(|c| c.id == id)
+    })
+}
+
+/// Whether `node` has both a width and a height once gaps are left out
+fn has_area(node: &Node, gap: i32) -> bool {
+    let (width, height) = gap_free_size(node, gap);
+    width > 0 && height > 0
+}
+
+/// Calculate the aspect ratio of a container (width / height), leaving out
+/// `gap` pixels of inner gaps. A container without height counts as square;
+/// split decisions check `has_area` before relying on the ratio.
+fn calculate_aspect_ratio(node: &Node, geometry: AspectGeometry, gap: i32) -> f32 {
+    let (width, height) = gap_free_size(node, gap);
+    let width = width as f32;
+    let height = match geometry {
+        AspectGeometry::Rect => height as f32,
+        // Title bars eat into the height but not the width
+        AspectGeometry::Content => (height - node.deco_rect.height).max(0) as f32,
+    };
+    if height == 0.0 {
+        1.0 // Avoid division by zero
+    } else {
+        width / height
+    }
+}
+
+/// Size of `node` without the inner gaps of `gap` pixels it covers: half a gap
+/// on every side of it, plus one gap between each pair of children along
+/// its split axis
+fn gap_free_size(node: &Node, gap: i32) -> (i32, i32) {
+    let between = gap * (node.nodes.len() as i32 - 1).max(0);
+    let (extra_w, extra_h) = match node.layout {
+        NodeLayout::SplitH => (between, 0),
+        NodeLayout::SplitV => (0, between),
+        _ => (0, 0),
+    };
+    (
+        (node.rect.width - gap - extra_w).max(0),
+        (node.rect.height - gap - extra_h).max(0),
+    )
+}
+
+/// Aspect ratio above which containers are split horizontally
+const SPLIT_SWITCH_RATIO: f32 = 1.1;
+
+/// How split directions are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum TilingLayout {
+    /// From each window's aspect ratio
+    Aspect,
+    /// Alternating with depth in the tree, whatever the shape: every new
+    /// window takes half of the last one, spiralling inwards
+    Spiral,
+}
+
+/// Split for `node` in spiral mode. The split applies to the container the
+/// next window will share with `node`: its parent when `node` is alone there,
+/// otherwise the new container wrapping it. Containers alternate between side
+/// by side and stacked by their level below the workspace, so the same tree
+/// always gets the same split.
+fn spiral_split(tree: &Node, node: &Node) -> &'static str {
+    let depth = workspace_depth(tree, node.id);
+    let alone = find_parent(tree, node.id).is_some_and(|parent| parent.nodes.len() == 1);
+    let level = if alone { depth.saturating_sub(1) } else { depth };
+    if level % 2 == 0 {
+        "splith"
+    } else {
+        "splitv"
+    }
+}
+
+/// Which way the split for a window's shape goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SplitMode {
+    /// Split along the longer edge, so both halves come out closer to square
+    Square,
+    /// Split along the shorter edge: tall windows split into narrower columns,
+    /// wide ones into flatter rows, e.g. for chat columns or stacked logs
+    Inverse,
+}
+
+/// Split command for a container with the given aspect ratio. The switch
+/// point sits a little above square: an exactly square window (1.0) and
+/// anything up to `SPLIT_SWITCH_RATIO` splits vertically in square mode.
+fn split_for_ratio(ratio: f32, mode: SplitMode) -> &'static str {
     // In Sway:
     // "splith" = Split Horizontal = Children arranged Left-to-Right
     // "splitv" = Split Vertical = Children arranged Top-to-Bottom
-    
-    let desired_layout = if ratio > 1.1 {
+    let wide = ratio > SPLIT_SWITCH_RATIO;
+    if wide == (mode == SplitMode::Square) {
         // Wide window: Split it horizontally so the new one goes next to it
+        // (or a tall one, inverted, so it stays narrow)
         "splith"
     } else {
         // Tall window: Split it vertically so the new one goes below
         "splitv"
+    }
+}
+
+/// Split for `ratio`, unless a split is already set and the ratio is within
+/// `threshold` of the switch point. That dead zone keeps near-square
+/// containers from flipping back and forth; `None` means keep `current`.
+/// It is centred on `SPLIT_SWITCH_RATIO` rather than on 1.0 because that is
+/// where the decision actually flips: centred on 1.0, a threshold below 0.1
+/// would leave the switch point outside the zone and the flapping in place.
+fn split_with_threshold(
+    current: Option<&'static str>,
+    ratio: f32,
+    threshold: f32,
+    mode: SplitMode,
+) -> Option<&'static str> {
+    if current.is_some() && (ratio - SPLIT_SWITCH_RATIO).abs() < threshold {
+        return None;
+    }
+    Some(split_for_ratio(ratio, mode))
+}
+
+/// `layout`, unless it would add another column to a container that already
+/// has `--max-columns` tiled windows side by side: then the window is stacked
+/// on instead
+fn limit_columns(
+    tree: &Node,
+    node: &Node,
+    config: &AutoTileConfig,
+    layout: &'static str,
+) -> &'static str {
+    let max = match config.max_columns {
+        Some(max) if layout == "splith" => max,
+        _ => return layout,
     };
+    let columns = find_parent(tree, node.id)
+        .filter(|parent| parent.layout == NodeLayout::SplitH)
+        .map_or(0, |parent| parent.nodes.len());
+    if columns < max {
+        return layout;
+    }
+    debug!(
+        target: LOG_DECISION,
+        "Container of {} has {} columns, stacking instead", node.id, columns
+    );
+    "splitv"
+}
 
-    debug!("Node {} Ratio: {:.2} -> Command: {}", focused_node.id, ratio, desired_layout);
-    
-    // Only run the command. Sway is smart enough not to break things if we spam it,
-    // but ideally we'd check the current split status. 
-    // However, 'split' commands set the split for the *future* window or the *current* container structure.
-    conn.run_command(desired_layout).context("Failed to set split")?;
+/// How close to square a window and its container must both be for the
+/// output's orientation to decide their split
+const TIEBREAK_BAND: f32 = 0.15;
 
-    Ok(())
+/// Split following the output's orientation, for a window whose own shape
+/// doesn't settle it: the window is within `TIEBREAK_BAND` of the switch
+/// point and its container is near-square too. A portrait output stacks such
+/// windows, a landscape one puts them side by side. `None` when the window's
+/// shape is clear enough or the output is square itself.
+fn output_tiebreak(ratio: f32, parent_ratio: f32, output_ratio: f32) -> Option<&'static str> {
+    let near_square = |r: f32, center: f32| (r - center).abs() < TIEBREAK_BAND;
+    if !near_square(ratio, SPLIT_SWITCH_RATIO) || !near_square(parent_ratio, 1.0) {
+        return None;
+    }
+    if output_ratio > 1.0 {
+        Some("splith")
+    } else if output_ratio < 1.0 {
+        Some("splitv")
+    } else {
+        None
+    }
 }
 
-fn balance_siblings(conn: &mut Connection) -> Result<()> {
-    // This runs 'balance' which equalizes the size of siblings in the current container
-    conn.run_command("balance")?;
-    Ok(())
+/// The split currently applied to `node`, as the command that produced it.
+/// A window that sits alone in a container carries its parent's split.
+fn current_split(tree: &Node, node: &Node) -> Option<&'static str> {
+    let layout = match find_parent(tree, node.id) {
+        Some(parent) if parent.nodes.len() == 1 => parent.layout,
+        _ => node.layout,
+    };
+    match layout {
+        NodeLayout::SplitH => Some("splith"),
+        NodeLayout::SplitV => Some("splitv"),
+        _ => None,
+    }
 }
 
-#[derive(Parser)]
-#[clap(version, author, about)]
-struct Cli {
-    /// Activate autotiling only on this workspace.
-    #[clap(long, short = 'w')]
-    workspace: Vec<i32>,
+/// Split dead zone for the window's app, or the global `--split-threshold`
+fn split_threshold_for(node: &Node, config: &AutoTileConfig) -> f32 {
+    window_app_names(node)
+        .iter()
+        .find_map(|name| config.app_split_thresholds.get(name).copied())
+        .unwrap_or(config.split_threshold)
+}
 
-    /// Enable automatic window balancing (run 'balance' on new windows)
-    #[clap(long, default_value_t = true)]
-    balance: bool,
+/// Parse `<app>=<number>` arguments such as `firefox=0.3`
+fn parse_app_value(s: &str) -> Result<(String, f32), String> {
+    let (app, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <app>=<number>, got '{}'", s))?;
+    let value = value
+        .trim()
+        .parse::<f32>()
+        .map_err(|err| format!("invalid number '{}': {}", value.trim(), err))?;
+    Ok((normalize_app_name(app), value))
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let args = Cli::parse();
-    
-    let config = AutoTileConfig {
-        workspaces: args.workspace.into_iter().collect(),
-        enable_balance: args.balance,
+/// Parse `--master-app` arguments: an app, optionally with its own master
+/// share, as in `firefox` or `steam=0.65`
+fn parse_master_app(s: &str) -> Result<(String, Option<f32>), String> {
+    let (app, pct) = match s.split_once('=') {
+        Some((app, pct)) => {
+            let pct = pct.trim().parse::<f32>().map_err(|err| {
+                format!("invalid master share '{}' for '{}': {}", pct.trim(), app.trim(), err)
+            })?;
+            let pct = master_share(pct).map_err(|err| format!("{} for '{}'", err, app.trim()))?;
+            (app, Some(pct))
+        }
+        None => (s, None),
     };
+    let app = normalize_app_name(app);
+    if app.is_empty() {
+        return Err(format!("missing app name in '{}'", s));
+    }
+    Ok((app, pct))
+}
 
-    info!("Jarvis Autotiling initialized. Workspaces: {:?}, Balance: {}", 
-        config.workspaces, config.enable_balance);
+/// Parse `<app>=<split>` arguments such as `foot=splitv`
+fn parse_app_split(s: &str) -> Result<(String, SplitDirection), String> {
+    let (app, split) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <app>=splith|splitv, got '{}'", s))?;
+    let app = normalize_app_name(app);
+    if app.is_empty() {
+        return Err(format!("missing app name in '{}'", s));
+    }
+    Ok((app, split.parse()?))
+}
 
-    // Connect to Sway
-    let mut conn = Connection::new().context("Failed to connect to Sway IPC")?;
-    
-    // Subscribe to Window events. 
-    // THIS is how you do it, Tony. No more 'while loop sleep'.
-    let events = Connection::new()
-        .context("Failed to open subscription connection")?
-        .subscribe(&[EventType::Window])
-        .context("Failed to subscribe to window events")?;
+/// The split configured for the window's app, if any
+fn app_split_for(node: &Node, config: &AutoTileConfig) -> Option<&'static str> {
+    window_app_names(node)
+        .iter()
+        .find_map(|name| config.app_splits.get(name).copied())
+}
 
-    // Initial pass: fix the currently focused window immediately
-    if let Err(e) = update_split_direction(&mut conn, &config) {
-        error!("Initial setup failed: {}", e);
+/// Node-level guards shared by every split decision
+fn is_autotile_candidate(node: &Node, config: &AutoTileConfig) -> bool {
+    // Check the app allowlist
+    if !config.only_apps.is_empty() && !node_matches_app(node, &config.only_apps) {
+        return false;
+    }
+    if node_matches_app(node, &config.exclude_apps) {
+        debug!(target: LOG_DECISION, "Node {} belongs to an excluded app, skipping", node.id);
+        return false;
     }
 
-    // Event Loop
-    for event in events {
-        match event {
-            Ok(Event::Window(e)) => {
-                match e.change {
-                    WindowChange::Focus => {
-                        // When focus changes, we determine how the *next* window should open
-                        // based on the dimensions of the window we just focused.
-                        if let Err(err) = update_split_direction(&mut conn, &config) {
-                            error!("Error handling focus: {}", err);
-                        }
-                    }
-                    WindowChange::New => {
-                        // A new window just appeared. 
-                        // It will inherit the split we set on the previous 'Focus' event.
-                        // Now we set the split for *this* new window (recursion).
-                        if let Err(err) = update_split_direction(&mut conn, &config) {
-                            error!("Error handling new window: {}", err);
-                        }
-
-                        // If enabled, balance the container so everything looks pretty
-                        if config.enable_balance {
-                            if let Err(err) = balance_siblings(&mut conn) {
-                                error!("Error balancing: {}", err);
-                            }
-                        }
-                    }
-                    WindowChange::Close => {
-                        // If a window closes, re-balance the survivors
-                        if config.enable_balance {
-                            if let Err(err) = balance_siblings(&mut conn) {
-                                error!("Error balancing: {}", err);
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            Ok(_) => {} // Ignore other events
-            Err(e) => {
-                error!("Event stream error: {}", e);
-                break; 
-            }
+    // The window opted out of autotiling
+    if node.marks.iter().any(|m| m == NO_AUTOTILE_MARK) {
+        debug!(target: LOG_DECISION, "Node {} is marked {}, skipping", node.id, NO_AUTOTILE_MARK);
+        return false;
+    }
+
+    // Leave XWayland menus and tooltips alone
+    if config.skip_override_redirect && is_xwayland_popup(node) {
+        debug!(target: LOG_DECISION, "Node {} looks like an XWayland popup, skipping", node.id);
+        return false;
+    }
+
+    // We don't want to mess with manual layouts
+    !(node.node_type == NodeType::FloatingCon
+        || is_tabbed_or_stacked(node)
+        || is_node_fullscreen(node))
+}
+
+fn is_tabbed_or_stacked(node: &Node) -> bool {
+    matches!(node.layout, NodeLayout::Tabbed | NodeLayout::Stacked)
+}
+
+/// The first node below `node` along the focus stack that is neither tabbed
+/// nor stacked, e.g. the visible tab's window or a split nested in that tab
+fn tiled_focus_descendant(node: &Node) -> Option<&Node> {
+    let mut current = node;
+    loop {
+        let next = *current.focus.first()?;
+        current = current.nodes.iter().find(|n| n.id == next)?;
+        if !is_tabbed_or_stacked(current) {
+            return Some(current);
         }
     }
+}
 
-    Ok(())
-}
\ No newline at end of file
+/// Whether the focused node sits somewhere below a `floating_nodes` list,
+/// whatever its own node type says
+fn is_focused_floating(tree: &Node) -> bool {
+    tree.iter()
+        .flat_map(|n| &n.floating_nodes)
+        .any(|floating| floating.find_as_ref(|n| n.focused).is_some())
+}
+
+/// Whether the node is fullscreen, on its workspace (mode 1) or across all
+/// outputs (mode 2)
+fn is_node_fullscreen(node: &Node) -> bool {
+    node.fullscreen_mode.is_some_and(|mode| mode != 0)
+}
+
+/// Whether `node` or any container around it is fullscreen
+fn in_fullscreen(tree: &Node, node: &Node) -> bool {
+    ancestors(tree, node.id).into_iter().any(is_node_fullscreen)
+}
+
+/// Mark a window carries to opt out of autotiling altogether, typically set
+/// by a `for_window` rule as the window appears
+const NO_AUTOTILE_MARK: &str = "_no_autotile";
+
+/// Mark that freezes a container's layout: its split is never changed,
+/// while autotiling carries on everywhere else
+const FREEZE_MARK: &str = "_autotile_freeze";
+
+/// Whether the split of `node` belongs to a frozen container. A split
+/// command changes the node's own layout or that of its parent, so a freeze
+/// mark on either counts.
+fn is_frozen(tree: &Node, node: &Node) -> bool {
+    let marked = |n: &Node| n.marks.iter().any(|m| m == FREEZE_MARK);
+    marked(node) || find_parent(tree, node.id).is_some_and(marked)
+}
+
+/// The container a split decision should act on for the focused node.
+/// Windows and split containers act on themselves; a focused workspace
+/// stands in for its first child; outputs and the root are never split.
+fn split_target(node: &Node) -> Option<&Node> {
+    match node.node_type {
+        NodeType::Con | NodeType::FloatingCon => Some(node),
+        NodeType::Workspace => node
+            .nodes
+            .first()
+            .filter(|child| child.node_type == NodeType::Con),
+        _ => None,
+    }
+}
+
+/// Whether workspace number `num` passes the `--workspace` filter
+fn workspace_num_allowed(config: &AutoTileConfig, num: i32) -> bool {
+    config.workspaces.is_empty() || config.workspaces.contains(&num)
+}
+
+/// The workspace node `id` sits in, found by walking up the tree from it
+fn workspace_of(tree: &Node, id: i64) -> Option<&Node> {
+    ancestors(tree, id)
+        .into_iter()
+        .rev()
+        .find(|n| n.node_type == NodeType::Workspace)
+}
+
+/// The `[workspace.<num>]` override of the workspace holding node `id`
+fn workspace_override<'a>(
+    tree: &Node,
+    config: &'a AutoTileConfig,
+    id: i64,
+) -> Option<&'a WorkspaceOverride> {
+    if config.workspace_overrides.is_empty() {
+        return None;
+    }
+    let num = workspace_of(tree, id)?.num?;
+    config.workspace_overrides.get(&num)
+}
+
+/// `config` with the override and the balance flags of the workspace an event
+/// happened on applied.
+/// A window that just closed is no longer in the tree; the focused workspace
+/// stands in for it. Master apps are resolved per window instead, see
+/// `master_percent_for`.
+fn workspace_config<'a>(
+    conn: &mut impl IpcCommand,
+    config: &'a AutoTileConfig,
+    con_id: i64,
+) -> Result<Cow<'a, AutoTileConfig>> {
+    if config.workspace_overrides.is_empty()
+        && config.balance_workspaces.is_empty()
+        && config.no_balance_workspaces.is_empty()
+    {
+        return Ok(Cow::Borrowed(config));
+    }
+    let tree = conn.get_tree()?;
+    let workspace = workspace_of(&tree, con_id).or_else(|| find_focused_workspace(&tree));
+    // Named workspaces have no number; sway reports them as -1
+    let num = workspace.map(|ws| ws.num.unwrap_or(-1));
+    let over = num.and_then(|num| config.workspace_overrides.get(&num));
+    // An enable_balance in the workspace's own section wins over the flags
+    let balance = over.and_then(|o| o.enable_balance).unwrap_or_else(|| {
+        config.enable_balance && num.is_none_or(|num| balance_num_allowed(config, num))
+    });
+    if balance == config.enable_balance {
+        return Ok(Cow::Borrowed(config));
+    }
+    let mut config = config.clone();
+    config.enable_balance = balance;
+    Ok(Cow::Owned(config))
+}
+
+/// Whether workspace `num` passes --balance-workspace and --no-balance-workspace
+fn balance_num_allowed(config: &AutoTileConfig, num: i32) -> bool {
+    (config.balance_workspaces.is_empty() || config.balance_workspaces.contains(&num))
+        && !config.no_balance_workspaces.contains(&num)
+}
+
+/// Whether the workspace holding node `id` passes the `--workspace` filter.
+/// The workspace comes from the node's place in the tree rather than from
+/// `get_workspaces()`, which can briefly disagree with the tree's focus on
+/// multi-output setups.
+fn node_workspace_allowed(tree: &Node, config: &AutoTileConfig, id: i64) -> bool {
+    if config.workspaces.is_empty() {
+        return true;
+    }
+    // Named workspaces have no number; sway reports them as -1
+    workspace_of(tree, id).is_none_or(|ws| workspace_num_allowed(config, ws.num.unwrap_or(-1)))
+}
+
+/// What a split decision should look at, when not simply the focused window
+#[derive(Debug, Default, Clone, Copy)]
+struct SplitRequest {
+    /// Decide for this window instead of the focused one
+    window: Option<i64>,
+    /// Use this aspect ratio instead of the window's own, for this decision only
+    ratio_hint: Option<f32>,
+}
+
+/// The actual brains of the operation.
+/// Determines if we should split Horizontally or Vertically based on the *Focused* node.
+/// Returns the split command that was issued, if any.
+fn update_split_direction(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    request: SplitRequest,
+) -> Result<Option<&'static str>> {
+    // 1. Get the tree to find what we are looking at
+    let tree = conn.get_tree()?;
+    
+    // 2. Find the focused node (or the window the request names)
+    let focused_node = match request.window {
+        Some(id) => tree
+            .find_as_ref(|n| n.id == id)
+            .map(|node| (node, workspace_depth(&tree, id))),
+        None => find_focused_with_depth(&tree),
+    };
+    let (focused_node, focused_depth) = match focused_node {
+        Some(found) => found,
+        None => return Ok(None), // No focus, nothing to do
+    };
+
+    // 2a. Focus can sit on a workspace, an output or the root (e.g. after
+    // `focus parent`); only real containers can be split
+    let target = match split_target(focused_node) {
+        Some(node) => node,
+        None => {
+            debug!(
+                target: LOG_DECISION,
+                "Focus on {:?} {}, nothing to split", focused_node.node_type, focused_node.id
+            );
+            return Ok(None);
+        }
+    };
+
+    // 2b. With --tile-in-tabbed, a focused tabbed or stacked container hands
+    // the decision to the tiled node its focus leads to
+    let target = match (config.tile_in_tabbed, is_tabbed_or_stacked(target)) {
+        (true, true) => match tiled_focus_descendant(target) {
+            Some(inner) => {
+                debug!(
+                    target: LOG_DECISION,
+                    "Container {} is {:?}, splitting {} inside it",
+                    target.id,
+                    target.layout,
+                    inner.id
+                );
+                inner
+            }
+            None => target,
+        },
+        _ => target,
+    };
+
+    // 3. Check workspace filter
+    if !node_workspace_allowed(&tree, config, target.id) {
+        return Ok(None);
+    }
+
+    // 3a. Don't nest any deeper than --max-depth
+    let depth = if target.id == focused_node.id {
+        focused_depth
+    } else {
+        focused_depth + 1
+    };
+    if config.max_depth.is_some_and(|max| depth > max) {
+        debug!(target: LOG_DECISION, "Node {} is {} levels deep, not splitting", target.id, depth);
+        return Ok(None);
+    }
+
+    // 4. Skip floating, tabbed, stacked, fullscreen or excluded windows. Focus
+    // inside a floating window doesn't always report as a floating con, so
+    // its place in the tree is checked too.
+    if !is_autotile_candidate(target, config) {
+        return Ok(None);
+    }
+    if request.window.is_none() && is_focused_floating(&tree) {
+        debug!(target: LOG_DECISION, "Focus is on a floating window, skipping");
+        return Ok(None);
+    }
+    if in_fullscreen(&tree, target) {
+        debug!(target: LOG_DECISION, "Node {} is fullscreen, skipping", target.id);
+        return Ok(None);
+    }
+    let ctx = WindowContext::locate(&tree, target);
+    if rules::any_rule_matches(&config.exclude_rules, &ctx) {
+        debug!(target: LOG_DECISION, "Node {} matches an exclude rule", target.id);
+        return Ok(None);
+    }
+    if is_frozen(&tree, target) {
+        debug!(target: LOG_DECISION, "Container of {} is frozen", target.id);
+        return Ok(None);
+    }
+    let parent_id = find_parent(&tree, target.id).map(|p| p.id);
+    if in_manual_grace(state, target.id) || parent_id.is_some_and(|id| in_manual_grace(state, id)) {
+        debug!(target: LOG_DECISION, "Container of {} was arranged by hand", target.id);
+        return Ok(None);
+    }
+
+    // 4b. Focus moving to another output always gets a fresh decision, since
+    // the windows there may be shaped very differently
+    let output = ctx.output.and_then(|o| o.name.clone());
+    let output_changed = state.last_output.is_some() && output != state.last_output;
+    if output_changed {
+        debug!(
+            target: LOG_DECISION,
+            "Focus moved from output {:?} to {:?}, re-evaluating", state.last_output, output
+        );
+    }
+    state.last_output = output;
+
+    // An app with a split of its own doesn't need its shape looked at
+    let app_split = app_split_for(target, config);
+
+    // 5. Calculate Aspect Ratio of the FOCUSED node (not the parent!)
+    // If we are Wide (>1.0), we want the NEXT window to be to the side -> SplitH
+    // If we are Tall (<1.0), we want the NEXT window to be below -> SplitV
+    // A window without width or height (still being mapped, or squeezed out
+    // by gaps) has no shape to go by, so it is left alone.
+    if app_split.is_none() && request.ratio_hint.is_none() && !has_area(target, config.gap) {
+        debug!(target: LOG_DECISION, "Node {} has no area, keeping split", target.id);
+        return Ok(None);
+    }
+    let ratio = request
+        .ratio_hint
+        .unwrap_or_else(|| calculate_aspect_ratio(target, config.aspect_geometry, config.gap));
+
+    let threshold = split_threshold_for(target, config);
+    let current = if output_changed {
+        None
+    } else {
+        current_split(&tree, target)
+    };
+    // 5a. A split forced through the control socket wins over everything, and a
+    // --split-expr replaces the built-in rule and its dead zone
+    let desired = match &config.split_expr {
+        _ if state.forced_split.is_some() => {
+            info!(target: LOG_DECISION, "Forced split {:?} on {}", state.forced_split, target.id);
+            state.forced_split
+        }
+        _ if app_split.is_some() => {
+            debug!(target: LOG_DECISION, "Node {} has app split {:?}", target.id, app_split);
+            app_split
+        }
+        _ if config.layout == TilingLayout::Spiral => Some(spiral_split(&tree, target)),
+        Some(expr) => {
+            let vars = expr_vars(&tree, target, ratio);
+            let verdict = expr.evaluate(&vars);
+            debug!(target: LOG_DECISION, "Node {} {:?} -> {:?}", target.id, vars, verdict);
+            match verdict {
+                Verdict::SplitH => Some("splith"),
+                Verdict::SplitV => Some("splitv"),
+                Verdict::Keep => None,
+            }
+        }
+        // The output the window is on, as laid out in the tree (rotation
+        // applied), breaks ties for near-square windows
+        None => ctx
+            .output
+            .zip(find_parent(&tree, target.id))
+            .and_then(|(output, parent)| {
+                let layout = output_tiebreak(
+                    ratio,
+                    calculate_aspect_ratio(parent, config.aspect_geometry, config.gap),
+                    calculate_aspect_ratio(output, AspectGeometry::Rect, 0),
+                )?;
+                debug!(
+                    target: LOG_DECISION,
+                    "Node {} is near-square, output {:?} decides {}", target.id, output.name, layout
+                );
+                Some(layout)
+            })
+            .or_else(|| split_with_threshold(current, ratio, threshold, config.split_mode))
+            .map(|layout| limit_columns(&tree, target, config, layout)),
+    };
+    let desired_layout = match desired {
+        Some(layout) => layout,
+        None => {
+            debug!(
+                target: LOG_DECISION,
+                "Node {} Ratio: {:.2}, keeping split", target.id, ratio
+            );
+            return Ok(None);
+        }
+    };
+
+    let old = current_split(&tree, target);
+    debug!(
+        target: LOG_DECISION,
+        from_layout = old.unwrap_or("none"), to_layout = desired_layout, aspect_ratio = ratio;
+        "Node {} Ratio: {:.2} -> Command: {}", target.id, ratio, desired_layout
+    );
+    
+    // Only run the command. Sway is smart enough not to break things if we spam it,
+    // but ideally we'd check the current split status. 
+    // However, 'split' commands set the split for the *future* window or the *current* container structure.
+    if split_cooling_down(config, state, target.id, desired_layout)
+        || !command_allowed(config, state, CommandKind::Split)
+    {
+        return Ok(None);
+    }
+    send_split(conn, config, state, target.id, desired_layout).context("Failed to set split")?;
+    // Sent, so a forced split that decided it is used up
+    state.forced_split = None;
+    state.stats.record_split(desired_layout);
+    let reason = if output_changed {
+        "output"
+    } else if request.window.is_some() {
+        "new"
+    } else {
+        "focus"
+    };
+    publish_layout_change(state, target.id, old, desired_layout, reason);
+
+    Ok(Some(desired_layout))
+}
+
+/// How far below its workspace the node `id` sits: 1 for a window directly on
+/// the workspace, 0 for the workspace itself or anything outside one
+fn workspace_depth(tree: &Node, id: i64) -> usize {
+    let path = ancestors(tree, id);
+    path.iter()
+        .position(|n| n.node_type == NodeType::Workspace)
+        .map_or(0, |ws| path.len() - ws - 1)
+}
+
+/// The focused node and its `workspace_depth`, found by following the focus
+/// stack down from the root
+fn find_focused_with_depth(tree: &Node) -> Option<(&Node, usize)> {
+    let mut node = tree;
+    let mut depth = None;
+    loop {
+        if node.node_type == NodeType::Workspace {
+            depth = Some(0);
+        }
+        if node.focused {
+            return Some((node, depth.unwrap_or(0)));
+        }
+        let next = *node.focus.first()?;
+        node = node
+            .nodes
+            .iter()
+            .chain(&node.floating_nodes)
+            .find(|n| n.id == next)?;
+        depth = depth.map(|d| d + 1);
+    }
+}
+
+/// Variables a `--split-expr` sees for `node`
+fn expr_vars(tree: &Node, node: &Node, aspect: f32) -> expr::Vars {
+    let depth = workspace_depth(tree, node.id);
+    let count = find_parent(tree, node.id).map_or(1, |p| p.nodes.len());
+    expr::Vars {
+        width: node.rect.width as f64,
+        height: node.rect.height as f64,
+        aspect: aspect as f64,
+        depth: depth as f64,
+        count: count as f64,
+    }
+}
+
+/// Aspect ratio of the size a new window asked for when it was mapped.
+/// Returns `None` when the client did not report a natural geometry.
+fn initial_aspect_ratio(node: &Node) -> Option<f32> {
+    let geometry = &node.geometry;
+    if geometry.width <= 0 || geometry.height <= 0 {
+        return None;
+    }
+    Some(geometry.width as f32 / geometry.height as f32)
+}
+
+/// Command that moves a freshly opened window in front of its predecessor.
+/// Returns `None` when sway's own placement already matches `side`.
+fn new_window_side_command(parent: &Node, con_id: i64, side: NewWindowSide) -> Option<String> {
+    if side == NewWindowSide::After {
+        return None;
+    }
+    // Already the first child: moving further would pop it out of the container
+    let index = parent.nodes.iter().position(|n| n.id == con_id)?;
+    if index == 0 {
+        return None;
+    }
+    let direction = match parent.layout {
+        NodeLayout::SplitH => "left",
+        NodeLayout::SplitV => "up",
+        _ => return None,
+    };
+    Some(format!("[con_id={}] move {}", con_id, direction))
+}
+
+/// Put a new window on the configured side of the window it was opened from
+fn place_new_window(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    con_id: i64,
+) -> Result<()> {
+    let side = config.new_window_side;
+    if side == NewWindowSide::After {
+        return Ok(());
+    }
+    let tree = conn.get_tree()?;
+    let cmd = find_parent(&tree, con_id).and_then(|p| new_window_side_command(p, con_id, side));
+    if let Some(cmd) = cmd {
+        debug!(target: LOG_DECISION, "Placing new window: {}", cmd);
+        run_or_log(conn, &cmd, config.dry_run).context("Failed to move new window")?;
+    }
+    Ok(())
+}
+
+/// How often the tree is re-read while waiting for a container to settle
+const SETTLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where waiting for a container stands after some polls of its rect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Settling {
+    Polling,
+    /// The last two polls showed the same geometry
+    Settled,
+    /// `max_polls` were taken without it settling
+    GaveUp,
+}
+
+/// Judge the rects polled so far (`None` while the container is missing). A
+/// container has settled once two consecutive reads show the same geometry.
+fn settling(rects: &[Option<Rect>], max_polls: usize) -> Settling {
+    match rects {
+        [.., previous, current] if current.is_some() && current == previous => Settling::Settled,
+        _ if rects.len() >= max_polls => Settling::GaveUp,
+        _ => Settling::Polling,
+    }
+}
+
+/// Polls taken at most while waiting `max_wait` for a container to settle
+fn settle_polls(max_wait: Duration) -> usize {
+    (max_wait.as_millis() / SETTLE_POLL_INTERVAL.as_millis()) as usize + 1
+}
+
+/// Poll the tree until the container `con_id` stops moving, giving up after
+/// `max_wait`. Returns the last tree read either way. Use this instead of
+/// sleeping whenever sway needs time to lay out a change.
+fn wait_for_tree_stable(
+    conn: &mut impl IpcCommand,
+    con_id: i64,
+    max_wait: Duration,
+) -> Result<Node> {
+    wait_until_stable(|| conn.get_tree(), con_id, max_wait)
+}
+
+/// `wait_for_tree_stable` over any source of tree snapshots
+fn wait_until_stable<F>(mut read_tree: F, con_id: i64, max_wait: Duration) -> Result<Node>
+where
+    F: FnMut() -> Result<Node>,
+{
+    let max_polls = settle_polls(max_wait);
+    let mut rects = Vec::new();
+    loop {
+        let tree = read_tree()?;
+        rects.push(tree.find_as_ref(|n| n.id == con_id).map(|n| n.rect));
+        match settling(&rects, max_polls) {
+            Settling::Polling => thread::sleep(SETTLE_POLL_INTERVAL),
+            Settling::Settled => {
+                debug!(target: LOG_IPC, "Container {} settled after {} reads", con_id, rects.len());
+                return Ok(tree);
+            }
+            Settling::GaveUp => {
+                debug!(target: LOG_IPC, "Container {} did not settle, going ahead", con_id);
+                return Ok(tree);
+            }
+        }
+    }
+}
+
+/// Remember the container of `window` as balance-exempt if it is a no-balance app
+fn mark_balance_exempt(
+    tree: &Node,
+    window: &Node,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) {
+    if !node_matches_app(window, &config.no_balance_apps) {
+        return;
+    }
+    if let Some(parent) = find_parent(tree, window.id) {
+        if state.balance_exempt.insert(parent.id) {
+            debug!(
+                target: LOG_BALANCE,
+                "Container {} is now balance-exempt (window {})", parent.id, window.id
+            );
+        }
+    }
+}
+
+/// Drop exempt containers that no longer exist in the tree
+fn prune_balance_exempt(tree: &Node, state: &mut DaemonState) {
+    state
+        .balance_exempt
+        .retain(|id| tree.find_as_ref(|n| n.id == *id).is_some());
+}
+
+/// Whether the container holding `window_id` has been marked balance-exempt
+fn is_balance_exempt(tree: &Node, window_id: i64, state: &DaemonState) -> bool {
+    find_parent(tree, window_id).is_some_and(|p| state.balance_exempt.contains(&p.id))
+}
+
+/// Balance the siblings of the window `con_id`
+fn balance_siblings(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    con_id: i64,
+) -> Result<()> {
+    if state.balance_unsupported || in_manual_grace(state, con_id) {
+        return Ok(());
+    }
+    if !command_allowed(config, state, CommandKind::Balance) {
+        return Ok(());
+    }
+
+    if !config.features.native_balance {
+        return emulate_balance(conn, config, state, con_id);
+    }
+
+    // This runs 'balance' which equalizes the size of siblings in the window's container
+    let outcomes = run_or_log(conn, scoped_command(con_id, "balance"), config.dry_run)?;
+    match outcomes.into_iter().next() {
+        // The compositor did not even parse it: it doesn't know the command
+        Some(Err(swayipc::Error::CommandParse(msg))) => {
+            warn!(
+                target: LOG_BALANCE,
+                "'balance' is not supported by this compositor ({}), disabling balancing", msg
+            );
+            state.balance_unsupported = true;
+            Ok(())
+        }
+        Some(Err(err)) => Err(err.into()),
+        _ => {
+            state.stats.balances += 1;
+            Ok(())
+        }
+    }
+}
+
+/// `balance` for compositors without it: resize every sibling of `con_id`
+/// to an even share of their container
+fn emulate_balance(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    con_id: i64,
+) -> Result<()> {
+    let tree = conn.get_tree()?;
+    let parent = match find_parent(&tree, con_id) {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+    let commands = even_out_commands(parent);
+    if commands.is_empty() {
+        return Ok(());
+    }
+    debug!(target: LOG_BALANCE, "Emulated balance: {}", commands.join("; "));
+    let mut batch = CommandBatch::default();
+    batch.extend(commands);
+    batch.flush(conn, config.dry_run).context("Failed to even out container")?;
+    state.stats.balances += 1;
+    Ok(())
+}
+
+/// Most floating windows the grid arranger will place
+const FLOAT_GRID_MAX: usize = 4;
+
+/// Find the workspace that currently holds focus
+fn find_focused_workspace(tree: &Node) -> Option<&Node> {
+    tree.find_focused_as_ref(|n| n.node_type == NodeType::Workspace)
+}
+
+/// Build the commands that lay out a workspace's floating windows as a grid.
+/// Only the first `FLOAT_GRID_MAX` floating windows are placed; a single
+/// floating window is left where the user put it.
+fn float_grid_commands(workspace: &Node) -> Vec<String> {
+    let windows: Vec<&Node> = workspace.floating_nodes.iter().take(FLOAT_GRID_MAX).collect();
+    if windows.len() < 2 {
+        return Vec::new();
+    }
+
+    let count = windows.len() as i32;
+    let cols = (count as f32).sqrt().ceil() as i32;
+    let rows = (count + cols - 1) / cols;
+    let area = &workspace.rect;
+    let cell_w = area.width / cols;
+    let cell_h = area.height / rows;
+
+    windows
+        .iter()
+        .zip(0..)
+        .map(|(node, i)| {
+            let x = area.x + (i % cols) * cell_w;
+            let y = area.y + (i / cols) * cell_h;
+            format!(
+                "[con_id={}] resize set width {} px height {} px, move absolute position {} px {} px",
+                node.id, cell_w, cell_h, x, y
+            )
+        })
+        .collect()
+}
+
+/// Tile the floating windows of the focused workspace into a grid
+fn arrange_floating_grid(conn: &mut impl IpcCommand, config: &AutoTileConfig) -> Result<()> {
+    let tree = conn.get_tree()?;
+    let workspace = match find_focused_workspace(&tree) {
+        Some(ws) => ws,
+        None => return Ok(()),
+    };
+
+    let mut batch = CommandBatch::default();
+    for cmd in float_grid_commands(workspace) {
+        debug!(target: LOG_DECISION, "Float grid: {}", cmd);
+        batch.push(cmd);
+    }
+    batch.flush(conn, config.dry_run).context("Failed to place floating windows")?;
+    Ok(())
+}
+
+/// Lower and upper bound for `--new-window-ratio`
+const NEW_WINDOW_RATIO_RANGE: (f32, f32) = (0.1, 0.9);
+
+/// Give the new window `con_id` the share `ratio` of its container. A window
+/// alone in its container already has all of it, so nothing is resized.
+fn apply_new_window_ratio(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    con_id: i64,
+    ratio: f32,
+) -> Result<()> {
+    let tree = conn.get_tree()?;
+    let parent = match find_parent(&tree, con_id) {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+    if parent.nodes.len() < 2 || !matches!(parent.layout, NodeLayout::SplitH | NodeLayout::SplitV)
+    {
+        return Ok(());
+    }
+
+    let cmd = format!(
+        "[con_id={}] resize set {} {} ppt",
+        con_id,
+        split_dimension(parent),
+        (ratio * 100.0).round() as i32
+    );
+    debug!(target: LOG_DECISION, "New window ratio: {}", cmd);
+    run_or_log(conn, &cmd, config.dry_run).context("Failed to size new window")?;
+    Ok(())
+}
+
+/// Lower and upper bound for the master share of a container
+const MASTER_PERCENT_RANGE: (f32, f32) = (0.5, 0.7);
+
+/// A master share as given by the user: a fraction within
+/// `MASTER_PERCENT_RANGE`, or the same as a percentage (`60` for 0.6). Any
+/// other value is an error rather than quietly clamped.
+fn master_share(value: f32) -> Result<f32, String> {
+    let (min, max) = MASTER_PERCENT_RANGE;
+    let share = if value > 1.0 { value / 100.0 } else { value };
+    if (min..=max).contains(&share) {
+        Ok(share)
+    } else {
+        Err(format!(
+            "master share {} is outside {} - {} (or {} - {} as a percentage)",
+            value,
+            min,
+            max,
+            min * 100.0,
+            max * 100.0
+        ))
+    }
+}
+
+/// Parse and validate `--master-percent`
+fn parse_master_percent(s: &str) -> Result<f32, String> {
+    let value = s
+        .trim()
+        .parse::<f32>()
+        .map_err(|err| format!("invalid master share '{}': {}", s.trim(), err))?;
+    master_share(value)
+}
+
+/// Resize direction that grows a child along its parent's split axis
+fn split_dimension(parent: &Node) -> &'static str {
+    if parent.layout == NodeLayout::SplitV {
+        "height"
+    } else {
+        "width"
+    }
+}
+
+/// The node to resize so the window `con_id` gets a container's share, and
+/// that container. A master nested in a container of its own is sized through
+/// its outermost ancestor that still lives alone in its parent, so it grows
+/// against the stack rather than within the nesting. `None` when the window
+/// has nothing to share its workspace with.
+fn master_resize_target(tree: &Node, con_id: i64) -> Option<(&Node, &Node)> {
+    let path = ancestors(tree, con_id);
+    for pair in path.windows(2).rev() {
+        let (parent, node) = (pair[0], pair[1]);
+        if node.node_type == NodeType::FloatingCon {
+            return None;
+        }
+        if parent.nodes.len() > 1 {
+            return Some((node, parent));
+        }
+        if parent.node_type == NodeType::Workspace {
+            break;
+        }
+    }
+    None
+}
+
+/// The children of `parent` forming the master region along with `target`:
+/// the first `--master-count` of them that hold a master window. `target`
+/// stands alone when it isn't among those.
+fn master_group(tree: &Node, config: &AutoTileConfig, parent: &Node, target: i64) -> Vec<i64> {
+    if config.master_count <= 1 {
+        return vec![target];
+    }
+    let holds_master = |child: &Node| {
+        child.id == target || child.iter().any(|n| master_percent_for(tree, n, config).is_some())
+    };
+    let group: Vec<i64> = parent
+        .nodes
+        .iter()
+        .filter(|child| holds_master(child))
+        .map(|child| child.id)
+        .take(config.master_count)
+        .collect();
+    if group.contains(&target) {
+        group
+    } else {
+        vec![target]
+    }
+}
+
+/// Resizes giving the window `con_id` the master share of its container,
+/// along the container's split axis. With `--master-count`, the share is split
+/// evenly among the master group. A window alone on its workspace already has
+/// all of it, and tabbed or stacked containers don't share space, so nothing
+/// is resized there.
+fn master_stack_commands(
+    config: &AutoTileConfig,
+    tree: &Node,
+    con_id: i64,
+    master_pct: f32,
+) -> Vec<String> {
+    master_stack_commands_after(config, tree, con_id, master_pct, &HashSet::new())
+}
+
+/// `master_stack_commands` for a batch that already resized the containers
+/// in `evened`: their shares in `tree` are stale, so nothing is skipped there
+fn master_stack_commands_after(
+    config: &AutoTileConfig,
+    tree: &Node,
+    con_id: i64,
+    master_pct: f32,
+    evened: &HashSet<i64>,
+) -> Vec<String> {
+    let master_pct = master_pct.clamp(MASTER_PERCENT_RANGE.0, MASTER_PERCENT_RANGE.1);
+    let (target, parent) = match master_resize_target(tree, con_id) {
+        Some(found) => found,
+        None => return Vec::new(),
+    };
+    if !matches!(parent.layout, NodeLayout::SplitH | NodeLayout::SplitV) {
+        return Vec::new();
+    }
+
+    let group = master_group(tree, config, parent, target.id);
+    let share = master_pct / group.len() as f32;
+    let ppt = (share * 100.0).round() as i32;
+    let mut commands = Vec::new();
+    for id in group {
+        // Re-applied on every new or closed window, so skip what is already right
+        let current = parent.nodes.iter().find(|n| n.id == id).and_then(|n| n.percent);
+        let current = current.filter(|_| !evened.contains(&parent.id));
+        if current.map(|pct| (pct * 100.0).round() as i32) == Some(ppt) {
+            debug!(target: LOG_MASTER, "Master {} already at {} ppt", id, ppt);
+            continue;
+        }
+        let cmd = format!("[con_id={}] resize set {} {} ppt", id, split_dimension(parent), ppt);
+        debug!(target: LOG_MASTER, "Master layout for {}: {}", con_id, cmd);
+        commands.push(cmd);
+    }
+    commands
+}
+
+/// Give the window `con_id` its master share, as `master_stack_commands`
+fn apply_master_stack_layout(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    tree: &Node,
+    con_id: i64,
+    master_pct: f32,
+) -> Result<()> {
+    let mut batch = CommandBatch::default();
+    batch.extend(master_stack_commands(config, tree, con_id, master_pct));
+    batch.flush(conn, config.dry_run).context("Failed to resize master")?;
+    Ok(())
+}
+
+/// Resize a focused master-app window to its master share
+fn apply_master_on_focus(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    window: &Node,
+) -> Result<()> {
+    let tree = conn.get_tree()?;
+    let window = tree.find_as_ref(|n| n.id == window.id).unwrap_or(window);
+    let master_pct = match master_percent_for(&tree, window, config) {
+        Some(pct) => pct,
+        None => return Ok(()),
+    };
+    let class = window_app_names(window).join(",");
+    debug!(
+        target: LOG_MASTER, class = class.as_str(), master_percent = master_pct;
+        "Focused {} is a master at {:.2}", window.id, master_pct
+    );
+    apply_master_stack_layout(conn, config, &tree, window.id, master_pct)
+}
+
+/// Give `con_id` and its siblings an even share of their container again
+fn reset_master_stack_layout(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    tree: &Node,
+    con_id: i64,
+) -> Result<()> {
+    let parent = match find_parent(tree, con_id) {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+    if parent.nodes.len() < 2 {
+        return Ok(());
+    }
+
+    let mut batch = CommandBatch::default();
+    batch.extend(even_out_commands(parent));
+    batch.flush(conn, config.dry_run).context("Failed to reset master size")?;
+    Ok(())
+}
+
+/// The master window of a container and its share: the first child that was
+/// promoted or belongs to a master app
+fn container_master<'a>(
+    tree: &Node,
+    container: &'a Node,
+    config: &AutoTileConfig,
+    state: &DaemonState,
+) -> Option<(&'a Node, f32)> {
+    container.nodes.iter().find_map(|child| {
+        if let Some(pct) = state.promoted.get(&child.id) {
+            Some((child, *pct))
+        } else {
+            master_percent_for(tree, child, config).map(|pct| (child, pct))
+        }
+    })
+}
+
+/// The master of every output and its share. Each output keeps its own
+/// master independently: the window chosen last time while it still
+/// qualifies, otherwise the first master window found on that output.
+fn output_masters(
+    tree: &Node,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Vec<(i64, f32)> {
+    let mut chosen = HashMap::new();
+    let mut masters = Vec::new();
+    let outputs = tree
+        .nodes
+        .iter()
+        .filter(|n| n.node_type == NodeType::Output && n.name.as_deref() != Some("__i3"));
+    for output in outputs {
+        let name = output.name.clone().unwrap_or_default();
+        let candidates: Vec<MasterSlot> = output
+            .iter()
+            .filter(|n| n.nodes.len() > 1)
+            .filter_map(|n| {
+                container_master(tree, n, config, state).map(|(master, percent)| MasterSlot {
+                    window: master.id,
+                    container: n.id,
+                    percent,
+                })
+            })
+            .collect();
+        let previous = state.output_masters.get(&name).map(|slot| slot.window);
+        let master = previous
+            .and_then(|id| candidates.iter().find(|slot| slot.window == id))
+            .or_else(|| candidates.first());
+        if let Some(slot) = master {
+            if previous != Some(slot.window) {
+                debug!(target: LOG_MASTER, "Output {} now has master {}", name, slot.window);
+            }
+            chosen.insert(name, *slot);
+            masters.push((slot.window, slot.percent));
+        }
+    }
+    state.output_masters = chosen;
+    masters
+}
+
+/// Re-apply master sizing on every output that holds a master window.
+/// This runs whenever a container's composition changes, so the master keeps
+/// its share no matter which window has focus.
+fn reapply_master_layouts(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<()> {
+    let tree = conn.get_tree()?;
+    state
+        .promoted
+        .retain(|id, _| tree.find_as_ref(|n| n.id == *id).is_some());
+
+    for (id, pct) in output_masters(&tree, config, state) {
+        apply_master_stack_layout(conn, config, &tree, id, pct)?;
+    }
+    Ok(())
+}
+
+/// When `closed` was the master of its output, promote the next window of
+/// its container so the stack keeps a master of the same share
+fn promote_master_successor(tree: &Node, state: &mut DaemonState, closed: i64) {
+    let (output, slot) = match state.output_masters.iter().find(|(_, s)| s.window == closed) {
+        Some((output, slot)) => (output.clone(), *slot),
+        None => return,
+    };
+    let successor = tree
+        .find_as_ref(|n| n.id == slot.container)
+        .filter(|container| container.nodes.len() > 1)
+        .and_then(|container| container.nodes.first());
+    match successor {
+        Some(next) => {
+            info!(target: LOG_MASTER, "Master {} closed, promoting {}", closed, next.id);
+            state.promoted.insert(next.id, slot.percent);
+            state.output_masters.insert(
+                output,
+                MasterSlot {
+                    window: next.id,
+                    ..slot
+                },
+            );
+        }
+        None => {
+            state.output_masters.remove(&output);
+        }
+    }
+}
+
+/// Move a newly opened master-app window to the front of its container and
+/// give it the master share, following `--master-on-open`
+fn promote_on_open(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    con_id: i64,
+) -> Result<()> {
+    let tree = conn.get_tree()?;
+    let window = match tree.find_as_ref(|n| n.id == con_id) {
+        Some(window) => window,
+        None => return Ok(()),
+    };
+    let pct = match master_percent_for(&tree, window, config) {
+        Some(pct) => pct,
+        None => return Ok(()),
+    };
+    let parent = match find_parent(&tree, con_id) {
+        Some(parent) if parent.nodes.len() > 1 => parent,
+        _ => return Ok(()),
+    };
+
+    let existing = parent.nodes.iter().find(|child| {
+        child.id != con_id
+            && (state.promoted.contains_key(&child.id)
+                || master_percent_for(&tree, child, config).is_some())
+    });
+    if let Some(master) = existing {
+        if config.master_on_open == MasterOpenPolicy::Keep {
+            debug!(target: LOG_MASTER, "Keeping master {} over new {}", master.id, con_id);
+            return Ok(());
+        }
+        state.promoted.remove(&master.id);
+    }
+
+    // Moving and sizing go out together, so the window lands in place at once
+    let mut batch = CommandBatch::default();
+    batch.extend(master_position_commands(parent, con_id));
+    batch.extend(master_stack_commands(config, &tree, con_id, pct));
+    batch.flush(conn, config.dry_run).context("Failed to move new master")?;
+    info!(target: LOG_MASTER, "Promoted new window {} to master", con_id);
+
+    let output = WindowContext::locate(&tree, window)
+        .output
+        .and_then(|o| o.name.clone())
+        .unwrap_or_default();
+    state.output_masters.insert(
+        output,
+        MasterSlot {
+            window: con_id,
+            container: parent.id,
+            percent: pct,
+        },
+    );
+    Ok(())
+}
+
+/// Commands that bring `con_id` to the master position, the front of `parent`
+fn master_position_commands(parent: &Node, con_id: i64) -> Vec<String> {
+    match parent.nodes.first() {
+        Some(first) if first.id != con_id => vec![scoped_command(
+            con_id,
+            &format!("swap container with con_id {}", first.id),
+        )],
+        _ => Vec::new(),
+    }
+}
+
+/// The tree and its focused node, which most control commands act on
+fn focused_tree(conn: &mut impl IpcCommand) -> Result<(Node, i64)> {
+    let tree = conn.get_tree()?;
+    let focused = tree
+        .find_focused_as_ref(|n| n.focused)
+        .context("No focused window")?
+        .id;
+    Ok((tree, focused))
+}
+
+/// Execute a command from the control socket and describe the outcome
+fn handle_control_command(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    command: ControlCommand,
+) -> Result<String> {
+    match command {
+        // Answered from memory, so it works even without a focused window
+        ControlCommand::Stats => {
+            let flavor = match config.ipc_flavor {
+                IpcFlavor::Auto => "auto",
+                IpcFlavor::Sway => "sway",
+                IpcFlavor::I3 => "i3",
+            };
+            Ok(format!("ok {}", state.stats.to_json(flavor)))
+        }
+        ControlCommand::Promote => {
+            let (tree, focused) = focused_tree(conn)?;
+            state.promoted.insert(focused, config.master_percent);
+            apply_master_stack_layout(conn, config, &tree, focused, config.master_percent)?;
+            info!(target: LOG_MASTER, "Promoted {} to master", focused);
+            Ok(format!("ok promoted {}", focused))
+        }
+        ControlCommand::Demote => {
+            let (tree, focused) = focused_tree(conn)?;
+            if state.promoted.remove(&focused).is_none() {
+                return Ok(format!("ok {} was not promoted", focused));
+            }
+            reset_master_stack_layout(conn, config, &tree, focused)?;
+            info!(target: LOG_MASTER, "Demoted {} from master", focused);
+            Ok(format!("ok demoted {}", focused))
+        }
+        ControlCommand::Tidy => {
+            let (tree, _) = focused_tree(conn)?;
+            tidy_workspace(conn, &tree, config, state)
+        }
+        ControlCommand::Rotate => {
+            let (tree, _) = focused_tree(conn)?;
+            rotate_stack(conn, &tree, config, state)
+        }
+        ControlCommand::SwapMaster => {
+            let (tree, _) = focused_tree(conn)?;
+            swap_master(conn, &tree, config, state)
+        }
+        ControlCommand::FocusMaster => {
+            let (tree, _) = focused_tree(conn)?;
+            focus_master(conn, &tree, config, state)
+        }
+        ControlCommand::NextSplit(None) => {
+            state.forced_split = None;
+            Ok("ok next split automatic".to_string())
+        }
+        ControlCommand::NextSplit(Some(layout)) => {
+            state.forced_split = Some(layout);
+            update_split_direction(conn, config, state, SplitRequest::default())?;
+            Ok(format!("ok next split {}", layout))
+        }
+    }
+}
+
+/// `tidy`: give every container of the focused workspace even shares, then
+/// size its masters again. Balancing comes first, as it would undo the
+/// master sizing otherwise.
+fn tidy_workspace(
+    conn: &mut impl IpcCommand,
+    tree: &Node,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<String> {
+    let workspace = find_focused_workspace(tree).context("No focused workspace")?;
+
+    // Balancing and master sizing go out together, so the workspace is
+    // rearranged once rather than visibly twice
+    let mut batch = CommandBatch::default();
+    let mut evened = HashSet::new();
+    for container in workspace.iter().filter(|n| n.nodes.len() > 1) {
+        let commands = even_out_commands(container);
+        if commands.is_empty() || state.balance_exempt.contains(&container.id) {
+            continue;
+        }
+        batch.extend(commands);
+        evened.insert(container.id);
+    }
+
+    let masters: Vec<(i64, f32)> = output_masters(tree, config, state)
+        .into_iter()
+        .filter(|(id, _)| workspace.find_as_ref(|n| n.id == *id).is_some())
+        .collect();
+    let mut sized = 0;
+    for (id, pct) in &masters {
+        let commands = master_stack_commands_after(config, tree, *id, *pct, &evened);
+        if !commands.is_empty() {
+            batch.extend(commands);
+            sized += 1;
+        }
+    }
+    batch.flush(conn, config.dry_run).context("Failed to tidy workspace")?;
+
+    info!(
+        target: LOG_BALANCE,
+        "Tidied workspace {}: {} containers balanced, {} masters sized",
+        workspace.name.as_deref().unwrap_or("?"),
+        evened.len(),
+        sized
+    );
+    Ok(format!("ok balanced {} containers, sized {} masters", evened.len(), sized))
+}
+
+/// The master of the focused workspace and its share, or the first window of
+/// the focused container at the default share when the workspace has none
+fn workspace_master(
+    tree: &Node,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<(i64, f32)> {
+    let workspace = find_focused_workspace(tree).context("No focused workspace")?;
+    let master = output_masters(tree, config, state)
+        .into_iter()
+        .find(|(id, _)| workspace.find_as_ref(|n| n.id == *id).is_some());
+    if let Some(master) = master {
+        return Ok(master);
+    }
+    let focused = tree
+        .find_focused_as_ref(|n| n.focused)
+        .context("No focused window")?;
+    let parent = find_parent(tree, focused.id).context("Focused window has no parent")?;
+    let first = parent.nodes.first().map_or(focused.id, |first| first.id);
+    Ok((first, config.master_percent))
+}
+
+/// `rotate`: cycle the stack windows next to the master of the focused
+/// workspace, or next to the first window of the focused container when the
+/// workspace has no master
+fn rotate_stack(
+    conn: &mut impl IpcCommand,
+    tree: &Node,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<String> {
+    let (master, _) = workspace_master(tree, config, state)?;
+    let container = find_parent(tree, master).context("Master has no container")?;
+
+    let commands = rotate_stack_commands(container, master);
+    if commands.is_empty() {
+        return Ok("ok nothing to rotate".to_string());
+    }
+    debug!(target: LOG_MASTER, "Rotate: {}", commands.join("; "));
+    let rotated = commands.len() + 1;
+    let mut batch = CommandBatch::default();
+    batch.extend(commands);
+    batch.flush(conn, config.dry_run).context("Failed to rotate stack")?;
+    Ok(format!("ok rotated {} stack windows", rotated))
+}
+
+/// `swap-master`: trade places between the master of the focused workspace
+/// and the top window of its stack, then size the new master
+fn swap_master(
+    conn: &mut impl IpcCommand,
+    tree: &Node,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<String> {
+    let (master, percent) = workspace_master(tree, config, state)?;
+    let container = find_parent(tree, master).context("Master has no container")?;
+    let top = match stack_windows(container, master).first() {
+        Some(top) => top.id,
+        None => return Ok("ok no stack to swap with".to_string()),
+    };
+
+    let cmd = scoped_command(master, &format!("swap container with con_id {}", top));
+    run_or_log(conn, &cmd, config.dry_run).context("Failed to swap master")?;
+    // A promotion belongs to the master slot, so it moves along
+    if let Some(pct) = state.promoted.remove(&master) {
+        state.promoted.insert(top, pct);
+    }
+    let tree = conn.get_tree()?;
+    apply_master_stack_layout(conn, config, &tree, top, percent)?;
+    info!(target: LOG_MASTER, "Swapped master {} with stack window {}", master, top);
+    Ok(format!("ok swapped master {} with {}", master, top))
+}
+
+/// `focus-master`: focus the first tiled master window of the focused
+/// workspace in tree order, promoted or matched by app, rule or mark
+fn focus_master(
+    conn: &mut impl IpcCommand,
+    tree: &Node,
+    config: &AutoTileConfig,
+    state: &DaemonState,
+) -> Result<String> {
+    let workspace = find_focused_workspace(tree).context("No focused workspace")?;
+    let mut leaves = Vec::new();
+    tiled_leaves_with_parents(workspace, &mut leaves);
+    let master = leaves.into_iter().find(|(n, _)| {
+        state.promoted.contains_key(&n.id) || master_percent_for(tree, n, config).is_some()
+    });
+    let master = match master {
+        Some((master, _)) => master.id,
+        None => return Ok("ok no master on this workspace".to_string()),
+    };
+    run_or_log(conn, scoped_command(master, "focus"), config.dry_run)
+        .context("Failed to focus master")?;
+    debug!(target: LOG_MASTER, "Focused master {}", master);
+    Ok(format!("ok focused master {}", master))
+}
+
+/// The stack next to `master` in `container`: the master's siblings, or the
+/// children of its only sibling when that is a container of its own
+fn stack_windows(container: &Node, master: i64) -> Vec<&Node> {
+    let siblings: Vec<&Node> = container.nodes.iter().filter(|n| n.id != master).collect();
+    match siblings.as_slice() {
+        [only] if !only.nodes.is_empty() => only.nodes.iter().collect(),
+        _ => siblings,
+    }
+}
+
+/// Commands moving every stack window of `container` one position up and the
+/// top one to the bottom, leaving `master` in place. Fewer than two stack
+/// windows need no commands.
+fn rotate_stack_commands(container: &Node, master: i64) -> Vec<String> {
+    let stack = stack_windows(container, master);
+    // Swapping the top window down the stack one by one carries it to the
+    // bottom and moves each window it passes up by one
+    match stack.split_first() {
+        Some((top, rest)) => rest
+            .iter()
+            .map(|n| scoped_command(top.id, &format!("swap container with con_id {}", n.id)))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Minimum time between two balances triggered by focus changes
+const FOCUS_BALANCE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How lopsided a container is: its largest child's share divided by its
+/// smallest. An evenly split container (or one with a single child) yields 1.0.
+fn container_imbalance(container: &Node) -> f64 {
+    let shares: Vec<f64> = container.nodes.iter().filter_map(|n| n.percent).collect();
+    if shares.len() < 2 {
+        return 1.0;
+    }
+    let max = shares.iter().cloned().fold(f64::MIN, f64::max);
+    let min = shares.iter().cloned().fold(f64::MAX, f64::min);
+    if min <= 0.0 {
+        return f64::INFINITY;
+    }
+    max / min
+}
+
+/// Commands giving every child of `container` an equal share. Tabbed and
+/// stacked containers show one child at a time, so they have none to even out.
+fn even_out_commands(container: &Node) -> Vec<String> {
+    let count = container.nodes.len();
+    if count < 2 || !matches!(container.layout, NodeLayout::SplitH | NodeLayout::SplitV) {
+        return Vec::new();
+    }
+    let dimension = split_dimension(container);
+    container
+        .nodes
+        .iter()
+        .map(|child| format!("[con_id={}] resize set {} {} ppt", child.id, dimension, 100 / count))
+        .collect()
+}
+
+/// Tiled containers of `workspace` with more than one child, most lopsided
+/// first, paired with their `container_imbalance`
+fn imbalance_report(workspace: &Node) -> Vec<(&Node, f64)> {
+    let mut report: Vec<(&Node, f64)> = workspace
+        .iter()
+        .filter(|n| n.nodes.len() > 1)
+        .map(|n| (n, container_imbalance(n)))
+        .collect();
+    report.sort_by(|a, b| b.1.total_cmp(&a.1));
+    report
+}
+
+/// One-shot `--report-imbalance`: print how uneven each container of the
+/// focused workspace is and, with `fix`, even out those above the threshold
+fn report_imbalance(conn: &mut impl IpcCommand, config: &AutoTileConfig, fix: bool) -> Result<()> {
+    let tree = conn.get_tree()?;
+    let workspace = find_focused_workspace(&tree).context("No focused workspace")?;
+    let report = imbalance_report(workspace);
+
+    let name = workspace.name.as_deref().unwrap_or("?");
+    if report.is_empty() {
+        println!("Workspace {}: no containers with more than one window", name);
+        return Ok(());
+    }
+    println!("Workspace {}: containers by imbalance (largest / smallest child)", name);
+    for (container, imbalance) in &report {
+        println!(
+            "  con {:>6}  {:?}, {} children  imbalance {:.2}",
+            container.id,
+            container.layout,
+            container.nodes.len(),
+            imbalance
+        );
+    }
+
+    if fix {
+        let mut batch = CommandBatch::default();
+        for (container, imbalance) in &report {
+            if *imbalance <= config.balance_threshold {
+                break;
+            }
+            batch.extend(even_out_commands(container));
+            println!("  balancing con {}", container.id);
+        }
+        batch.flush(conn, config.dry_run).context("Failed to balance containers")?;
+    }
+    Ok(())
+}
+
+/// Outcome of one `--validate-tree` check
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TreeCheck {
+    name: &'static str,
+    /// What is wrong and where; empty when the check passed
+    problems: Vec<String>,
+}
+
+/// The checks behind `--validate-tree`: the assumptions the daemon makes
+/// about the tree it gets from sway
+fn tree_checks(tree: &Node) -> Vec<TreeCheck> {
+    let focused = tree.find_focused_as_ref(|n| n.focused);
+    let mut checks = Vec::new();
+
+    let mut problems = Vec::new();
+    if focused.is_none() {
+        problems.push("no node has focus; splits would never be decided".to_string());
+    }
+    checks.push(TreeCheck {
+        name: "focused node found",
+        problems,
+    });
+
+    let mut problems = Vec::new();
+    if let Some(node) = focused {
+        if node.node_type != NodeType::Root && find_parent(tree, node.id).is_none() {
+            problems.push(format!("focused node {} has no parent in the tree", node.id));
+        }
+    }
+    checks.push(TreeCheck {
+        name: "parent of focused node resolvable",
+        problems,
+    });
+
+    let mut problems = Vec::new();
+    let windows = tree.iter().filter(|n| {
+        matches!(n.node_type, NodeType::Con | NodeType::FloatingCon)
+            && n.nodes.is_empty()
+            && n.floating_nodes.is_empty()
+    });
+    for window in windows {
+        if window_app_names(window).is_empty() {
+            problems.push(format!(
+                "window {} ({:?}) has neither class nor app_id; app options can't match it",
+                window.id,
+                window.name.as_deref().unwrap_or("untitled")
+            ));
+        }
+    }
+    checks.push(TreeCheck {
+        name: "every window has a class or app_id",
+        problems,
+    });
+
+    let mut problems = Vec::new();
+    if let Some(node) = focused {
+        let expected = [NodeType::Root, NodeType::Output, NodeType::Workspace];
+        for (depth, step) in ancestors(tree, node.id).iter().enumerate() {
+            let ok = match expected.get(depth) {
+                Some(node_type) => step.node_type == *node_type,
+                None => matches!(step.node_type, NodeType::Con | NodeType::FloatingCon),
+            };
+            if !ok {
+                problems.push(format!(
+                    "node {} at depth {} of the focus chain is a {:?}",
+                    step.id, depth, step.node_type
+                ));
+            }
+        }
+    }
+    checks.push(TreeCheck {
+        name: "focus chain is root > output > workspace > containers",
+        problems,
+    });
+
+    checks
+}
+
+/// One-shot `--validate-tree`: run `tree_checks` against the live tree and
+/// print what passed and what didn't
+fn validate_tree(conn: &mut impl IpcCommand) -> Result<()> {
+    let tree = conn.get_tree()?;
+    let checks = tree_checks(&tree);
+    for check in &checks {
+        let verdict = if check.problems.is_empty() { "pass" } else { "FAIL" };
+        println!("[{}] {}", verdict, check.name);
+        for problem in &check.problems {
+            println!("       {}", problem);
+        }
+    }
+    let failed = checks.iter().filter(|c| !c.problems.is_empty()).count();
+    if failed > 0 {
+        bail!("{} of {} tree checks failed", failed, checks.len());
+    }
+    println!("All {} tree checks passed", checks.len());
+    Ok(())
+}
+
+/// What `--explain` found out about the focused window
+#[derive(Debug, Clone, PartialEq)]
+struct Explanation {
+    con_id: i64,
+    app: Option<String>,
+    parent_id: Option<i64>,
+    parent_layout: Option<NodeLayout>,
+    aspect_ratio: f32,
+    current: Option<&'static str>,
+    /// The split the daemon would set; `None` keeps the current one
+    decision: Option<&'static str>,
+}
+
+impl Explanation {
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "con_id": self.con_id,
+            "app": self.app,
+            "parent_id": self.parent_id,
+            "parent_layout": self.parent_layout.map(|l| format!("{:?}", l).to_lowercase()),
+            "aspect_ratio": self.aspect_ratio,
+            "current": self.current,
+            "decision": self.decision,
+        })
+        .to_string()
+    }
+}
+
+impl std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Focused:      con {} ({})", self.con_id, self.app.as_deref().unwrap_or("-"))?;
+        match (self.parent_id, self.parent_layout) {
+            (Some(id), Some(layout)) => writeln!(f, "Parent:       con {}, {:?}", id, layout)?,
+            _ => writeln!(f, "Parent:       none")?,
+        }
+        writeln!(f, "Aspect ratio: {:.2}", self.aspect_ratio)?;
+        writeln!(f, "Current:      {}", self.current.unwrap_or("none"))?;
+        write!(f, "Decision:     {}", self.decision.unwrap_or("keep the current split"))
+    }
+}
+
+/// Run the split decision for the focused window as a dry run and collect
+/// what went into it
+fn explain_decision(conn: &mut impl IpcCommand, config: &AutoTileConfig) -> Result<Explanation> {
+    let tree = conn.get_tree()?;
+    let focused = tree
+        .find_focused_as_ref(|n| n.focused)
+        .context("No focused window")?;
+    let parent = find_parent(&tree, focused.id);
+
+    let mut dry_run = config.clone();
+    dry_run.dry_run = true;
+    let mut state = DaemonState::default();
+    let decision = update_split_direction(conn, &dry_run, &mut state, SplitRequest::default())?;
+
+    Ok(Explanation {
+        con_id: focused.id,
+        app: window_app_names(focused).into_iter().next(),
+        parent_id: parent.map(|p| p.id),
+        parent_layout: parent.map(|p| p.layout),
+        aspect_ratio: calculate_aspect_ratio(focused, config.aspect_geometry, config.gap),
+        current: current_split(&tree, focused),
+        decision,
+    })
+}
+
+/// One-shot `--explain`: print the decision for the focused window
+fn explain(conn: &mut impl IpcCommand, config: &AutoTileConfig, format: LogFormat) -> Result<()> {
+    let explanation = explain_decision(conn, config)?;
+    match format {
+        LogFormat::Text => println!("{}", explanation),
+        LogFormat::Json => println!("{}", explanation.to_json()),
+    }
+    Ok(())
+}
+
+/// Balance on focus, at most once per `FOCUS_BALANCE_INTERVAL`, and only
+/// when the focused container is more lopsided than `--balance-threshold`
+fn balance_on_focus(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<()> {
+    let now = Instant::now();
+    if state
+        .last_focus_balance
+        .is_some_and(|last| now.duration_since(last) < FOCUS_BALANCE_INTERVAL)
+    {
+        return Ok(());
+    }
+    let tree = conn.get_tree()?;
+    let focused = match tree.find_focused_as_ref(|n| n.focused) {
+        Some(focused) if node_workspace_allowed(&tree, config, focused.id) => focused,
+        _ => return Ok(()),
+    };
+    let imbalance = find_parent(&tree, focused.id).map_or(1.0, container_imbalance);
+    if imbalance <= config.balance_threshold {
+        debug!(target: LOG_BALANCE, "Imbalance {:.2} within threshold, skipping", imbalance);
+        return Ok(());
+    }
+
+    state.last_focus_balance = Some(now);
+    balance_siblings(conn, config, state, focused.id)
+}
+
+/// Collect every tiled window below `node` together with its parent
+fn tiled_leaves_with_parents<'a>(node: &'a Node, out: &mut Vec<(&'a Node, &'a Node)>) {
+    for child in &node.nodes {
+        if child.nodes.is_empty() {
+            out.push((child, node));
+        } else {
+            tiled_leaves_with_parents(child, out);
+        }
+    }
+}
+
+/// A split that no longer matches its window's shape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SplitCorrection {
+    con_id: i64,
+    from: &'static str,
+    to: &'static str,
+}
+
+/// Splits needed to bring the managed workspaces back in line.
+/// Only windows that sit alone in a split container (the shape our own split
+/// commands leave behind) are corrected; anything else is treated as a
+/// deliberate arrangement, as are windows the normal guards would skip.
+fn resweep_corrections(tree: &Node, config: &AutoTileConfig) -> Vec<SplitCorrection> {
+    let workspaces = tree.iter().filter(|n| {
+        n.node_type == NodeType::Workspace
+            && !n.name.as_deref().unwrap_or_default().starts_with("__i3")
+            && n.num.is_some_and(|num| workspace_num_allowed(config, num))
+    });
+
+    let mut corrections = Vec::new();
+    for workspace in workspaces {
+        let mut leaves = Vec::new();
+        tiled_leaves_with_parents(workspace, &mut leaves);
+        for (leaf, parent) in leaves {
+            if parent.nodes.len() != 1 || !is_autotile_candidate(leaf, config) {
+                continue;
+            }
+            if is_frozen(tree, leaf) || in_fullscreen(tree, leaf) || !has_area(leaf, config.gap) {
+                continue;
+            }
+            let current = match parent.layout {
+                NodeLayout::SplitH => "splith",
+                NodeLayout::SplitV => "splitv",
+                _ => continue,
+            };
+            let ratio = calculate_aspect_ratio(leaf, config.aspect_geometry, config.gap);
+            let desired = match config.layout {
+                TilingLayout::Aspect => split_for_ratio(ratio, config.split_mode),
+                TilingLayout::Spiral => spiral_split(tree, leaf),
+            };
+            if current != desired {
+                corrections.push(SplitCorrection {
+                    con_id: leaf.id,
+                    from: current,
+                    to: desired,
+                });
+            }
+        }
+    }
+    corrections
+}
+
+/// Carry out `resweep_corrections` for `tree`, telling subscribers why
+fn apply_split_corrections(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    tree: &Node,
+    reason: &str,
+) -> Result<()> {
+    for fix in resweep_corrections(tree, config) {
+        if split_cooling_down(config, state, fix.con_id, fix.to) {
+            continue;
+        }
+        if !command_allowed(config, state, CommandKind::Split) {
+            break;
+        }
+        debug!(
+            target: LOG_DECISION,
+            "{}: {} {} -> {}", reason, fix.con_id, fix.from, fix.to
+        );
+        send_split(conn, config, state, fix.con_id, fix.to).context("Failed to correct split")?;
+        state.stats.record_split(fix.to);
+        publish_layout_change(state, fix.con_id, Some(fix.from), fix.to, reason);
+    }
+    Ok(())
+}
+
+/// React to an output being added, removed or reconfigured (resolution,
+/// transform, scale): window geometry changed under us, so correct splits the
+/// new aspect ratios no longer favor, then re-decide the focused window
+fn handle_output_change(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<()> {
+    if is_paused(config, state) {
+        return Ok(());
+    }
+    // Give sway a moment to lay the workspaces out at the new size
+    let tree = conn.get_tree()?;
+    let tree = match find_focused_workspace(&tree) {
+        Some(ws) => wait_for_tree_stable(conn, ws.id, config.settle_max_wait)?,
+        None => tree,
+    };
+
+    apply_split_corrections(conn, config, state, &tree, "output")?;
+    // Conservative mode only decides for windows that open or move
+    if !config.conservative {
+        update_split_direction(conn, config, state, SplitRequest::default())?;
+    }
+    Ok(())
+}
+
+/// Switching to a workspace sends no window event when it has nothing to
+/// focus, and the focus event of one that has can come before the tree shows
+/// the switch. Decide the split there again once the workspace holds focus,
+/// so the first window opened on it lands right.
+fn handle_workspace_focus(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    workspace: &Node,
+) -> Result<()> {
+    if is_paused(config, state) || config.conservative {
+        return Ok(());
+    }
+    if !workspace_num_allowed(config, workspace.num.unwrap_or(-1)) {
+        return Ok(());
+    }
+    if workspace.nodes.is_empty() && workspace.floating_nodes.is_empty() {
+        debug!(target: LOG_DECISION, "Workspace {:?} is empty, nothing to decide", workspace.name);
+        return Ok(());
+    }
+    update_split_direction(conn, config, state, SplitRequest::default())?;
+    Ok(())
+}
+
+/// Apply --new-window-ratio and --master-on-open to a new window. Both come
+/// after balancing, which would even them out again.
+fn size_new_window(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    con_id: i64,
+    excluded: bool,
+) {
+    if let (Some(ratio), false) = (config.new_window_ratio, excluded) {
+        if let Err(err) = apply_new_window_ratio(conn, config, con_id, ratio) {
+            error!(target: LOG_DECISION, "Error sizing new window: {}", err);
+        }
+    }
+
+    // Last, so balancing doesn't even out the fresh master again
+    if config.master_on_open != MasterOpenPolicy::Off {
+        if let Err(err) = promote_on_open(conn, config, state, con_id) {
+            error!(target: LOG_MASTER, "Error promoting new master: {}", err);
+        }
+    }
+}
+
+/// Queue `con_id` for a balance `delay` from now, or for the one already due.
+/// Without a delay the balance comes once the newest window stopped moving.
+fn schedule_balance(state: &mut DaemonState, con_id: i64, delay: Option<Duration>) {
+    let pending = state.pending_balance.get_or_insert_with(|| PendingBalance {
+        due: Instant::now() + delay.unwrap_or(SETTLE_POLL_INTERVAL),
+        windows: Vec::new(),
+        rects: None,
+    });
+    pending.windows.push(con_id);
+    if delay.is_none() {
+        pending.rects = Some(Vec::new());
+    }
+    debug!(
+        target: LOG_BALANCE,
+        "Balance for {} due in {:?}", con_id, pending.due.saturating_duration_since(Instant::now())
+    );
+}
+
+/// Run the delayed balance once it is due: every container that gained a
+/// window is balanced once, then the new windows are sized
+fn maybe_run_pending_balance(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<()> {
+    match &state.pending_balance {
+        Some(pending) if pending.due <= Instant::now() => {}
+        _ => return Ok(()),
+    }
+    let tree = conn.get_tree()?;
+    // Waiting for the newest window to settle polls again a little later
+    if let Some(pending) = state.pending_balance.as_mut() {
+        if let (Some(rects), Some(&newest)) = (pending.rects.as_mut(), pending.windows.last()) {
+            rects.push(tree.find_as_ref(|n| n.id == newest).map(|n| n.rect));
+            if settling(rects, settle_polls(config.settle_max_wait)) == Settling::Polling {
+                pending.due = Instant::now() + SETTLE_POLL_INTERVAL;
+                return Ok(());
+            }
+        }
+    }
+    let windows = state.pending_balance.take().map_or_else(Vec::new, |p| p.windows);
+    let mut balanced = HashSet::new();
+    let mut opened = Vec::new();
+    for &id in &windows {
+        // Windows closed again in the meantime need nothing
+        let (window, parent) = match tree.find_as_ref(|n| n.id == id).zip(find_parent(&tree, id)) {
+            Some(found) => found,
+            None => continue,
+        };
+        // Only a reload can have excluded a queued window; it is left as is
+        let excluded = node_matches_app(window, &config.exclude_apps);
+        opened.push((id, excluded));
+        mark_balance_exempt(&tree, window, config, state);
+        if excluded || !balanced.insert(parent.id) || is_balance_exempt(&tree, id, state) {
+            continue;
+        }
+        balance_siblings(conn, config, state, id)?;
+    }
+    // Balances go out one by one, as each answer tells whether the compositor
+    // knows `balance`; the sizing that follows shares a single message
+    let mut batch = CommandBatch::default();
+    let mut queue = Queueing { conn: &mut *conn, batch: &mut batch };
+    for (id, excluded) in opened {
+        size_new_window(&mut queue, config, state, id, excluded);
+    }
+    batch.flush(conn, config.dry_run).context("Failed to size new windows")?;
+    if is_master_trigger(config, WindowChange::New) {
+        reapply_master_layouts(conn, config, state)?;
+    }
+    Ok(())
+}
+
+/// Run a re-sweep if `--resweep-interval` has elapsed since the last one
+fn maybe_resweep(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<()> {
+    let interval = match config.resweep_interval {
+        Some(interval) if !is_paused(config, state) => interval,
+        _ => return Ok(()),
+    };
+    let now = Instant::now();
+    if state
+        .last_resweep
+        .is_some_and(|last| now.duration_since(last) < interval)
+    {
+        return Ok(());
+    }
+    state.last_resweep = Some(now);
+
+    let tree = conn.get_tree()?;
+    apply_split_corrections(conn, config, state, &tree, "resweep")
+}
+
+/// Move a new window to its assigned workspace, if a rule matches it.
+/// Returns whether the window was moved away.
+fn apply_assignment(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    window: &Node,
+) -> Result<bool> {
+    let rule = match rules::find_assignment(window, &config.assignments) {
+        Some(rule) => rule,
+        None => return Ok(false),
+    };
+    let mut batch = CommandBatch::default();
+    batch.extend(rules::assignment_commands(rule, window.id));
+    debug!(target: LOG_DECISION, "Assignment: {}", batch.commands.join("; "));
+    batch.flush(conn, config.dry_run).context("Failed to apply assignment")?;
+    Ok(true)
+}
+
+/// Focus-stealing heuristic: a focus change counts as the user's own doing when
+/// a binding ran no more than `guard` before it. Focus moved by applications
+/// (or by focus_follows_mouse, which runs no binding) fails this check.
+fn focus_is_user_driven(last_binding: Option<Instant>, now: Instant, guard: Duration) -> bool {
+    last_binding.is_some_and(|at| now.saturating_duration_since(at) <= guard)
+}
+
+/// On a workspace's transition from zero to one tiled window, give it the
+/// `--first-window-layout`. Returns whether the layout was issued.
+fn apply_first_window_layout(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    con_id: i64,
+) -> Result<bool> {
+    let layout = match config.first_window_layout.layout() {
+        Some(layout) => layout,
+        None => return Ok(false),
+    };
+    let tree = conn.get_tree()?;
+    let is_tiled = tree
+        .find_as_ref(|n| n.id == con_id)
+        .is_some_and(|n| n.node_type == NodeType::Con);
+    if !is_tiled {
+        return Ok(false);
+    }
+    let tiled = match workspace_of(&tree, con_id) {
+        Some(ws) if node_workspace_allowed(&tree, config, con_id) => {
+            ws.nodes.iter().map(count_windows).sum::<usize>()
+        }
+        _ => return Ok(false),
+    };
+    if tiled != 1 {
+        return Ok(false);
+    }
+
+    debug!(target: LOG_DECISION, "First window {} on its workspace: layout {}", con_id, layout);
+    // With the window alone on the workspace this sets the workspace's layout
+    let cmd = scoped_command(con_id, &format!("layout {}", layout));
+    run_or_log(conn, &cmd, config.dry_run)
+        .context("Failed to set first window layout")?;
+    publish_layout_change(state, con_id, None, layout, "first-window");
+    Ok(true)
+}
+
+/// Number of windows (leaf containers, tiled or floating) below `node`
+fn count_windows(node: &Node) -> usize {
+    node.iter()
+        .filter(|n| n.nodes.is_empty() && n.floating_nodes.is_empty())
+        .filter(|n| matches!(n.node_type, NodeType::Con | NodeType::FloatingCon))
+        .count()
+}
+
+/// With `--smart-gaps`, drop the focused workspace's inner gaps while it has a
+/// single tiled window and restore them once it has more. Only changes are
+/// sent, so gaps the user set by hand in between are left alone. The command
+/// is scoped to a window of that workspace, so it lands there even if focus
+/// moved on before sway runs it.
+fn apply_smart_gaps(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<()> {
+    let gap = match config.smart_gaps {
+        Some(gap) => gap,
+        None => return Ok(()),
+    };
+    let tree = conn.get_tree()?;
+    state
+        .smart_gaps_off
+        .retain(|id, _| tree.find_as_ref(|n| n.id == *id).is_some());
+    let workspace = match find_focused_workspace(&tree) {
+        Some(ws) => ws,
+        None => return Ok(()),
+    };
+    let mut leaves = Vec::new();
+    tiled_leaves_with_parents(workspace, &mut leaves);
+    let (tiled, window) = match leaves.first() {
+        Some((window, _)) => (leaves.len(), window.id),
+        None => return Ok(()),
+    };
+    let off = tiled == 1;
+    if state.smart_gaps_off.insert(workspace.id, off) == Some(off) {
+        return Ok(());
+    }
+
+    let gaps = format!("gaps inner current set {}", if off { 0 } else { gap });
+    let cmd = scoped_command(window, &gaps);
+    debug!(
+        target: LOG_DECISION,
+        "Workspace {} has {} tiled windows: {}",
+        workspace.name.as_deref().unwrap_or("?"),
+        tiled,
+        cmd
+    );
+    run_or_log(conn, &cmd, config.dry_run).context("Failed to set smart gaps")?;
+    Ok(())
+}
+
+/// Whether the focused workspace holds more windows than `--max-managed-windows`.
+/// Each workspace is reported once when it crosses the limit.
+fn focused_workspace_crowded(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<bool> {
+    let limit = match config.max_managed_windows {
+        Some(limit) => limit,
+        None => return Ok(false),
+    };
+    let tree = conn.get_tree()?;
+    let workspace = match find_focused_workspace(&tree) {
+        Some(ws) => ws,
+        None => return Ok(false),
+    };
+
+    let count = count_windows(workspace);
+    if count <= limit {
+        state.crowded_workspaces.remove(&workspace.id);
+        return Ok(false);
+    }
+    if state.crowded_workspaces.insert(workspace.id) {
+        info!(
+            target: LOG_DECISION,
+            "Workspace {} has {} windows (limit {}), not autotiling it",
+            workspace.name.as_deref().unwrap_or("?"),
+            count,
+            limit
+        );
+    }
+    Ok(true)
+}
+
+/// Drop timestamps that fell out of the measuring window
+fn prune_events(events: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while events
+        .front()
+        .is_some_and(|t| now.duration_since(*t) > window)
+    {
+        events.pop_front();
+    }
+}
+
+/// Count a window open or close and report whether churn is over the threshold
+fn record_churn(config: &AutoTileConfig, state: &mut DaemonState, now: Instant) -> bool {
+    let threshold = match config.churn_threshold {
+        Some(threshold) => threshold,
+        None => return false,
+    };
+    state.churn_events.push_back(now);
+    prune_events(&mut state.churn_events, now, config.churn_window);
+    if !state.churning && state.churn_events.len() > threshold {
+        info!(
+            target: LOG_DECISION,
+            "{} windows opened or closed within {:?}, backing off",
+            state.churn_events.len(),
+            config.churn_window
+        );
+        state.churning = true;
+    }
+    state.churning
+}
+
+/// Leave the low-intervention state once churn has subsided, catching up on
+/// the balance that was skipped meanwhile
+fn maybe_end_churn(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<()> {
+    let threshold = match config.churn_threshold {
+        Some(threshold) if state.churning => threshold,
+        _ => return Ok(()),
+    };
+    prune_events(&mut state.churn_events, Instant::now(), config.churn_window);
+    if state.churn_events.len() > threshold {
+        return Ok(());
+    }
+    info!(target: LOG_DECISION, "Window churn subsided, resuming");
+    state.churning = false;
+    if std::mem::take(&mut state.churn_balance_pending) && config.enable_balance {
+        let tree = conn.get_tree()?;
+        if let Some(focused) = tree.find_focused_as_ref(|n| n.focused) {
+            balance_siblings(conn, config, state, focused.id)?;
+        }
+    }
+    Ok(())
+}
+
+/// The layout demo mode shows after `previous`
+fn next_demo_layout(previous: Option<&'static str>) -> &'static str {
+    match previous {
+        Some("splith") => "splitv",
+        _ => "splith",
+    }
+}
+
+/// In demo mode, flip the focused container's layout once the interval is up
+fn maybe_demo_flip(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+) -> Result<()> {
+    let interval = match config.demo_interval {
+        Some(interval) if !is_paused(config, state) => interval,
+        _ => return Ok(()),
+    };
+    let now = Instant::now();
+    if state
+        .last_demo_flip
+        .is_some_and(|last| now.duration_since(last) < interval)
+    {
+        return Ok(());
+    }
+    state.last_demo_flip = Some(now);
+    let tree = conn.get_tree()?;
+    let focused = match tree.find_focused_as_ref(|n| n.focused) {
+        Some(focused) if node_workspace_allowed(&tree, config, focused.id) => focused.id,
+        _ => return Ok(()),
+    };
+
+    let layout = next_demo_layout(state.last_demo_layout);
+    debug!(target: LOG_DECISION, "Demo: layout {} on {}", layout, focused);
+    let cmd = scoped_command(focused, &format!("layout {}", layout));
+    run_or_log(conn, &cmd, config.dry_run)
+        .context("Failed to flip layout")?;
+    publish_layout_change(state, focused, state.last_demo_layout, layout, "demo");
+    state.last_demo_layout = Some(layout);
+    Ok(())
+}
+
+/// Time from `now` until the first of the loop's own timers is due: the
+/// pending balance, the next re-sweep or the next demo flip. The last two
+/// don't run while paused, so they don't count then.
+fn next_deadline(config: &AutoTileConfig, state: &DaemonState, now: Instant) -> Option<Duration> {
+    let paused = is_paused(config, state);
+    let periodic = |last: Option<Instant>, interval: Option<Duration>| {
+        let interval = interval.filter(|_| !paused)?;
+        Some(last.map_or(Duration::ZERO, |last| (last + interval).saturating_duration_since(now)))
+    };
+    [
+        state.pending_balance.as_ref().map(|p| p.due.saturating_duration_since(now)),
+        periodic(state.last_resweep, config.resweep_interval),
+        periodic(state.last_demo_flip, config.demo_interval),
+    ]
+    .iter()
+    .flatten()
+    .min()
+    .copied()
+}
+
+/// Dispatch a single window event.
+/// Returns the split command issued in response, if any.
+fn handle_window_event(
+    conn: &mut impl IpcCommand,
+    config: &AutoTileConfig,
+    state: &mut DaemonState,
+    e: &WindowEvent,
+) -> Option<&'static str> {
+    if is_paused(config, state) {
+        debug!(target: LOG_DECISION, "Paused in mode '{}'", state.current_mode);
+        state.stats.skipped += 1;
+        return None;
+    }
+
+    let effective = match workspace_config(conn, config, e.container.id) {
+        Ok(effective) => effective,
+        Err(err) => {
+            error!(target: LOG_DECISION, "Error resolving workspace overrides: {}", err);
+            Cow::Borrowed(config)
+        }
+    };
+    let config = &*effective;
+    let mut decision = None;
+
+    // During open/close churn only note that a balance is due
+    if matches!(e.change, WindowChange::New | WindowChange::Close)
+        && record_churn(config, state, Instant::now())
+    {
+        debug!(target: LOG_DECISION, "Churning, leaving {} alone", e.container.id);
+        state.churn_balance_pending = true;
+        state.stats.skipped += 1;
+        return None;
+    }
+
+    if matches!(e.change, WindowChange::Focus | WindowChange::New) {
+        match focused_workspace_crowded(conn, config, state) {
+            Ok(true) => {
+                state.stats.skipped += 1;
+                return None;
+            }
+            Ok(false) => {}
+            Err(err) => error!(target: LOG_DECISION, "Error counting windows: {}", err),
+        }
+    }
+
+    // A forced split no decision used doesn't carry over to another window
+    if e.change == WindowChange::Focus {
+        if let Some(layout) = state.forced_split.take() {
+            info!(
+                target: LOG_DECISION,
+                "Forced split {} expired unused at {}", layout, e.container.id
+            );
+        }
+    }
+
+    match e.change {
+        // Conservative mode only ever acts when windows are created
+        WindowChange::Focus if config.conservative => {}
+        WindowChange::Focus => {
+            if let Some(guard) = config.focus_guard {
+                if !focus_is_user_driven(state.last_binding, Instant::now(), guard) {
+                    debug!(
+                        target: LOG_DECISION,
+                        "Focus on {} not caused by a binding, skipping", e.container.id
+                    );
+                    return None;
+                }
+            }
+
+            // When focus changes, we determine how the *next* window should open
+            // based on the dimensions of the window we just focused.
+            match update_split_direction(conn, config, state, SplitRequest::default()) {
+                Ok(split) => decision = split,
+                Err(err) => error!(target: LOG_DECISION, "Error handling focus: {}", err),
+            }
+
+            if has_master_config(config) && is_master_trigger(config, e.change) {
+                if let Err(err) = apply_master_on_focus(conn, config, &e.container) {
+                    error!(target: LOG_MASTER, "Error applying master layout: {}", err);
+                }
+            }
+
+            if config.balance_on_every_focus {
+                if let Err(err) = balance_on_focus(conn, config, state) {
+                    error!(target: LOG_BALANCE, "Error balancing on focus: {}", err);
+                }
+            }
+
+            if !config.no_balance_apps.is_empty() {
+                match conn.get_tree() {
+                    Ok(tree) => mark_balance_exempt(&tree, &e.container, config, state),
+                    Err(err) => {
+                        error!(target: LOG_BALANCE, "Error tracking balance exemptions: {}", err)
+                    }
+                }
+            }
+        }
+        WindowChange::New => {
+            // Assigned windows leave for their own workspace; nothing to tile here
+            match apply_assignment(conn, config, &e.container) {
+                Ok(true) => return None,
+                Ok(false) => {}
+                Err(err) => error!(target: LOG_DECISION, "Error applying assignment: {}", err),
+            }
+
+            // The first window of a workspace gets the configured layout instead
+            match apply_first_window_layout(conn, config, state, e.container.id) {
+                Ok(true) => return None,
+                Ok(false) => {}
+                Err(err) => error!(target: LOG_DECISION, "Error setting first layout: {}", err),
+            }
+
+            // Placement, split and sizing all go out as one message, decided
+            // on the tree as the window opened. Placement comes first: moving a
+            // window after splitting it would leave the split behind.
+            let mut batch = CommandBatch::default();
+            let mut queue = Queueing { conn: &mut *conn, batch: &mut batch };
+            if let Err(err) = place_new_window(&mut queue, config, e.container.id) {
+                error!(target: LOG_DECISION, "Error placing new window: {}", err);
+            }
+
+            // A new window just appeared. 
+            // It will inherit the split we set on the previous 'Focus' event.
+            // Now we set the split for *this* new window (recursion).
+            // With the aspect bias, the shape the window asked for wins this once.
+            // Conservative mode decides for the window that was created, wherever
+            // focus went in the meantime.
+            let request = SplitRequest {
+                window: config.conservative.then_some(e.container.id),
+                ratio_hint: if config.new_window_aspect_bias {
+                    initial_aspect_ratio(&e.container)
+                } else {
+                    None
+                },
+            };
+            match update_split_direction(&mut queue, config, state, request) {
+                Ok(split) => decision = split,
+                Err(err) => error!(target: LOG_DECISION, "Error handling new window: {}", err),
+            }
+
+            // Excluded apps don't reshape their container either
+            let excluded = node_matches_app(&e.container, &config.exclude_apps);
+
+            // If enabled, balance the container so everything looks pretty. The
+            // balance runs from the event loop later; sizing comes after it, so
+            // the balance takes it along
+            if config.enable_balance && !excluded {
+                schedule_balance(state, e.container.id, config.balance_delay);
+            } else {
+                size_new_window(&mut queue, config, state, e.container.id, excluded);
+            }
+            if let Err(err) = batch.flush(conn, config.dry_run) {
+                error!(target: LOG_DECISION, "Error sending new window commands: {}", err);
+            }
+        }
+        WindowChange::Close => {
+            // Forget exempt containers that went away with this window
+            let tree = match conn.get_tree() {
+                Ok(tree) => tree,
+                Err(err) => {
+                    error!(target: LOG_BALANCE, "Error reading tree after close: {}", err);
+                    return decision;
+                }
+            };
+            prune_balance_exempt(&tree, state);
+            if config.master_close == MasterCloseAction::Promote {
+                promote_master_successor(&tree, state, e.container.id);
+            }
+
+            // If a window closes, re-balance the survivors around the focused one.
+            // A floating window leaves no gap in the tiling, and with focus on
+            // the workspace itself there are no survivors to balance.
+            let floating = e.container.node_type == NodeType::FloatingCon;
+            let focused = tree
+                .find_focused_as_ref(|n| n.focused)
+                .filter(|n| !floating && n.node_type == NodeType::Con)
+                .filter(|n| node_workspace_allowed(&tree, config, n.id))
+                .filter(|n| !is_balance_exempt(&tree, n.id, state));
+            if let (true, Some(focused)) = (config.enable_balance, focused) {
+                if let Err(err) = balance_siblings(conn, config, state, focused.id) {
+                    error!(target: LOG_BALANCE, "Error balancing: {}", err);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    if config.float_grid
+        && matches!(e.change, WindowChange::New | WindowChange::Close | WindowChange::Floating)
+    {
+        if let Err(err) = arrange_floating_grid(conn, config) {
+            error!(target: LOG_DECISION, "Error arranging floating grid: {}", err);
+        }
+    }
+
+    if matches!(e.change, WindowChange::New | WindowChange::Close | WindowChange::Floating) {
+        if let Err(err) = apply_smart_gaps(conn, config, state) {
+            error!(target: LOG_DECISION, "Error applying smart gaps: {}", err);
+        }
+    }
+
+    // Focus only resizes the focused master, in its own arm; everything else
+    // re-applies master sizing across the tree. Setting or clearing the
+    // --master-mark changes who is master right away.
+    let marked = e.change == WindowChange::Mark && config.master_mark.is_some();
+    let reapply =
+        marked || (e.change != WindowChange::Focus && is_master_trigger(config, e.change));
+    let has_masters = has_master_config(config) || !state.promoted.is_empty();
+    if reapply && has_masters {
+        if let Err(err) = reapply_master_layouts(conn, config, state) {
+            error!(target: LOG_MASTER, "Error keeping masters at master size: {}", err);
+        }
+    }
+
+    decision
+}
+
+/// Coalesce a burst of focus events: after each one, wait up to `window` for
+/// another and keep only the last. Whatever else arrives in the meantime ends
+/// the burst and is queued to be handled right after it, so new windows are
+/// never debounced away.
+fn debounce_focus(
+    rx: &mpsc::Receiver<LoopMessage>,
+    first: Box<WindowEvent>,
+    window: Duration,
+    queued: &mut VecDeque<LoopMessage>,
+    stats: &mut SessionStats,
+) -> LoopMessage {
+    let mut latest = first;
+    let mut skipped = 0;
+    loop {
+        match rx.recv_timeout(window) {
+            Ok(LoopMessage::Sway(Ok(Event::Window(e)))) if e.change == WindowChange::Focus => {
+                stats.events += 1;
+                stats.skipped += 1;
+                skipped += 1;
+                latest = e;
+            }
+            Ok(other) => {
+                queued.push_back(other);
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+    if skipped > 0 {
+        debug!(
+            target: LOG_DECISION,
+            "Coalesced {} focus events into focus on {}", skipped + 1, latest.container.id
+        );
+    }
+    LoopMessage::Sway(Ok(Event::Window(latest)))
+}
+
+/// First delay before reconnecting to sway; doubled after every failed attempt
+const RECONNECT_BACKOFF_START: Duration = Duration::from_millis(100);
+/// Longest delay between two reconnection attempts
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Run `connect` until it succeeds, retrying up to `retries` times with a
+/// delay starting at `delay` and doubling up to `RECONNECT_BACKOFF_MAX`.
+/// Meant for startup, where sway may not be listening yet.
+fn connect_with_retries<T>(
+    what: &str,
+    retries: u32,
+    delay: Duration,
+    mut connect: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut backoff = delay;
+    let mut attempt = 0;
+    loop {
+        match connect() {
+            Ok(connected) => return Ok(connected),
+            Err(err) if attempt < retries => {
+                attempt += 1;
+                debug!(
+                    target: LOG_IPC,
+                    "{} failed ({:#}), retry {}/{} in {:?}", what, err, attempt, retries, backoff
+                );
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+            }
+            Err(err) => {
+                error!(
+                    target: LOG_IPC,
+                    "{} failed after {} attempt(s): {:#}", what, attempt + 1, err
+                );
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Subscribe to the events the daemon handles and forward them to the loop
+/// from a thread of their own. Forwarding stops after the first error, which
+/// tells the loop the connection is gone.
+fn subscribe_events(tx: mpsc::Sender<LoopMessage>) -> Result<()> {
+    let events = Connection::new()
+        .context("Failed to open subscription connection")?
+        .subscribe([
+            EventType::Window,
+            EventType::Mode,
+            EventType::Binding,
+            EventType::Output,
+            EventType::Workspace,
+        ])
+        .context("Failed to subscribe to window events")?;
+    thread::spawn(move || {
+        for event in events {
+            let failed = event.is_err();
+            if tx.send(LoopMessage::Sway(event)).is_err() || failed {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Re-open the command connection and the event subscription after sway went
+/// away, retrying with exponential backoff. Control requests arriving in the
+/// meantime are turned away. Returns `None` if a shutdown signal comes first.
+fn reconnect(
+    tx: &mpsc::Sender<LoopMessage>,
+    rx: &mpsc::Receiver<LoopMessage>,
+) -> Option<Connection> {
+    let mut backoff = RECONNECT_BACKOFF_START;
+    let mut attempt = 0;
+    let mut reload = false;
+    loop {
+        attempt += 1;
+        warn!(target: LOG_IPC, "Reconnecting to sway in {:?} (attempt {})", backoff, attempt);
+        let deadline = Instant::now() + backoff;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(left) {
+                Ok(LoopMessage::Shutdown(signal)) => {
+                    info!("Received signal {} while reconnecting, shutting down", signal);
+                    return None;
+                }
+                Ok(LoopMessage::Control(req)) => {
+                    let _ = req.reply.send("error: not connected to sway".to_string());
+                }
+                // Handled once the loop runs again
+                Ok(LoopMessage::Reload) => reload = true,
+                // Leftovers of the connection that just died
+                Ok(LoopMessage::Sway(_)) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(left),
+            }
+        }
+
+        let connected = Connection::new()
+            .context("Failed to connect to Sway IPC")
+            .and_then(|conn| subscribe_events(tx.clone()).map(|()| conn));
+        match connected {
+            Ok(conn) => {
+                info!(target: LOG_IPC, "Reconnected to sway after {} attempt(s)", attempt);
+                if reload {
+                    let _ = tx.send(LoopMessage::Reload);
+                }
+                return Some(conn);
+            }
+            Err(err) => warn!(target: LOG_IPC, "Reconnecting failed: {:#}", err),
+        }
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+/// Command line options. Each can also be set through an `AUTOTILE_*`
+/// environment variable (list options take comma separated values there, or
+/// `;` separated for rules, which contain commas themselves); the command
+/// line wins over the environment.
+#[derive(Parser)]
+#[clap(version, author, about)]
+struct Cli {
+    /// Read settings from this TOML file, or from stdin for `-`. Defaults to
+    /// ~/.config/autotiling-rs/config.toml when that exists. Flags given on the
+    /// command line or in the environment take precedence.
+    #[clap(long, short = 'c', env = "AUTOTILE_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Activate autotiling only on this workspace.
+    #[clap(long, short = 'w', env = "AUTOTILE_WORKSPACES", value_delimiter = ',')]
+    workspace: Vec<i32>,
+
+    /// Enable automatic window balancing (run 'balance' on new windows)
+    #[clap(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        num_args = 0..=1,
+        default_missing_value = "true",
+        env = "AUTOTILE_BALANCE"
+    )]
+    balance: bool,
+
+    /// Never balance the container of windows with this class or app_id.
+    #[clap(long, env = "AUTOTILE_NO_BALANCE_APPS", value_delimiter = ',')]
+    no_balance_app: Vec<String>,
+
+    /// Wake the event loop after this many milliseconds without events.
+    #[clap(long, default_value_t = 1000, env = "AUTOTILE_EVENT_TIMEOUT_MS")]
+    event_timeout_ms: u64,
+
+    /// Arrange floating windows on a workspace into a non-overlapping grid.
+    #[clap(long, env = "AUTOTILE_FLOAT_GRID")]
+    float_grid: bool,
+
+    /// Decide a new window's first split from its own initial geometry.
+    #[clap(long, env = "AUTOTILE_NEW_WINDOW_ASPECT_BIAS")]
+    new_window_aspect_bias: bool,
+
+    /// Share of the container given to a master window, between 0.5 and 0.7, or
+    /// as a percentage between 50 and 70. Other values are rejected.
+    #[clap(
+        long,
+        default_value_t = 0.6,
+        value_parser = parse_master_percent,
+        env = "AUTOTILE_MASTER_PERCENT"
+    )]
+    master_percent: f32,
+
+    /// Give windows with this class or app_id the master share when focused, either
+    /// --master-percent or their own as in `firefox=0.65` or `firefox=65`.
+    #[clap(
+        long,
+        value_parser = parse_master_app,
+        env = "AUTOTILE_MASTER_APPS",
+        value_delimiter = ','
+    )]
+    master_app: Vec<(String, Option<f32>)>,
+
+    /// Autotile only windows with this class or app_id; all others are left alone.
+    #[clap(long, env = "AUTOTILE_ONLY_APPS", value_delimiter = ',')]
+    only_app: Vec<String>,
+
+    /// Skip transient XWayland menus and tooltips (pass `false` to disable).
+    #[clap(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        env = "AUTOTILE_SKIP_OVERRIDE_REDIRECT"
+    )]
+    skip_override_redirect: bool,
+
+    /// Also run 'balance' whenever focus changes (rate limited).
+    #[clap(long, env = "AUTOTILE_BALANCE_ON_EVERY_FOCUS")]
+    balance_on_every_focus: bool,
+
+    /// Open new windows after (right of / below) or before the focused window.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = NewWindowSide::After,
+        env = "AUTOTILE_NEW_WINDOW_SIDE"
+    )]
+    new_window_side: NewWindowSide,
+
+    /// Every this many seconds, re-check all managed workspaces and fix drifted splits.
+    #[clap(long, env = "AUTOTILE_RESWEEP_INTERVAL")]
+    resweep_interval: Option<u64>,
+
+    /// Suspend autotiling while sway is in this binding mode (e.g. "resize").
+    #[clap(long, env = "AUTOTILE_PAUSE_IN_MODES", value_delimiter = ',')]
+    pause_in_mode: Vec<String>,
+
+    /// Append every handled window event, the tree and the decision to this file as JSON lines.
+    #[clap(long, env = "AUTOTILE_RECORD_SESSIONS")]
+    record_sessions: Option<PathBuf>,
+
+    /// With --balance-on-every-focus, only balance containers whose largest child
+    /// is more than this many times the size of the smallest.
+    #[clap(long, default_value_t = 1.05, env = "AUTOTILE_BALANCE_THRESHOLD")]
+    balance_threshold: f64,
+
+    /// Send new windows of an app to a workspace: <app>=<workspace>[:<layout>].
+    #[clap(long, env = "AUTOTILE_ASSIGNMENTS", value_delimiter = ',')]
+    assign: Vec<AssignRule>,
+
+    /// Treat windows matching all conditions as masters, e.g. class=firefox,output=DP-1.
+    /// Keys: class, app_id, title (substring), output, workspace.
+    #[clap(long, env = "AUTOTILE_MASTER_RULES", value_delimiter = ';')]
+    master_rule: Vec<MatchRule>,
+
+    /// Never autotile windows matching all conditions (same syntax as --master-rule).
+    #[clap(long, env = "AUTOTILE_EXCLUDE_RULES", value_delimiter = ';')]
+    exclude_rule: Vec<MatchRule>,
+
+    /// Ignore focus changes that did not follow a key or mouse binding within this many
+    /// milliseconds, so windows stealing focus don't reshape the layout.
+    #[clap(long, env = "AUTOTILE_FOCUS_GUARD_MS")]
+    focus_guard_ms: Option<u64>,
+
+    /// Stop autotiling a workspace once it holds more than this many windows.
+    #[clap(long, env = "AUTOTILE_MAX_MANAGED_WINDOWS")]
+    max_managed_windows: Option<usize>,
+
+    /// Demo mode: alternate the focused container between splith and splitv on a timer.
+    #[clap(long, env = "AUTOTILE_DEMO")]
+    demo: bool,
+
+    /// Milliseconds between layout flips in --demo mode.
+    #[clap(long, default_value_t = 2000, env = "AUTOTILE_DEMO_INTERVAL_MS")]
+    demo_interval_ms: u64,
+
+    /// Keep the current split while the aspect ratio is within this distance of the
+    /// switch point (1.1), so near-square windows don't flip back and forth.
+    #[clap(
+        long,
+        visible_alias = "aspect-hysteresis",
+        default_value_t = 0.0,
+        env = "AUTOTILE_SPLIT_THRESHOLD"
+    )]
+    split_threshold: f32,
+
+    /// Per-app split dead zone, e.g. `firefox=0.3`; overrides --split-threshold.
+    #[clap(
+        long,
+        value_parser = parse_app_value,
+        env = "AUTOTILE_APP_SPLIT_THRESHOLDS",
+        value_delimiter = ','
+    )]
+    app_split_threshold: Vec<(String, f32)>,
+
+    /// Only decide splits when a window is created, never on focus changes.
+    #[clap(long, env = "AUTOTILE_CONSERVATIVE")]
+    conservative: bool,
+
+    /// Compute aspect ratios from the whole container (`rect`) or from the content
+    /// area below the title bar (`content`).
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = AspectGeometry::Rect,
+        env = "AUTOTILE_ASPECT_GEOMETRY"
+    )]
+    aspect_geometry: AspectGeometry,
+
+    /// What to do when a master window closes: `promote` the next window of its
+    /// container to master, or `keep` the remaining windows as they are.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = MasterCloseAction::Promote,
+        env = "AUTOTILE_ON_MASTER_CLOSE"
+    )]
+    on_master_close: MasterCloseAction,
+
+    /// Back off (no splits, balancing deferred) while more than this many windows
+    /// open or close within --churn-window-ms, e.g. under build tools spawning
+    /// short-lived terminals.
+    #[clap(long, env = "AUTOTILE_CHURN_THRESHOLD")]
+    churn_threshold: Option<usize>,
+
+    /// Milliseconds over which --churn-threshold counts window opens and closes.
+    #[clap(long, default_value_t = 2000, env = "AUTOTILE_CHURN_WINDOW_MS")]
+    churn_window_ms: u64,
+
+    /// Longest time in milliseconds to wait for a new window's geometry to settle
+    /// before balancing. The balance comes sooner as soon as two reads agree.
+    #[clap(long, default_value_t = 200, env = "AUTOTILE_SETTLE_MAX_MS")]
+    settle_max_ms: u64,
+
+    /// Window changes after which master sizing is re-applied: focus, new, close,
+    /// move and/or floating.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "focus,new,close",
+        env = "AUTOTILE_MASTER_TRIGGERS"
+    )]
+    master_triggers: Vec<MasterTrigger>,
+
+    /// Only the first window of each --master-app on a workspace is master; further
+    /// windows of the same app there go to the stack.
+    #[clap(long, env = "AUTOTILE_MASTER_FIRST_WINDOW")]
+    master_first_window: bool,
+
+    /// Move newly opened master-app windows to the master position right away:
+    /// `swap` with an existing master, `keep` an existing master, or `off`.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = MasterOpenPolicy::Off,
+        env = "AUTOTILE_MASTER_ON_OPEN"
+    )]
+    master_on_open: MasterOpenPolicy,
+
+    /// Decide splits with an expression instead of the aspect ratio rule, e.g.
+    /// "splitv if height > 1.3 * width else splith". Variables: width, height,
+    /// aspect, depth, count; the result is splith, splitv or keep.
+    #[clap(long, env = "AUTOTILE_SPLIT_EXPR")]
+    split_expr: Option<SplitExpr>,
+
+    /// Send at most this many split commands per second; balancing is limited
+    /// separately.
+    #[clap(long, env = "AUTOTILE_MAX_SPLITS_PER_SEC")]
+    max_splits_per_sec: Option<u32>,
+
+    /// Send at most this many balance commands per second.
+    #[clap(long, env = "AUTOTILE_MAX_BALANCES_PER_SEC")]
+    max_balances_per_sec: Option<u32>,
+
+    /// Layout to give a workspace when its first window opens (none, splith or
+    /// splitv), so the windows that follow behave predictably.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = FirstWindowLayout::None,
+        env = "AUTOTILE_FIRST_WINDOW_LAYOUT"
+    )]
+    first_window_layout: FirstWindowLayout,
+
+    /// After a binding runs split, layout, resize or move, leave the affected
+    /// container alone for this many milliseconds.
+    #[clap(long, env = "AUTOTILE_MANUAL_GRACE_MS")]
+    manual_grace_ms: Option<u64>,
+
+    /// IPC dialect of the compositor: `sway`, `i3` (no `balance` command, it is
+    /// emulated with resizes) or `auto` to detect it from the version reply.
+    #[clap(long, value_enum, default_value_t = IpcFlavor::Auto, env = "AUTOTILE_IPC_FLAVOR")]
+    ipc_flavor: IpcFlavor,
+
+    /// Stop splitting windows nested more than this many levels below their
+    /// workspace. Unlimited by default.
+    #[clap(long, env = "AUTOTILE_MAX_DEPTH")]
+    max_depth: Option<usize>,
+
+    /// Give a new window this share of its container (0.1 - 0.9) instead of an
+    /// even split. Applied after --balance, so the new window keeps its share and
+    /// its siblings share the rest; later balances even it out again.
+    #[clap(long, env = "AUTOTILE_NEW_WINDOW_RATIO")]
+    new_window_ratio: Option<f32>,
+
+    /// Never autotile windows with this class or app_id, and don't rebalance when
+    /// they open.
+    #[clap(long, env = "AUTOTILE_EXCLUDE_APPS", value_delimiter = ',')]
+    exclude_app: Vec<String>,
+
+    /// Coalesce focus events arriving within this many milliseconds of each other
+    /// (e.g. while alt-tabbing) into one decision for the last focused window.
+    /// New and closed windows are never debounced.
+    #[clap(long, env = "AUTOTILE_DEBOUNCE_MS")]
+    debounce_ms: Option<u64>,
+
+    /// Log every command the daemon would send at info level instead of sending it.
+    /// Events and the tree are still read as usual.
+    #[clap(long, env = "AUTOTILE_DRY_RUN")]
+    dry_run: bool,
+
+    /// When a tabbed or stacked container has focus, split the tiled window or
+    /// container inside its visible tab instead of leaving it alone.
+    #[clap(long, env = "AUTOTILE_TILE_IN_TABBED")]
+    tile_in_tabbed: bool,
+
+    /// Sway's inner gap in pixels (`gaps inner`), so aspect ratios are computed
+    /// from the windows without the gaps around and between them.
+    #[clap(long, default_value_t = 0, env = "AUTOTILE_GAP")]
+    gap: i32,
+
+    /// Format of log lines: `text` for reading, `json` for log collectors
+    /// (one object per line with level, timestamp, message and decision fields).
+    #[clap(long, value_enum, default_value_t = LogFormat::Text, env = "AUTOTILE_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Balance only on this workspace (repeatable), independently of --workspace.
+    /// Without it, --balance applies everywhere.
+    #[clap(long, env = "AUTOTILE_BALANCE_WORKSPACES", value_delimiter = ',')]
+    balance_workspace: Vec<i32>,
+
+    /// Never balance on this workspace (repeatable), e.g. one you resize by hand.
+    #[clap(long, env = "AUTOTILE_NO_BALANCE_WORKSPACES", value_delimiter = ',')]
+    no_balance_workspace: Vec<i32>,
+
+    /// Treat windows carrying this sway mark as masters, e.g. `_master` with
+    /// `bindsym $mod+m mark --toggle _master`. Works alongside --master-app.
+    #[clap(long, env = "AUTOTILE_MASTER_MARK")]
+    master_mark: Option<String>,
+
+    /// `square` splits windows along their longer edge so they stay close to
+    /// square; `inverse` splits along the shorter one, keeping tall windows
+    /// tall and narrow and wide ones wide and flat.
+    #[clap(long, value_enum, default_value_t = SplitMode::Square, env = "AUTOTILE_SPLIT_MODE")]
+    split_mode: SplitMode,
+
+    /// Balance this many milliseconds after a window opens. Without it, the
+    /// balance comes once the window's geometry settles, after at most
+    /// --settle-max-ms. Either way other events are handled in the meantime,
+    /// and windows opening before the balance is due join it, so a burst is
+    /// balanced once.
+    #[clap(long, env = "AUTOTILE_BALANCE_DELAY_MS")]
+    balance_delay_ms: Option<u64>,
+
+    /// `aspect` picks each split from the window's shape; `spiral` alternates
+    /// side by side and stacked with every new window, the classic spiral
+    /// (dwindle) tiling.
+    #[clap(long, value_enum, default_value_t = TilingLayout::Aspect, env = "AUTOTILE_LAYOUT")]
+    layout: TilingLayout,
+
+    /// Once a container holds this many windows side by side, stack new ones
+    /// below the focused window instead of adding columns, whatever the aspect
+    /// ratio says. Keeps ultrawide outputs from filling with thin columns.
+    #[clap(long, env = "AUTOTILE_MAX_COLUMNS")]
+    max_columns: Option<usize>,
+
+    /// Always give an app's windows this split, e.g. `foot=splitv`, whatever
+    /// their shape. Adds to the `[app_split]` table of the configuration file.
+    #[clap(
+        long,
+        value_parser = parse_app_split,
+        env = "AUTOTILE_APP_SPLITS",
+        value_delimiter = ','
+    )]
+    app_split: Vec<(String, SplitDirection)>,
+
+    /// Let the first this many master windows of a container (by app, rule or
+    /// --master-mark) share the master region, each getting an even part of it.
+    #[clap(long, default_value_t = 1, env = "AUTOTILE_MASTER_COUNT")]
+    master_count: usize,
+
+    /// Don't send a container the split it was sent less than this many
+    /// milliseconds ago. The tree read after a command can lag behind it, which
+    /// would otherwise have the same split sent over and over.
+    #[clap(long, env = "AUTOTILE_COMMAND_COOLDOWN_MS")]
+    command_cooldown_ms: Option<u64>,
+
+    /// Turn inner gaps off on a workspace with a single tiled window and set
+    /// them back to this many pixels once there are more. Leave this off when
+    /// sway's own `smart_gaps` is enabled: both would set the same gaps.
+    #[clap(long, env = "AUTOTILE_SMART_GAPS")]
+    smart_gaps: Option<i32>,
+
+    /// How often to retry connecting to sway at startup, for when the daemon
+    /// starts before sway's IPC socket is up.
+    #[clap(long, default_value_t = 10, env = "AUTOTILE_CONNECT_RETRIES")]
+    connect_retries: u32,
+
+    /// Milliseconds before the first startup connection retry; doubled after
+    /// every failed one, up to 5 seconds.
+    #[clap(long, default_value_t = 100, env = "AUTOTILE_CONNECT_RETRY_MS")]
+    connect_retry_ms: u64,
+
+    /// Print the containers of the focused workspace sorted by imbalance, then exit.
+    #[clap(long, env = "AUTOTILE_REPORT_IMBALANCE")]
+    report_imbalance: bool,
+
+    /// With --report-imbalance, balance the containers above --balance-threshold.
+    #[clap(long, requires = "report_imbalance", env = "AUTOTILE_FIX")]
+    fix: bool,
+
+    /// Check that the tree has the shape the daemon relies on, print a report and
+    /// exit (non-zero if a check fails).
+    #[clap(long, env = "AUTOTILE_VALIDATE_TREE")]
+    validate_tree: bool,
+
+    /// Print what the daemon would decide for the focused window right now,
+    /// without sending any command, then exit. JSON with --log-format json.
+    #[clap(long, env = "AUTOTILE_EXPLAIN")]
+    explain: bool,
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// env_logger's usual human-readable lines
+    Text,
+    /// One JSON object per line, with structured fields next to the message
+    Json,
+}
+
+/// Collects the structured fields of a log record into a JSON object
+struct JsonFields(serde_json::Map<String, Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = if let Some(n) = value.to_i64() {
+            Value::from(n)
+        } else if let Some(n) = value.to_f64() {
+            Value::from(n)
+        } else if let Some(b) = value.to_bool() {
+            Value::from(b)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// A log record as one JSON line: level, timestamp, target, message and any
+/// structured fields the call site attached
+fn write_json_record(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let mut fields = JsonFields(serde_json::Map::new());
+    fields.0.insert("level".into(), record.level().as_str().into());
+    fields.0.insert("timestamp".into(), buf.timestamp_millis().to_string().into());
+    fields.0.insert("target".into(), record.target().into());
+    fields.0.insert("message".into(), record.args().to_string().into());
+    // A field that can't be read is left out rather than losing the line
+    let _ = record.key_values().visit(&mut fields);
+    writeln!(buf, "{}", Value::Object(fields.0))
+}
+
+/// Install env_logger behind `CountingLogger`, so the exit summary knows how
+/// many errors were logged
+fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(write_json_record);
+    }
+    let inner = builder.build();
+    log::set_max_level(inner.filter());
+    if log::set_boxed_logger(Box::new(CountingLogger { inner })).is_err() {
+        eprintln!("A logger was already installed");
+    }
+}
+
+/// Combine the command line, the environment and the configuration file into
+/// the daemon's configuration. Runs again whenever the file is reloaded.
+fn build_config(args: &Cli, matches: &ArgMatches, file: FileConfig) -> AutoTileConfig {
+    // The file fills in whatever the command line and environment left at its default
+    let explicit = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    let enable_balance = match file.enable_balance {
+        Some(balance) if !explicit("balance") => balance,
+        _ => args.balance,
+    };
+
+    let master_percent = match file.master_percent {
+        Some(pct) if !explicit("master_percent") => pct,
+        _ => args.master_percent,
+    };
+    let split_expr = match file.split_expr {
+        Some(expr) if !explicit("split_expr") => Some(expr),
+        _ => args.split_expr.clone(),
+    };
+    let workspace_overrides = file.workspace;
+    let mut app_splits: HashMap<String, &'static str> =
+        file.app_split.into_iter().map(|(app, split)| (app, split.command())).collect();
+    app_splits.extend(args.app_split.iter().map(|(app, split)| (app.clone(), split.command())));
+    let mut master_rules = args.master_rule.clone();
+    let mut exclude_rules = args.exclude_rule.clone();
+    for entry in file.rules {
+        match entry.action {
+            RuleAction::Master => master_rules.push(entry.rule),
+            RuleAction::Exclude => exclude_rules.push(entry.rule),
+        }
+    }
+    let mut master_apps = file.master_apps;
+    master_apps.extend(
+        args.master_app
+            .iter()
+            .map(|(app, pct)| (app.clone(), pct.unwrap_or(master_percent))),
+    );
+    AutoTileConfig {
+        workspaces: args.workspace.iter().copied().collect(),
+        enable_balance,
+        no_balance_apps: app_set(args.no_balance_app.clone()),
+        event_timeout: Duration::from_millis(args.event_timeout_ms),
+        float_grid: args.float_grid,
+        new_window_aspect_bias: args.new_window_aspect_bias,
+        master_percent,
+        master_apps,
+        only_apps: app_set(args.only_app.clone()),
+        skip_override_redirect: args.skip_override_redirect,
+        balance_on_every_focus: args.balance_on_every_focus,
+        new_window_side: args.new_window_side,
+        resweep_interval: args.resweep_interval.map(Duration::from_secs),
+        pause_modes: args.pause_in_mode.iter().cloned().collect(),
+        balance_threshold: args.balance_threshold,
+        assignments: args.assign.clone(),
+        master_rules,
+        exclude_rules,
+        focus_guard: args.focus_guard_ms.map(Duration::from_millis),
+        max_managed_windows: args.max_managed_windows,
+        demo_interval: args
+            .demo
+            .then_some(Duration::from_millis(args.demo_interval_ms)),
+        split_threshold: args.split_threshold,
+        app_split_thresholds: args.app_split_threshold.iter().cloned().collect(),
+        conservative: args.conservative,
+        aspect_geometry: args.aspect_geometry,
+        master_close: args.on_master_close,
+        churn_threshold: args.churn_threshold,
+        churn_window: Duration::from_millis(args.churn_window_ms),
+        settle_max_wait: Duration::from_millis(args.settle_max_ms),
+        master_triggers: args.master_triggers.clone(),
+        master_first_window: args.master_first_window,
+        master_on_open: args.master_on_open,
+        split_expr,
+        max_splits_per_sec: args.max_splits_per_sec,
+        max_balances_per_sec: args.max_balances_per_sec,
+        first_window_layout: args.first_window_layout,
+        manual_grace: args.manual_grace_ms.map(Duration::from_millis),
+        ipc_flavor: args.ipc_flavor,
+        max_depth: args.max_depth,
+        new_window_ratio: args
+            .new_window_ratio
+            .map(|ratio| ratio.clamp(NEW_WINDOW_RATIO_RANGE.0, NEW_WINDOW_RATIO_RANGE.1)),
+        exclude_apps: app_set(args.exclude_app.clone()),
+        workspace_overrides,
+        focus_debounce: args
+            .debounce_ms
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis),
+        dry_run: args.dry_run,
+        tile_in_tabbed: args.tile_in_tabbed,
+        gap: args.gap.max(0),
+        balance_workspaces: args.balance_workspace.iter().copied().collect(),
+        no_balance_workspaces: args.no_balance_workspace.iter().copied().collect(),
+        master_mark: args.master_mark.clone(),
+        split_mode: args.split_mode,
+        balance_delay: args.balance_delay_ms.map(Duration::from_millis),
+        layout: args.layout,
+        max_columns: args.max_columns,
+        app_splits,
+        features: Features::new(args.ipc_flavor, None),
+        master_count: args.master_count.max(1),
+        command_cooldown: args.command_cooldown_ms.map(Duration::from_millis),
+        smart_gaps: args.smart_gaps.map(|gap| gap.max(0)),
+    }
+}
+
+/// Re-read the configuration file for SIGHUP and rebuild the configuration
+/// around it. The compositor's IPC flavor was detected once and is kept.
+fn reload_config(
+    args: &Cli,
+    matches: &ArgMatches,
+    current: &AutoTileConfig,
+) -> Result<AutoTileConfig> {
+    if args.config.as_deref() == Some(Path::new("-")) {
+        bail!("the configuration was read from stdin and can't be read again");
+    }
+    let file = FileConfig::discover(args.config.as_deref()).context("Failed to reload")?;
+    let mut config = build_config(args, matches, file);
+    config.ipc_flavor = current.ipc_flavor;
+    config.features = current.features;
+    Ok(config)
+}
+
+fn main() -> Result<()> {
+    let matches = Cli::command().get_matches();
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    init_logging(args.log_format);
+    let mut state = DaemonState {
+        current_mode: "default".to_string(),
+        ..DaemonState::default()
+    };
+
+    // Every way out of the daemon passes here, so even a session that failed
+    // reports what it did
+    let result = run(&args, &matches, &mut state);
+    if !(args.report_imbalance || args.validate_tree || args.explain) {
+        info!("{}", state.stats.summary());
+    }
+    result
+}
+
+/// Set up from `args` and run the daemon (or one of its one-shot modes)
+/// until it is told to stop
+fn run(args: &Cli, matches: &ArgMatches, state: &mut DaemonState) -> Result<()> {
+    let file = FileConfig::discover(args.config.as_deref())?;
+    let mut config = build_config(args, matches, file);
+
+    info!("Jarvis Autotiling initialized. Workspaces: {:?}, Balance: {}", 
+        config.workspaces, config.enable_balance);
+
+    let mut recorder = match &args.record_sessions {
+        Some(path) => Some(SessionRecorder::open(path)?),
+        None => None,
+    };
+
+    // Connect to Sway, which may still be starting up at login
+    let retry_delay = Duration::from_millis(args.connect_retry_ms);
+    let mut conn = connect_with_retries(
+        "Connecting to sway",
+        args.connect_retries,
+        retry_delay,
+        || Connection::new().context("Failed to connect to Sway IPC"),
+    )?;
+    let version = match conn.get_version() {
+        Ok(version) => Some(version),
+        Err(err) => {
+            warn!(target: LOG_IPC, "get_version() failed: {}", err);
+            None
+        }
+    };
+    config.ipc_flavor = detect_ipc_flavor(version.as_ref(), config.ipc_flavor);
+    config.features = Features::new(config.ipc_flavor, version.map(|v| (v.major, v.minor)));
+    info!(target: LOG_IPC, "IPC flavor: {:?}, {:?}", config.ipc_flavor, config.features);
+
+    if args.report_imbalance {
+        return report_imbalance(&mut conn, &config, args.fix);
+    }
+    if args.validate_tree {
+        return validate_tree(&mut conn);
+    }
+    if args.explain {
+        return explain(&mut conn, &config, args.log_format);
+    }
+    
+    // Subscribe to Window events. 
+    // THIS is how you do it, Tony. No more 'while loop sleep'.
+    // The blocking event iterator lives on its own thread so the loop can wake up
+    // every `event_timeout` even when sway is quiet.
+    let (tx, rx) = mpsc::channel();
+    let control_tx = tx.clone();
+    let signal_tx = tx.clone();
+    connect_with_retries("Subscribing to events", args.connect_retries, retry_delay, || {
+        subscribe_events(tx.clone())
+    })?;
+    // Both connections are up: a Type=notify service is ready now
+    if let Err(err) = notify::notify("READY=1") {
+        warn!("Failed to notify systemd: {:#}", err);
+    }
+    let mut watchdog = notify::Watchdog::from_env();
+
+    // Initial pass: fix the currently focused window immediately
+    let initial = update_split_direction(&mut conn, &config, state, SplitRequest::default());
+    if let Err(e) = initial {
+        error!(target: LOG_DECISION, "Initial setup failed: {}", e);
+    }
+
+    // Event Loop
+    // Turn signals into messages so the loop can wind down properly, or swap in
+    // a reloaded configuration between two events
+    let mut signals =
+        Signals::new([SIGTERM, SIGINT, SIGHUP]).context("Failed to install signal handler")?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let message = match signal {
+                SIGHUP => LoopMessage::Reload,
+                _ => LoopMessage::Shutdown(signal),
+            };
+            let last = matches!(message, LoopMessage::Shutdown(_));
+            if signal_tx.send(message).is_err() || last {
+                break;
+            }
+        }
+    });
+
+    // Control socket: its requests are executed here, on the loop that owns `conn`
+    let forward = move |req| control_tx.send(LoopMessage::Control(req)).is_ok();
+    let control_socket = match control::spawn_listener(forward, state.subscribers.clone()) {
+        Ok(path) => Some(path),
+        Err(err) => {
+            error!(target: control::LOG_TARGET, "Control socket unavailable: {:#}", err);
+            None
+        }
+    };
+
+    // Messages that arrived while a burst of focus events was being coalesced
+    let mut queued = VecDeque::new();
+    loop {
+        let message = match queued.pop_front() {
+            Some(message) => Ok(message),
+            None => {
+                let mut wake = watchdog
+                    .as_ref()
+                    .map_or(config.event_timeout, |w| w.interval().min(config.event_timeout));
+                if let Some(deadline) = next_deadline(&config, state, Instant::now()) {
+                    wake = wake.min(deadline);
+                }
+                rx.recv_timeout(wake)
+            }
+        };
+        if let Ok(LoopMessage::Sway(Ok(_))) = message {
+            state.stats.events += 1;
+        }
+        let message = match (config.focus_debounce, message) {
+            (Some(window), Ok(LoopMessage::Sway(Ok(Event::Window(e)))))
+                if e.change == WindowChange::Focus =>
+            {
+                Ok(debounce_focus(&rx, e, window, &mut queued, &mut state.stats))
+            }
+            (_, message) => message,
+        };
+        match message {
+            Ok(LoopMessage::Sway(Ok(Event::Window(e)))) => {
+                // The recording needs the tree as the decision saw it, so read it first
+                let before = match recorder {
+                    Some(_) => get_tree(&mut conn).ok(),
+                    None => None,
+                };
+                let decision = handle_window_event(&mut conn, &config, state, &e);
+                if let (Some(recorder), Some(tree)) = (recorder.as_mut(), before) {
+                    if let Err(err) = recorder.record(&tree, &e, decision) {
+                        error!("Error recording session: {:#}", err);
+                    }
+                }
+            }
+            Ok(LoopMessage::Sway(Ok(Event::Mode(e)))) => {
+                debug!(target: LOG_IPC, "Binding mode changed to '{}'", e.change);
+                state.current_mode = e.change;
+            }
+            Ok(LoopMessage::Sway(Ok(Event::Binding(e)))) => {
+                if let Err(err) = note_binding(&mut conn, &config, state, &e) {
+                    error!(target: LOG_DECISION, "Error handling binding: {}", err);
+                }
+            }
+            Ok(LoopMessage::Sway(Ok(Event::Output(_)))) => {
+                debug!(target: LOG_IPC, "Output configuration changed");
+                if let Err(err) = handle_output_change(&mut conn, &config, state) {
+                    error!(target: LOG_DECISION, "Error handling output change: {}", err);
+                }
+            }
+            Ok(LoopMessage::Sway(Ok(Event::Workspace(e))))
+                if e.change == WorkspaceChange::Focus =>
+            {
+                if let Some(workspace) = &e.current {
+                    let result = handle_workspace_focus(&mut conn, &config, state, workspace);
+                    if let Err(err) = result {
+                        error!(target: LOG_DECISION, "Error handling workspace focus: {}", err);
+                    }
+                }
+            }
+            Ok(LoopMessage::Sway(Ok(_))) => {} // Ignore other events
+            Ok(LoopMessage::Control(req)) => {
+                let reply = handle_control_command(&mut conn, &config, state, req.command)
+                    .unwrap_or_else(|err| format!("error: {:#}", err));
+                let _ = req.reply.send(reply);
+            }
+            Ok(LoopMessage::Shutdown(signal)) => {
+                info!("Received signal {}, shutting down", signal);
+                break;
+            }
+            Ok(LoopMessage::Reload) => match reload_config(args, matches, &config) {
+                Ok(reloaded) => {
+                    config = reloaded;
+                    info!("Configuration reloaded");
+                }
+                Err(err) => error!("Keeping the previous configuration: {:#}", err),
+            },
+            Ok(LoopMessage::Sway(Err(e))) => {
+                // Sway restarted or the socket went away: config and state
+                // carry over, only the connections are replaced
+                error!(target: LOG_IPC, "Event stream error: {}", e);
+                conn = match reconnect(&tx, &rx) {
+                    Some(conn) => conn,
+                    None => break,
+                };
+                let request = SplitRequest::default();
+                if let Err(err) = update_split_direction(&mut conn, &config, state, request) {
+                    error!(target: LOG_DECISION, "Setup after reconnecting failed: {}", err);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // Keepalive tick: nothing arrived, a chance to look at shared state
+                debug!(target: LOG_IPC, "No events for {:?}", config.event_timeout);
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                error!(target: LOG_IPC, "Event stream closed");
+                break;
+            }
+        }
+
+        if let Some(watchdog) = watchdog.as_mut() {
+            if let Err(err) = watchdog.maybe_ping() {
+                warn!("Failed to ping the systemd watchdog: {:#}", err);
+            }
+        }
+        if let Err(err) = maybe_resweep(&mut conn, &config, state) {
+            error!(target: LOG_DECISION, "Error during re-sweep: {}", err);
+        }
+        if let Err(err) = maybe_demo_flip(&mut conn, &config, state) {
+            error!(target: LOG_DECISION, "Error in demo mode: {}", err);
+        }
+        if let Err(err) = maybe_end_churn(&mut conn, &config, state) {
+            error!(target: LOG_BALANCE, "Error balancing after churn: {}", err);
+        }
+        if let Err(err) = maybe_run_pending_balance(&mut conn, &config, state) {
+            error!(target: LOG_BALANCE, "Error running delayed balance: {}", err);
+        }
+    }
+
+    // Whatever ended the loop, leave nothing behind for the next instance
+    if let Err(err) = notify::notify("STOPPING=1") {
+        warn!("Failed to notify systemd: {:#}", err);
+    }
+    if let Some(path) = &control_socket {
+        control::remove_socket(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipc::MockIpc;
+    use serde_json::{json, Value};
+
+    /// Configuration as the daemon would build it from `args`
+    fn test_config(args: &[&str]) -> AutoTileConfig {
+        test_config_with(args, FileConfig::default())
+    }
+
+    /// Configuration from `args` on top of the configuration file `file`
+    fn test_config_with(args: &[&str], file: FileConfig) -> AutoTileConfig {
+        let argv = std::iter::once("autotiling-rs").chain(args.iter().copied());
+        let matches = Cli::command().get_matches_from(argv);
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        build_config(&cli, &matches, file)
+    }
+
+    fn window(id: i64, width: i32, height: i32) -> Value {
+        json!({
+            "id": id,
+            "type": "con",
+            "layout": "none",
+            "rect": { "x": 0, "y": 0, "width": width, "height": height },
+        })
+    }
+
+    fn with_app_id(mut node: Value, app_id: &str) -> Value {
+        node["app_id"] = app_id.into();
+        node
+    }
+
+    fn focused(mut node: Value) -> Value {
+        node["focused"] = true.into();
+        node
+    }
+
+    /// Focus stack of a node with `children`: the child holding the focused
+    /// window, or the first one
+    fn focus_of(children: &[Value]) -> Value {
+        let holds_focus = |child: &&Value| child.to_string().contains("\"focused\":true");
+        let child = children.iter().find(holds_focus).or(children.first());
+        child.map_or(json!([]), |child| json!([child["id"]]))
+    }
+
+    /// A split container holding `children`
+    fn container(id: i64, layout: &str, width: i32, height: i32, children: Vec<Value>) -> Value {
+        let mut node = window(id, width, height);
+        node["layout"] = layout.into();
+        node["focus"] = focus_of(&children);
+        node["nodes"] = children.into();
+        node
+    }
+
+    /// A tree with one output of the given size holding workspace 1, whose
+    /// `layout` container holds `windows`
+    fn tree(output: (i32, i32), layout: &str, windows: Vec<Value>) -> Node {
+        let focus = focus_of(&windows);
+        let rect = json!({ "x": 0, "y": 0, "width": output.0, "height": output.1 });
+        fallback::minimal_tree(&json!({
+            "id": 1,
+            "type": "root",
+            "focus": [2],
+            "nodes": [{
+                "id": 2,
+                "type": "output",
+                "name": "DP-1",
+                "rect": rect,
+                "focus": [3],
+                "nodes": [{
+                    "id": 3,
+                    "type": "workspace",
+                    "name": "1",
+                    "num": 1,
+                    "layout": layout,
+                    "rect": rect,
+                    "focus": focus,
+                    "nodes": windows,
+                }],
+            }],
+        }))
+        .unwrap()
+    }
+
+    /// A window event of kind `change` (`"new"`, `"focus"`, ...) for `node`
+    fn event(change: &str, node: Value) -> WindowEvent {
+        let container = fallback::minimal_tree(&node).unwrap();
+        serde_json::from_value(json!({ "change": change, "container": container })).unwrap()
+    }
+
+    /// A binding event running `command`
+    fn binding(command: &str) -> BindingEvent {
+        serde_json::from_value(json!({
+            "change": "run",
+            "binding": {
+                "command": command, "event_state_mask": ["Mod4"], "input_code": 0,
+                "symbol": "v", "input_type": "keyboard",
+            },
+        }))
+        .unwrap()
+    }
+
+    /// Every command of `commands` that runs `balance`
+    fn balances(commands: &[String]) -> Vec<&String> {
+        commands.iter().filter(|c| c.ends_with("balance")).collect()
+    }
+
+    fn decide(tree: Node, args: &[&str]) -> (Option<&'static str>, Vec<String>) {
+        decide_with(&mut DaemonState::default(), tree, &test_config(args))
+    }
+
+    /// The focus decision on `tree` from `state`, and the commands it sent
+    fn decide_with(
+        state: &mut DaemonState,
+        tree: Node,
+        config: &AutoTileConfig,
+    ) -> (Option<&'static str>, Vec<String>) {
+        let mut ipc = MockIpc::new(tree);
+        let split =
+            update_split_direction(&mut ipc, config, state, SplitRequest::default()).unwrap();
+        (split, ipc.commands)
+    }
+
+    #[test]
+    fn wide_window_splits_horizontally() {
+        let tree = tree((1920, 1080), "splith", vec![focused(window(10, 1600, 600))]);
+        let (split, commands) = decide(tree, &[]);
+        assert_eq!(split, Some("splith"));
+        assert_eq!(commands, ["[con_id=10] splith"]);
+    }
+
+    #[test]
+    fn identical_splits_wait_out_the_cooldown() {
+        let tree = tree((1920, 1080), "splith", vec![focused(window(10, 1600, 600))]);
+        let config = test_config(&["--command-cooldown-ms", "60000"]);
+        let mut state = DaemonState::default();
+        let mut ipc = MockIpc::new(tree);
+        for _ in 0..2 {
+            update_split_direction(&mut ipc, &config, &mut state, SplitRequest::default())
+                .unwrap();
+        }
+        assert_eq!(ipc.commands, ["[con_id=10] splith"]);
+        assert!(state.last_splits.contains_key(&10));
+    }
+
+    #[test]
+    fn startup_connection_is_retried() {
+        let mut attempts = 0;
+        let connected = connect_with_retries("Connecting", 3, Duration::ZERO, || {
+            attempts += 1;
+            if attempts < 3 {
+                anyhow::bail!("socket not ready");
+            }
+            Ok(attempts)
+        });
+        assert_eq!(connected.unwrap(), 3);
+
+        let mut attempts = 0;
+        let failed: Result<()> = connect_with_retries("Connecting", 2, Duration::ZERO, || {
+            attempts += 1;
+            anyhow::bail!("socket not ready")
+        });
+        assert!(failed.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn tall_window_splits_vertically() {
+        let tree = tree((1920, 1080), "splith", vec![focused(window(10, 600, 1600))]);
+        let (split, commands) = decide(tree, &[]);
+        assert_eq!(split, Some("splitv"));
+        assert_eq!(commands, ["[con_id=10] splitv"]);
+    }
+
+    #[test]
+    fn only_listed_apps_are_autotiled() {
+        let wide = |app: &str| {
+            let windows = vec![focused(with_app_id(window(10, 1600, 600), app))];
+            tree((1920, 1080), "splith", windows)
+        };
+        // Without a list every app is autotiled
+        assert_eq!(decide(wide("firefox"), &[]).0, Some("splith"));
+        assert_eq!(decide(wide("kitty"), &["--only-app", "kitty"]).0, Some("splith"));
+        let (split, commands) = decide(wide("firefox"), &["--only-app", "kitty"]);
+        assert_eq!(split, None);
+        assert!(commands.is_empty());
+    }
+
+    /// A view as sway reports it, `extra` keys added
+    fn sway_view(extra: Value) -> Value {
+        let rect = json!({ "x": 0, "y": 0, "width": 300, "height": 200 });
+        let mut view = json!({
+            "id": 10, "name": "menu", "type": "floating_con", "border": "none",
+            "current_border_width": 0, "layout": "none", "orientation": "none",
+            "percent": null, "rect": rect, "window_rect": rect, "deco_rect": rect,
+            "geometry": rect, "urgent": false, "focused": true, "focus": [],
+            "nodes": [], "floating_nodes": [], "sticky": false, "pid": 4242,
+            "fullscreen_mode": 0, "max_render_time": 0, "inhibit_idle": false,
+            "idle_inhibitors": { "user": "none", "application": "none" },
+        });
+        view.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        view
+    }
+
+    #[test]
+    fn xwayland_popups_are_detected_in_both_trees() {
+        let cases = [
+            (json!({ "shell": "xwayland", "window_properties": { "window_type": "menu" } }), true),
+            (json!({ "shell": "xwayland", "window_properties": { "transient_for": 7 } }), true),
+            (json!({ "shell": "xwayland", "window_properties": { "class": "Gimp" } }), false),
+            (json!({ "shell": "xdg_shell", "app_id": "foot" }), false),
+        ];
+        for (extra, popup) in cases {
+            let raw = sway_view(extra);
+            let typed: Node = serde_json::from_value(raw.clone()).unwrap();
+            let minimal = fallback::minimal_tree(&raw).unwrap();
+            assert_eq!(is_xwayland_popup(&typed), popup, "{}", raw);
+            assert_eq!(is_xwayland_popup(&minimal), popup, "minimal tree of {}", raw);
+        }
+
+        // A big transient window is a dialog, which is tiled
+        let mut dialog = sway_view(json!({
+            "shell": "xwayland", "window_properties": { "transient_for": 7 },
+        }));
+        dialog["geometry"] = json!({ "x": 0, "y": 0, "width": 800, "height": 600 });
+        assert!(!is_xwayland_popup(&fallback::minimal_tree(&dialog).unwrap()));
+    }
+
+    #[test]
+    fn paused_modes_suppress_splits_until_left() {
+        let config = test_config(&["--pause-in-mode", "resize"]);
+        let mut state = DaemonState::default();
+        let wide = tree((1920, 1080), "splith", vec![focused(window(10, 1600, 600))]);
+        let mut ipc = MockIpc::new(wide);
+        let focus = event("focus", window(10, 1600, 600));
+
+        state.current_mode = "resize".to_string();
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), None);
+        assert!(ipc.commands.is_empty());
+
+        state.current_mode = "default".to_string();
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), Some("splith"));
+    }
+
+    #[test]
+    fn bindings_with_layout_commands_are_recognized() {
+        assert!(is_layout_command("splitv"));
+        assert!(is_layout_command("focus left; resize grow width 10px"));
+        assert!(is_layout_command("[app_id=\"foot\"] move left"));
+        assert!(is_layout_command("exec foot, layout tabbed"));
+        assert!(!is_layout_command("exec foot"));
+        assert!(!is_layout_command("focus left"));
+        assert!(!is_layout_command("[app_id=\"foot\"] focus"));
+        assert!(!is_layout_command(""));
+
+        let windows = vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        let mut state = DaemonState::default();
+        let config = test_config(&["--manual-grace-ms", "60000"]);
+        note_binding(&mut ipc, &config, &mut state, &binding("focus right")).unwrap();
+        assert!(state.last_binding.is_some());
+        assert!(state.manual_grace.is_empty());
+        note_binding(&mut ipc, &config, &mut state, &binding("splitv")).unwrap();
+        // The focused window and the container it sits in
+        assert!(in_manual_grace(&state, 10));
+        assert!(in_manual_grace(&state, 3));
+        assert!(!in_manual_grace(&state, 11));
+    }
+
+    #[test]
+    fn manual_splits_hold_off_autotiling_for_the_grace_period() {
+        let windows = || vec![focused(window(10, 1600, 600)), window(11, 320, 600)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows()));
+        let mut state = DaemonState::default();
+        let config = test_config(&["--manual-grace-ms", "60000"]);
+        note_binding(&mut ipc, &config, &mut state, &binding("splitv")).unwrap();
+
+        let split = update_split_direction(&mut ipc, &config, &mut state, SplitRequest::default());
+        assert_eq!(split.unwrap(), None);
+        balance_siblings(&mut ipc, &config, &mut state, 10).unwrap();
+        assert!(ipc.commands.is_empty(), "{:?}", ipc.commands);
+
+        // Once the grace period is over the window is autotiled again
+        let past = Instant::now() - Duration::from_millis(1);
+        state.manual_grace.values_mut().for_each(|until| *until = past);
+        let split = update_split_direction(&mut ipc, &config, &mut state, SplitRequest::default());
+        assert_eq!(split.unwrap(), Some("splith"));
+        assert_eq!(ipc.commands, ["[con_id=10] splith"]);
+
+        // Without --manual-grace-ms a manual split holds nothing off
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows()));
+        let mut state = DaemonState::default();
+        note_binding(&mut ipc, &test_config(&[]), &mut state, &binding("splitv")).unwrap();
+        assert!(state.manual_grace.is_empty());
+    }
+
+    #[test]
+    fn dry_run_sends_nothing() {
+        let tree = tree((1920, 1080), "splith", vec![focused(window(10, 1600, 600))]);
+        let (split, commands) = decide(tree, &["--dry-run"]);
+        assert_eq!(split, Some("splith"));
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn near_square_window_follows_output_orientation() {
+        // A near-square window stacked on another in a near-square half
+        let half = || {
+            let windows = vec![focused(window(10, 960, 1000)), window(11, 960, 80)];
+            vec![container(20, "splitv", 960, 1080, windows), window(21, 960, 1080)]
+        };
+        let (landscape, _) = decide(tree((1920, 1080), "splith", half()), &[]);
+        let (portrait, _) = decide(tree((1080, 1920), "splitv", half()), &[]);
+        assert_eq!(landscape, Some("splith"));
+        assert_eq!(portrait, Some("splitv"));
+    }
+
+    #[test]
+    fn master_apps_take_their_own_share() {
+        let args = ["--master-app", "Firefox=0.65,emacs", "--master-app", "steam=70"];
+        let config = test_config(&args);
+        assert_eq!(config.master_apps["firefox"], 0.65);
+        assert_eq!(config.master_apps["emacs"], 0.6);
+        assert_eq!(config.master_apps["steam"], 0.7);
+        assert!(parse_master_app("steam=big").unwrap_err().contains("invalid master share"));
+        assert!(parse_master_app("steam=0.9").unwrap_err().contains("outside 0.5 - 0.7"));
+    }
+
+    #[test]
+    fn master_shares_accept_fractions_and_percentages() {
+        assert_eq!(master_share(0.55), Ok(0.55));
+        assert_eq!(master_share(60.0), Ok(0.6));
+        assert!(master_share(6.0).is_err());
+        assert!(master_share(0.9).is_err());
+        assert!(parse_master_percent("6").is_err());
+        let file = FileConfig::from_reader("master_percent = 65".as_bytes()).unwrap();
+        assert_eq!(file.master_percent, Some(0.65));
+        let file = FileConfig::from_reader("[master_apps]\nfirefox = 0.2".as_bytes());
+        assert!(format!("{:#}", file.unwrap_err()).contains("firefox"));
+    }
+
+    #[test]
+    fn rotating_moves_the_top_of_the_stack_to_the_bottom() {
+        let stack = vec![window(11, 0, 0), window(12, 0, 0), window(13, 0, 0)];
+        let nested = container(20, "splitv", 0, 0, stack.clone());
+        let flat = [vec![window(10, 0, 0)], stack].concat();
+        let expected = [
+            "[con_id=11] swap container with con_id 12",
+            "[con_id=11] swap container with con_id 13",
+        ];
+        for children in [flat, vec![window(10, 0, 0), nested]] {
+            let tree = tree((1920, 1080), "splith", children);
+            let workspace = find_focused_workspace(&tree).unwrap();
+            assert_eq!(rotate_stack_commands(workspace, 10), expected);
+        }
+
+        let pair = tree((1920, 1080), "splith", vec![window(10, 0, 0), window(11, 0, 0)]);
+        let workspace = find_focused_workspace(&pair).unwrap();
+        assert!(rotate_stack_commands(workspace, 10).is_empty());
+
+        // The swaps go out as one message
+        let config = test_config(&["--master-app", "firefox"]);
+        let master = focused(with_app_id(window(10, 0, 0), "firefox"));
+        let windows = vec![master, window(11, 0, 0), window(12, 0, 0), window(13, 0, 0)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        let mut state = DaemonState::default();
+        let reply = handle_control_command(&mut ipc, &config, &mut state, ControlCommand::Rotate);
+        assert_eq!(reply.unwrap(), "ok rotated 3 stack windows");
+        assert_eq!(ipc.commands, [expected.join("; ")]);
+    }
+
+    #[test]
+    fn swap_master_trades_places_with_the_top_of_the_stack() {
+        let config = test_config(&[]);
+        let mut state = DaemonState::default();
+        state.promoted.insert(10, 0.6);
+        let windows = vec![
+            focused(window(10, 960, 1080)),
+            window(11, 960, 540),
+            window(12, 960, 540),
+        ];
+        let stacked = tree((1920, 1080), "splith", windows);
+        let mut ipc = MockIpc::new(stacked.clone());
+        let reply = swap_master(&mut ipc, &stacked, &config, &mut state).unwrap();
+        assert_eq!(reply, "ok swapped master 10 with 11");
+        assert_eq!(ipc.commands[0], "[con_id=10] swap container with con_id 11");
+        assert_eq!(ipc.commands[1], "[con_id=11] resize set width 60 ppt");
+        assert_eq!(state.promoted.get(&11), Some(&0.6));
+
+        let single = tree((1920, 1080), "splith", vec![focused(window(10, 1920, 1080))]);
+        let mut ipc = MockIpc::new(single.clone());
+        let reply = swap_master(&mut ipc, &single, &config, &mut state).unwrap();
+        assert_eq!(reply, "ok no stack to swap with");
+        assert!(ipc.commands.is_empty());
+    }
+
+    #[test]
+    fn focus_master_picks_the_first_master_of_the_workspace() {
+        let config = test_config(&["--master-app", "firefox"]);
+        let firefox = |mut w: Value| {
+            w["app_id"] = "firefox".into();
+            w
+        };
+        let windows = vec![
+            focused(window(10, 640, 1080)),
+            firefox(window(11, 640, 1080)),
+            firefox(window(12, 640, 1080)),
+        ];
+        let with_master = tree((1920, 1080), "splith", windows);
+        let mut ipc = MockIpc::new(with_master.clone());
+        let state = DaemonState::default();
+        let reply = focus_master(&mut ipc, &with_master, &config, &state).unwrap();
+        assert_eq!(reply, "ok focused master 11");
+        assert_eq!(ipc.commands, ["[con_id=11] focus"]);
+
+        let plain = tree((1920, 1080), "splith", vec![focused(window(10, 1920, 1080))]);
+        let mut ipc = MockIpc::new(plain.clone());
+        let reply = focus_master(&mut ipc, &plain, &config, &state).unwrap();
+        assert_eq!(reply, "ok no master on this workspace");
+        assert!(ipc.commands.is_empty());
+    }
+
+    #[test]
+    fn gaps_are_left_out_of_aspect_ratios() {
+        // Three side by side children with 10px gaps: 1000x1000 without them
+        let children = vec![window(11, 0, 0), window(12, 0, 0), window(13, 0, 0)];
+        let square = container(20, "splith", 1030, 1010, children);
+        let tree = tree((1920, 1080), "splith", vec![square]);
+        let square = tree.find_as_ref(|n| n.id == 20).unwrap();
+        assert_eq!(calculate_aspect_ratio(square, AspectGeometry::Rect, 10), 1.0);
+        assert!(calculate_aspect_ratio(square, AspectGeometry::Rect, 0) > 1.0);
+    }
+
+    #[test]
+    fn content_geometry_leaves_out_the_title_bar() {
+        let mut titled = focused(window(10, 1200, 1100));
+        titled["deco_rect"] = json!({ "x": 0, "y": 0, "width": 1200, "height": 100 });
+        let titled = tree((1920, 1080), "splith", vec![titled]);
+        let node = titled.find_as_ref(|n| n.id == 10).unwrap();
+        assert_eq!(calculate_aspect_ratio(node, AspectGeometry::Content, 0), 1.2);
+        assert!(calculate_aspect_ratio(node, AspectGeometry::Rect, 0) < SPLIT_SWITCH_RATIO);
+        assert_eq!(gap_free_size(node, 10), (1190, 1090));
+
+        assert_eq!(decide(titled.clone(), &[]).0, Some("splitv"));
+        assert_eq!(decide(titled, &["--aspect-geometry", "content"]).0, Some("splith"));
+    }
+
+    #[test]
+    fn focus_in_a_floating_window_is_detected() {
+        let mut tree = tree((1920, 1080), "splith", vec![window(10, 960, 1080)]);
+        assert!(!is_focused_floating(&tree));
+
+        // A plain con inside the floating wrapper holds focus
+        let inner = focused(window(31, 400, 300));
+        let wrapper = container(30, "splith", 400, 300, vec![inner]);
+        let mut wrapper = fallback::minimal_tree(&wrapper).unwrap();
+        wrapper.node_type = NodeType::FloatingCon;
+        tree.nodes[0].nodes[0].floating_nodes.push(wrapper);
+        assert!(is_focused_floating(&tree));
+        let (split, commands) = decide(tree, &[]);
+        assert_eq!(split, None);
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn balance_is_scoped_to_the_window() {
+        let config = test_config(&[]);
+        let mut state = DaemonState::default();
+        let windows = vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        balance_siblings(&mut ipc, &config, &mut state, 10).unwrap();
+        assert_eq!(ipc.commands, ["[con_id=10] balance"]);
+        assert_eq!(state.stats.balances, 1);
+    }
+
+    #[test]
+    fn unknown_balance_disables_balancing() {
+        let config = test_config(&[]);
+        let mut state = DaemonState::default();
+        let windows = vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        ipc.unknown = vec!["balance"];
+        balance_siblings(&mut ipc, &config, &mut state, 10).unwrap();
+        assert!(state.balance_unsupported);
+        assert_eq!(state.stats.balances, 0);
+
+        // Nothing more is attempted, neither natively nor emulated
+        balance_siblings(&mut ipc, &config, &mut state, 11).unwrap();
+        assert_eq!(ipc.commands, ["[con_id=10] balance"]);
+    }
+
+    #[test]
+    fn balancing_follows_the_balance_workspaces() {
+        let balances_on_1 = |args: &[&str]| {
+            let config = test_config(args);
+            let windows = vec![focused(window(10, 960, 1080))];
+            let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+            workspace_config(&mut ipc, &config, 10).unwrap().enable_balance
+        };
+        assert!(balances_on_1(&[]));
+        assert!(balances_on_1(&["--balance-workspace", "1,2"]));
+        assert!(!balances_on_1(&["--balance-workspace", "2"]));
+        assert!(!balances_on_1(&["--no-balance-workspace", "1"]));
+    }
+
+    #[test]
+    fn workspace_focus_decides_only_on_managed_nonempty_workspaces() {
+        let focus_workspace = |windows: Vec<Value>, args: &[&str]| {
+            let config = test_config(args);
+            let mut state = DaemonState::default();
+            let tree = tree((1920, 1080), "splith", windows);
+            let workspace = tree.nodes[0].nodes[0].clone();
+            let mut ipc = MockIpc::new(tree);
+            handle_workspace_focus(&mut ipc, &config, &mut state, &workspace).unwrap();
+            ipc.commands
+        };
+        let windows = || vec![focused(window(10, 1600, 600))];
+        assert_eq!(focus_workspace(windows(), &[]), ["[con_id=10] splith"]);
+        assert!(focus_workspace(windows(), &["--workspace", "2"]).is_empty());
+        assert!(focus_workspace(vec![], &[]).is_empty());
+    }
+
+    #[test]
+    fn master_mark_makes_a_master_whatever_the_app() {
+        let config = test_config(&["--master-mark", "_master", "--master-percent", "0.7"]);
+        let mut marked = window(10, 960, 1080);
+        marked["marks"] = json!(["_master"]);
+        marked["app_id"] = json!("foot");
+        let mut other = window(11, 960, 1080);
+        other["app_id"] = json!("foot");
+        let tree = tree((1920, 1080), "splith", vec![marked, other]);
+        let node = |id| tree.find_as_ref(|n| n.id == id).unwrap();
+        assert_eq!(master_percent_for(&tree, node(10), &config), Some(0.7));
+        assert_eq!(master_percent_for(&tree, node(11), &config), None);
+    }
+
+    #[test]
+    fn square_and_degenerate_windows() {
+        let split = |width, height| {
+            // A wide container keeps the output tiebreak out of it
+            let windows = vec![focused(window(10, width, height))];
+            let container = container(20, "splith", 1920, 1080, windows);
+            decide(tree((1920, 1080), "splith", vec![container]), &[])
+        };
+        assert_eq!(split(800, 800), (Some("splitv"), vec!["[con_id=10] splitv".to_string()]));
+        assert_eq!(split(1100, 1000).0, Some("splitv"));
+        assert_eq!(split(1120, 1000).0, Some("splith"));
+        for (width, height) in [(800, 0), (0, 800), (0, 0)] {
+            assert_eq!(split(width, height), (None, vec![]));
+        }
+    }
+
+    #[test]
+    fn spiral_alternates_with_each_window() {
+        let spiral = |tree: Node| decide(tree, &["--layout", "spiral"]).0;
+        // Shapes don't matter: the first window goes side by side even when tall
+        let first = vec![focused(window(10, 600, 1080))];
+        assert_eq!(spiral(tree((1920, 1080), "splith", first)), Some("splith"));
+
+        let second = vec![window(10, 960, 1080), focused(window(11, 960, 1080))];
+        assert_eq!(spiral(tree((1920, 1080), "splith", second)), Some("splitv"));
+
+        let third = vec![window(11, 960, 540), focused(window(12, 960, 540))];
+        let nested = vec![window(10, 960, 1080), container(20, "splitv", 960, 1080, third)];
+        assert_eq!(spiral(tree((1920, 1080), "splith", nested)), Some("splith"));
+
+        let fourth = vec![window(12, 480, 540), focused(window(13, 480, 540))];
+        let third = vec![window(11, 960, 540), container(21, "splith", 960, 540, fourth)];
+        let nested = vec![window(10, 960, 1080), container(20, "splitv", 960, 1080, third)];
+        assert_eq!(spiral(tree((1920, 1080), "splith", nested)), Some("splitv"));
+    }
+
+    #[test]
+    fn max_columns_stacks_once_the_container_is_full() {
+        // Four columns that are each still wide enough for another one
+        let split = |args: &[&str]| {
+            let mut windows: Vec<Value> = (10..13).map(|id| window(id, 860, 600)).collect();
+            windows.push(focused(window(13, 860, 600)));
+            decide(tree((3440, 1440), "splith", windows), args).0
+        };
+        assert_eq!(split(&[]), Some("splith"));
+        assert_eq!(split(&["--max-columns", "5"]), Some("splith"));
+        assert_eq!(split(&["--max-columns", "4"]), Some("splitv"));
+    }
+
+    #[test]
+    fn app_split_wins_over_the_shape() {
+        let mut wide = focused(window(10, 1600, 600));
+        wide["app_id"] = json!("foot");
+        let tree = tree((1920, 1080), "splith", vec![wide]);
+        let (split, _) = decide(tree, &["--app-split", "Foot=splitv"]);
+        assert_eq!(split, Some("splitv"));
+
+        let err = parse_app_split("foot=sideways").unwrap_err();
+        assert!(err.contains("expected splith or splitv"), "{}", err);
+        let file = FileConfig::from_reader("[app_split]\nfoot = \"down\"".as_bytes());
+        assert!(format!("{:#}", file.unwrap_err()).contains("unknown variant"));
+    }
+
+    #[test]
+    fn the_dead_zone_straddles_the_switch_point() {
+        let keep = |ratio| split_with_threshold(Some("splitv"), ratio, 0.2, SplitMode::Square);
+        // Just inside SPLIT_SWITCH_RATIO ± 0.2 the current split stays
+        assert_eq!(keep(1.29), None);
+        assert_eq!(keep(SPLIT_SWITCH_RATIO), None);
+        assert_eq!(keep(0.91), None);
+        // Just outside it the shape decides again
+        assert_eq!(keep(1.31), Some("splith"));
+        assert_eq!(keep(0.89), Some("splitv"));
+        // Without a split to keep, or without a threshold, there is no dead zone
+        assert_eq!(split_with_threshold(None, 1.29, 0.2, SplitMode::Square), Some("splith"));
+        let exact = |ratio| split_with_threshold(Some("splitv"), ratio, 0.0, SplitMode::Square);
+        assert_eq!(exact(1.11), Some("splith"));
+        assert_eq!(exact(1.1), Some("splitv"));
+    }
+
+    #[test]
+    fn split_modes_pick_opposite_directions() {
+        assert_eq!(split_for_ratio(1.6, SplitMode::Square), "splith");
+        assert_eq!(split_for_ratio(0.6, SplitMode::Square), "splitv");
+        assert_eq!(split_for_ratio(1.6, SplitMode::Inverse), "splitv");
+        assert_eq!(split_for_ratio(0.6, SplitMode::Inverse), "splith");
+        // The dead zone holds in either mode
+        assert_eq!(split_with_threshold(Some("splith"), 1.05, 0.1, SplitMode::Inverse), None);
+
+        let tree = tree((1920, 1080), "splith", vec![focused(window(10, 600, 1000))]);
+        let (split, _) = decide(tree, &["--split-mode", "inverse"]);
+        assert_eq!(split, Some("splith"));
+    }
+
+    #[test]
+    fn delayed_balance_runs_once_for_a_burst() {
+        let config = test_config(&["--balance-delay-ms", "50"]);
+        let mut state = DaemonState::default();
+        let windows = vec![
+            window(10, 640, 1080),
+            window(11, 640, 1080),
+            focused(window(12, 640, 1080)),
+        ];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        for id in [11, 12] {
+            let opened = event("new", window(id, 640, 1080));
+            handle_window_event(&mut ipc, &config, &mut state, &opened);
+        }
+        assert!(balances(&ipc.commands).is_empty());
+
+        // Not due yet
+        maybe_run_pending_balance(&mut ipc, &config, &mut state).unwrap();
+        assert!(state.pending_balance.is_some());
+
+        state.pending_balance.as_mut().unwrap().due = Instant::now();
+        maybe_run_pending_balance(&mut ipc, &config, &mut state).unwrap();
+        assert_eq!(balances(&ipc.commands), ["[con_id=11] balance"]);
+        assert!(state.pending_balance.is_none());
+    }
+
+    #[test]
+    fn balance_waits_for_new_windows_to_settle_without_blocking() {
+        let config = test_config(&[]);
+        let mut state = DaemonState::default();
+        let windows = vec![
+            window(10, 640, 1080),
+            window(11, 640, 1080),
+            focused(window(12, 640, 1080)),
+        ];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        for id in [11, 12] {
+            let opened = event("new", window(id, 640, 1080));
+            handle_window_event(&mut ipc, &config, &mut state, &opened);
+        }
+        assert!(balances(&ipc.commands).is_empty());
+
+        // The first poll has nothing to compare with, the second one agrees
+        let run_when_due = |ipc: &mut MockIpc, state: &mut DaemonState| {
+            state.pending_balance.as_mut().unwrap().due = Instant::now();
+            maybe_run_pending_balance(ipc, &config, state).unwrap();
+        };
+        run_when_due(&mut ipc, &mut state);
+        assert!(balances(&ipc.commands).is_empty());
+        assert!(state.pending_balance.as_ref().unwrap().due > Instant::now());
+        run_when_due(&mut ipc, &mut state);
+        assert_eq!(balances(&ipc.commands), ["[con_id=11] balance"]);
+        assert!(state.pending_balance.is_none());
+    }
+
+    #[test]
+    fn queued_windows_excluded_since_are_left_alone() {
+        let config = test_config(&["--exclude-app", "foot", "--new-window-ratio", "0.6"]);
+        let windows = vec![window(10, 960, 1080), with_app_id(window(11, 960, 1080), "foot")];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        let mut state = DaemonState::default();
+        let due = Instant::now();
+        state.pending_balance = Some(PendingBalance { due, windows: vec![11], rects: None });
+        maybe_run_pending_balance(&mut ipc, &config, &mut state).unwrap();
+        assert!(ipc.commands.is_empty(), "{:?}", ipc.commands);
+        assert!(state.pending_balance.is_none());
+    }
+
+    #[test]
+    fn containers_of_no_balance_apps_stay_exempt_until_they_close() {
+        let config = test_config(&["--no-balance-app", "gimp"]);
+        let mut state = DaemonState::default();
+        let gimp = || {
+            let mut w = focused(window(10, 960, 540));
+            w["app_id"] = "gimp".into();
+            w
+        };
+        let column = container(20, "splitv", 960, 1080, vec![gimp(), window(11, 960, 540)]);
+        let windows = vec![column, window(12, 960, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+
+        handle_window_event(&mut ipc, &config, &mut state, &event("focus", gimp()));
+        assert!(state.balance_exempt.contains(&20));
+
+        // A sibling opening next to gimp leaves the container as it is
+        handle_window_event(&mut ipc, &config, &mut state, &event("new", window(11, 960, 540)));
+        assert!(balances(&ipc.commands).is_empty(), "{:?}", ipc.commands);
+
+        // Once the container is gone, so is its exemption
+        ipc.tree = tree((1920, 1080), "splith", vec![focused(window(12, 1920, 1080))]);
+        handle_window_event(&mut ipc, &config, &mut state, &event("close", gimp()));
+        assert!(state.balance_exempt.is_empty());
+    }
+
+    #[test]
+    fn floating_windows_are_laid_out_in_a_grid() {
+        let grid = |count: i64, rect: Value| {
+            let floating: Vec<Value> = (0..count)
+                .map(|i| {
+                    let mut w = window(100 + i, 300, 200);
+                    w["type"] = "floating_con".into();
+                    w
+                })
+                .collect();
+            let workspace = json!({
+                "id": 3, "type": "workspace", "rect": rect, "floating_nodes": floating,
+            });
+            float_grid_commands(&fallback::minimal_tree(&workspace).unwrap())
+        };
+        let landscape = json!({ "x": 0, "y": 0, "width": 1920, "height": 1080 });
+        let place = |id, w, h, x, y| {
+            let resize = format!("[con_id={}] resize set width {} px height {} px", id, w, h);
+            format!("{}, move absolute position {} px {} px", resize, x, y)
+        };
+
+        assert!(grid(1, landscape.clone()).is_empty());
+        assert_eq!(
+            grid(2, landscape.clone()),
+            [place(100, 960, 1080, 0, 0), place(101, 960, 1080, 960, 0)]
+        );
+        let four = [
+            place(100, 960, 540, 0, 0),
+            place(101, 960, 540, 960, 0),
+            place(102, 960, 540, 0, 540),
+            place(103, 960, 540, 960, 540),
+        ];
+        assert_eq!(grid(4, landscape.clone()), four);
+        // Past FLOAT_GRID_MAX the rest stay where they are
+        assert_eq!(grid(5, landscape), four);
+
+        // A portrait output on the right of another one
+        let portrait = json!({ "x": 1920, "y": 0, "width": 1080, "height": 1920 });
+        assert_eq!(
+            grid(3, portrait),
+            [
+                place(100, 540, 960, 1920, 0),
+                place(101, 540, 960, 2460, 0),
+                place(102, 540, 960, 1920, 960),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_window_shape_biases_its_first_split() {
+        // The window ended up tall, but mapped wide
+        let tall = || focused(window(10, 600, 1600));
+        let mut opened = tall();
+        opened["geometry"] = json!({ "x": 0, "y": 0, "width": 1600, "height": 400 });
+        let new_split = |args: &[&str]| {
+            let config = test_config(args);
+            let mut state = DaemonState::default();
+            let mut ipc = MockIpc::new(tree((1920, 1080), "splith", vec![tall()]));
+            handle_window_event(&mut ipc, &config, &mut state, &event("new", opened.clone()))
+        };
+        assert_eq!(new_split(&[]), Some("splitv"));
+        assert_eq!(new_split(&["--new-window-aspect-bias"]), Some("splith"));
+
+        // The hint is used for that one decision only
+        let config = test_config(&["--new-window-aspect-bias"]);
+        let mut state = DaemonState::default();
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", vec![tall()]));
+        let request = SplitRequest {
+            ratio_hint: Some(4.0),
+            ..SplitRequest::default()
+        };
+        let biased = update_split_direction(&mut ipc, &config, &mut state, request).unwrap();
+        assert_eq!(biased, Some("splith"));
+        let after = update_split_direction(&mut ipc, &config, &mut state, SplitRequest::default());
+        assert_eq!(after.unwrap(), Some("splitv"));
+    }
+
+    /// A window taking `share` of its container
+    fn sized(mut node: Value, share: f64) -> Value {
+        node["percent"] = share.into();
+        node
+    }
+
+    #[test]
+    fn every_focus_balances_only_in_that_mode() {
+        let lopsided = || {
+            let windows = vec![
+                focused(sized(window(10, 1536, 1080), 0.8)),
+                sized(window(11, 384, 1080), 0.2),
+            ];
+            tree((1920, 1080), "splith", windows)
+        };
+        let focus = event("focus", window(10, 1536, 1080));
+        let focus_balances = |args: &[&str], events: usize| {
+            let config = test_config(args);
+            let mut state = DaemonState::default();
+            let mut ipc = MockIpc::new(lopsided());
+            for _ in 0..events {
+                handle_window_event(&mut ipc, &config, &mut state, &focus);
+            }
+            balances(&ipc.commands).len()
+        };
+        assert_eq!(focus_balances(&[], 1), 0);
+        assert_eq!(focus_balances(&["--balance-on-every-focus"], 1), 1);
+        // Rate limited: a burst of focus changes balances once
+        assert_eq!(focus_balances(&["--balance-on-every-focus"], 3), 1);
+        // Unmanaged workspaces are left alone
+        assert_eq!(focus_balances(&["--balance-on-every-focus", "-w", "2"], 1), 0);
+    }
+
+    #[test]
+    fn focus_balances_only_above_the_threshold() {
+        let row = |shares: [f64; 3]| {
+            let windows = vec![
+                focused(sized(window(10, 960, 1080), shares[0])),
+                sized(window(11, 480, 1080), shares[1]),
+                sized(window(12, 480, 1080), shares[2]),
+            ];
+            tree((1920, 1080), "splith", windows)
+        };
+        let imbalance = |shares| container_imbalance(&row(shares).nodes[0].nodes[0]);
+        assert_eq!(imbalance([0.5, 0.25, 0.25]), 2.0);
+        assert_eq!(imbalance([0.0, 0.5, 0.5]), f64::INFINITY);
+        let single = tree((1920, 1080), "splith", vec![sized(window(10, 1920, 1080), 1.0)]);
+        assert_eq!(container_imbalance(&single.nodes[0].nodes[0]), 1.0);
+
+        let balances_at = |shares| {
+            let config = test_config(&["--balance-threshold", "2"]);
+            let mut state = DaemonState::default();
+            let mut ipc = MockIpc::new(row(shares));
+            balance_on_focus(&mut ipc, &config, &mut state).unwrap();
+            balances(&ipc.commands).len()
+        };
+        // Exactly at the threshold still counts as even enough
+        assert_eq!(balances_at([0.5, 0.25, 0.25]), 0);
+        assert_eq!(balances_at([0.5, 0.26, 0.24]), 1);
+    }
+
+    #[test]
+    fn assignments_go_out_as_one_command() {
+        let config = test_config(&["--assign", "slack=chat:tabbed", "--assign", "firefox=2"]);
+        let slack = fallback::minimal_tree(&with_app_id(window(10, 960, 1080), "Slack")).unwrap();
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", vec![]));
+        assert!(apply_assignment(&mut ipc, &config, &slack).unwrap());
+        assert_eq!(
+            ipc.commands,
+            ["[con_id=10] move container to workspace chat; [con_id=10] layout tabbed"]
+        );
+
+        let foot = fallback::minimal_tree(&with_app_id(window(11, 960, 1080), "foot")).unwrap();
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", vec![]));
+        assert!(!apply_assignment(&mut ipc, &config, &foot).unwrap());
+        assert!(ipc.commands.is_empty());
+    }
+
+    #[test]
+    fn lopsided_containers_are_reported_and_fixed() {
+        let windows = vec![
+            focused(sized(window(10, 1536, 1080), 0.8)),
+            sized(window(11, 384, 1080), 0.2),
+        ];
+        let stacked = vec![sized(window(12, 384, 540), 0.5), sized(window(13, 384, 540), 0.5)];
+        let column = container(20, "splitv", 384, 1080, stacked);
+        let lopsided = tree((1920, 1080), "splith", windows);
+        let mut with_column = lopsided.clone();
+        with_column.nodes[0].nodes[0].nodes.push(fallback::minimal_tree(&column).unwrap());
+
+        let workspace = &lopsided.nodes[0].nodes[0];
+        assert_eq!(
+            even_out_commands(workspace),
+            ["[con_id=10] resize set width 50 ppt", "[con_id=11] resize set width 50 ppt"]
+        );
+        let column = &with_column.nodes[0].nodes[0].nodes[2];
+        assert_eq!(
+            even_out_commands(column),
+            ["[con_id=12] resize set height 50 ppt", "[con_id=13] resize set height 50 ppt"]
+        );
+        let report: Vec<i64> =
+            imbalance_report(&with_column.nodes[0].nodes[0]).iter().map(|(n, _)| n.id).collect();
+        assert_eq!(report, [3, 20]);
+
+        let config = test_config(&[]);
+        let mut ipc = MockIpc::new(with_column);
+        report_imbalance(&mut ipc, &config, false).unwrap();
+        assert!(ipc.commands.is_empty());
+        // Only the lopsided workspace is evened out, in one message
+        report_imbalance(&mut ipc, &config, true).unwrap();
+        assert_eq!(ipc.commands.len(), 1);
+        assert!(ipc.commands[0].starts_with("[con_id=10] resize set width"), "{:?}", ipc.commands);
+        assert!(!ipc.commands[0].contains("con_id=12"));
+    }
+
+    #[test]
+    fn file_rules_add_to_the_command_line() {
+        let file = FileConfig::from_reader(
+            r#"
+            [[rules]]
+            match = "class=firefox"
+            action = "master"
+
+            [[rules]]
+            match = "title=picture-in-picture"
+            action = "exclude"
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+        let config = test_config_with(&["--master-rule", "app_id=emacs"], file);
+        let rule = |s: &str| s.parse::<MatchRule>().unwrap();
+        assert_eq!(config.master_rules, [rule("app_id=emacs"), rule("class=firefox")]);
+        assert_eq!(config.exclude_rules, [rule("title=picture-in-picture")]);
+
+        let bad = r#"[[rules]]
+            match = "colour=red"
+            action = "master""#;
+        assert!(FileConfig::from_reader(bad.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn settling_needs_two_equal_reads_or_the_cap() {
+        let at = |width| {
+            let rect = json!({ "x": 0, "y": 0, "width": width, "height": 1080 });
+            Some(serde_json::from_value::<Rect>(rect).unwrap())
+        };
+        assert_eq!(settling(&[at(960)], 5), Settling::Polling);
+        assert_eq!(settling(&[at(960), at(960)], 5), Settling::Settled);
+        assert_eq!(settling(&[at(1920), at(960), at(960)], 5), Settling::Settled);
+        // Still moving, or not in the tree yet
+        assert_eq!(settling(&[at(1920), at(960)], 5), Settling::Polling);
+        assert_eq!(settling(&[None, None], 5), Settling::Polling);
+        assert_eq!(settling(&[at(1920), at(1200), at(960)], 3), Settling::GaveUp);
+        assert_eq!(settling(&[at(960)], 1), Settling::GaveUp);
+
+        let widths = [1920, 1200, 960, 960, 960];
+        let reads_until_stable = |max_wait| {
+            let mut reads = 0;
+            wait_until_stable(
+                || {
+                    let width = widths[reads.min(widths.len() - 1)];
+                    reads += 1;
+                    Ok(tree((1920, 1080), "splith", vec![window(10, width, 1080)]))
+                },
+                10,
+                max_wait,
+            )
+            .unwrap();
+            reads
+        };
+        assert_eq!(reads_until_stable(Duration::from_secs(1)), 4);
+        assert_eq!(reads_until_stable(SETTLE_POLL_INTERVAL), 2);
+        assert_eq!(reads_until_stable(Duration::ZERO), 1);
+    }
+
+    #[test]
+    fn waiting_returns_the_tree_once_it_stops_moving() {
+        let at = |width| tree((1920, 1080), "splith", vec![window(10, width, 1080)]);
+        let width = |tree: &Node| tree.find_as_ref(|n| n.id == 10).unwrap().rect.width;
+
+        let mut ipc = MockIpc::new(at(960));
+        ipc.upcoming = [1920, 1200, 960].iter().map(|&w| at(w)).collect();
+        let settled = wait_for_tree_stable(&mut ipc, 10, Duration::from_secs(1)).unwrap();
+        assert_eq!((width(&settled), ipc.reads), (960, 4));
+
+        // Still moving at the cap: the latest tree is used anyway
+        let mut ipc = MockIpc::new(at(960));
+        ipc.upcoming = (0..10).map(|i| at(1900 - 50 * i)).collect();
+        let moving = wait_for_tree_stable(&mut ipc, 10, 2 * SETTLE_POLL_INTERVAL).unwrap();
+        assert_eq!((width(&moving), ipc.reads), (1800, 3));
+
+        // A window that isn't in the tree yet doesn't count as settled
+        let mut ipc = MockIpc::new(at(960));
+        ipc.upcoming.push_back(tree((1920, 1080), "splith", vec![]));
+        wait_for_tree_stable(&mut ipc, 10, Duration::from_secs(1)).unwrap();
+        assert_eq!(ipc.reads, 3);
+    }
+
+    #[test]
+    fn focus_guard_skips_focus_no_binding_caused() {
+        let now = Instant::now();
+        let guard = Duration::from_millis(250);
+        assert!(!focus_is_user_driven(None, now, guard));
+        assert!(focus_is_user_driven(Some(now - guard), now, guard));
+        assert!(!focus_is_user_driven(Some(now - guard - Duration::from_millis(1)), now, guard));
+
+        let config = test_config(&["--focus-guard-ms", "60000"]);
+        let mut state = DaemonState::default();
+        let windows = vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        let focus = event("focus", window(10, 960, 1080));
+        // Focus moved by an application or the pointer
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), None);
+        assert!(ipc.commands.is_empty());
+
+        note_binding(&mut ipc, &config, &mut state, &binding("focus left")).unwrap();
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), Some("splitv"));
+        assert_eq!(ipc.commands, ["[con_id=10] splitv"]);
+    }
+
+    #[test]
+    fn crowded_workspaces_are_left_alone() {
+        let row = |count: i64| {
+            let mut windows = vec![focused(window(10, 960, 1080))];
+            windows.extend((11..10 + count).map(|id| window(id, 960, 1080)));
+            tree((1920, 1080), "splith", windows)
+        };
+        let config = test_config(&["--max-managed-windows", "3"]);
+        let mut state = DaemonState::default();
+        let focus = event("focus", window(10, 960, 1080));
+
+        let mut ipc = MockIpc::new(row(3));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), Some("splitv"));
+        let mut ipc = MockIpc::new(row(4));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), None);
+        assert!(ipc.commands.is_empty());
+        assert!(state.crowded_workspaces.contains(&3));
+        assert_eq!(state.stats.skipped, 1);
+
+        // Closing a window brings it back under management
+        let mut ipc = MockIpc::new(row(3));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), Some("splitv"));
+        assert!(state.crowded_workspaces.is_empty());
+    }
+
+    #[test]
+    fn the_loop_wakes_for_its_own_timers() {
+        assert_eq!(next_demo_layout(None), "splith");
+        assert_eq!(next_demo_layout(Some("splith")), "splitv");
+        assert_eq!(next_demo_layout(Some("splitv")), "splith");
+
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        let mut state = DaemonState::default();
+        assert_eq!(next_deadline(&test_config(&[]), &state, now), None);
+
+        let timers = ["--resweep-interval", "10", "--demo", "--demo-interval-ms", "3000"];
+        let config = test_config(&timers);
+        // Neither ran yet
+        assert_eq!(next_deadline(&config, &state, now), Some(Duration::ZERO));
+        state.last_resweep = Some(now - secs(4));
+        state.last_demo_flip = Some(now - secs(1));
+        assert_eq!(next_deadline(&config, &state, now), Some(secs(2)));
+        state.pending_balance =
+            Some(PendingBalance { due: now + secs(1), windows: vec![10], rects: None });
+        assert_eq!(next_deadline(&config, &state, now), Some(secs(1)));
+
+        // Paused, only the pending balance is waited for
+        let config = test_config(&["--resweep-interval", "10", "--pause-in-mode", "resize"]);
+        state.last_resweep = Some(now - secs(20));
+        state.current_mode = "resize".into();
+        assert_eq!(next_deadline(&config, &state, now), Some(secs(1)));
+    }
+
+    #[test]
+    fn only_windows_and_workspace_children_are_split() {
+        let wide = tree((1920, 1080), "splith", vec![window(10, 1600, 600)]);
+        let output = &wide.nodes[0];
+        let workspace = &output.nodes[0];
+        assert_eq!(split_target(&workspace.nodes[0]).map(|n| n.id), Some(10));
+        assert_eq!(split_target(workspace).map(|n| n.id), Some(10));
+        assert!(split_target(output).is_none());
+        assert!(split_target(&wide).is_none());
+
+        // A focused workspace decides for its first window
+        let mut focused_workspace = wide.clone();
+        focused_workspace.nodes[0].nodes[0].focused = true;
+        let (split, commands) = decide(focused_workspace, &[]);
+        assert_eq!(split, Some("splith"));
+        assert_eq!(commands, ["[con_id=10] splith"]);
+
+        // An empty workspace, its output or the root have nothing to split
+        let mut empty = tree((1920, 1080), "splith", vec![]);
+        empty.nodes[0].nodes[0].focused = true;
+        assert!(split_target(&empty.nodes[0].nodes[0]).is_none());
+        assert_eq!(decide(empty, &[]), (None, vec![]));
+        let mut focused_output = wide.clone();
+        focused_output.nodes[0].focused = true;
+        assert_eq!(decide(focused_output, &[]), (None, vec![]));
+        let mut focused_root = wide;
+        focused_root.focused = true;
+        assert_eq!(decide(focused_root, &[]), (None, vec![]));
+    }
+
+    #[test]
+    fn app_split_thresholds_override_the_global_one() {
+        let config = test_config(&[
+            "--split-threshold",
+            "0.1",
+            "--app-split-threshold",
+            "Foot=0.3, xterm=0.2",
+        ]);
+        let threshold = |app_id: Option<&str>, class: Option<&str>| {
+            let mut node = window(10, 960, 1080);
+            if let Some(app_id) = app_id {
+                node = with_app_id(node, app_id);
+            }
+            if let Some(class) = class {
+                node["window_properties"] = json!({ "class": class });
+            }
+            split_threshold_for(&fallback::minimal_tree(&node).unwrap(), &config)
+        };
+        assert_eq!(threshold(Some("foot"), None), 0.3);
+        assert_eq!(threshold(None, Some("XTerm")), 0.2);
+        // app_id is looked up before class
+        assert_eq!(threshold(Some("foot"), Some("xterm")), 0.3);
+        assert_eq!(threshold(Some("kitty"), Some("xterm")), 0.2);
+        assert_eq!(threshold(Some("kitty"), None), 0.1);
+        assert_eq!(threshold(None, None), 0.1);
+        assert!(parse_app_value("foot").is_err());
+        assert!(parse_app_value("foot=wide").is_err());
+    }
+
+    #[test]
+    fn every_flag_has_an_environment_variable() {
+        let command = Cli::command();
+        for arg in command.get_arguments() {
+            let id = arg.get_id().as_str();
+            if matches!(id, "help" | "version") {
+                continue;
+            }
+            let env = arg.get_env().and_then(|env| env.to_str());
+            assert!(env.is_some_and(|env| env.starts_with("AUTOTILE_")), "--{} has no env", id);
+        }
+
+        // No other test reads this variable, so setting it can't race them
+        let parse = |args: &[&str]| {
+            let argv = std::iter::once("autotiling-rs").chain(args.iter().copied());
+            Cli::parse_from(argv).record_sessions
+        };
+        std::env::set_var("AUTOTILE_RECORD_SESSIONS", "/tmp/from-env.jsonl");
+        let from_env = parse(&[]);
+        let from_flag = parse(&["--record-sessions", "/tmp/from-flag.jsonl"]);
+        std::env::remove_var("AUTOTILE_RECORD_SESSIONS");
+        assert_eq!(from_env, Some(PathBuf::from("/tmp/from-env.jsonl")));
+        assert_eq!(from_flag, Some(PathBuf::from("/tmp/from-flag.jsonl")));
+    }
+
+    #[test]
+    fn output_changes_correct_splits_for_the_new_shape() {
+        // The output was rotated to portrait: the window is tall now
+        let column = container(20, "splith", 1080, 1920, vec![focused(window(11, 1080, 1920))]);
+        let portrait = tree((1080, 1920), "splith", vec![column]);
+        let config = test_config(&[]);
+        let mut state = DaemonState::default();
+        let mut ipc = MockIpc::new(portrait.clone());
+        handle_output_change(&mut ipc, &config, &mut state).unwrap();
+        assert_eq!(ipc.commands.first().map(String::as_str), Some("[con_id=11] splitv"));
+        assert!(ipc.commands.iter().all(|c| c == "[con_id=11] splitv"), "{:?}", ipc.commands);
+
+        let config = test_config(&["--pause-in-mode", "resize"]);
+        let mut state = DaemonState { current_mode: "resize".into(), ..DaemonState::default() };
+        let mut ipc = MockIpc::new(portrait);
+        handle_output_change(&mut ipc, &config, &mut state).unwrap();
+        assert!(ipc.commands.is_empty());
+
+        // Conservative mode leaves the focused window's decision for later
+        let wide = || tree((1920, 1080), "splith", vec![focused(window(10, 1600, 600))]);
+        let mut ipc = MockIpc::new(wide());
+        handle_output_change(&mut ipc, &test_config(&[]), &mut DaemonState::default()).unwrap();
+        assert_eq!(ipc.commands, ["[con_id=10] splith"]);
+        let mut ipc = MockIpc::new(wide());
+        let config = test_config(&["--conservative"]);
+        handle_output_change(&mut ipc, &config, &mut DaemonState::default()).unwrap();
+        assert!(ipc.commands.is_empty(), "{:?}", ipc.commands);
+    }
+
+    #[test]
+    fn rotating_to_portrait_flips_the_splits_it_should() {
+        // Two rows, a larger one on top, each alone in a container split as its shape says
+        let rows = |output: (i32, i32)| {
+            let (width, height) = (output.0, output.1 / 3);
+            let tall = focused(window(11, width, 2 * height));
+            let top = container(20, "splith", width, 2 * height, vec![tall]);
+            let bottom = container(21, "splith", width, height, vec![window(12, width, height)]);
+            tree(output, "splitv", vec![top, bottom])
+        };
+        let config = test_config(&[]);
+        let landscape = rows((1920, 1080));
+        assert!(resweep_corrections(&landscape, &config).is_empty());
+        assert_eq!(decide(landscape, &[]).0, Some("splith"));
+
+        // 1080x1280 on top is tall now, 1080x640 below still wide
+        let mut state = DaemonState::default();
+        let mut ipc = MockIpc::new(rows((1080, 1920)));
+        handle_output_change(&mut ipc, &config, &mut state).unwrap();
+        assert_eq!(ipc.commands.first().map(String::as_str), Some("[con_id=11] splitv"));
+        assert!(ipc.commands.iter().all(|c| !c.contains("con_id=12")), "{:?}", ipc.commands);
+    }
+
+    #[test]
+    fn focus_on_another_output_ignores_the_dead_zone() {
+        // Within the dead zone, the current split is kept
+        let column = container(20, "splitv", 1300, 1000, vec![focused(window(11, 1300, 1000))]);
+        let squarish = tree((1920, 1080), "splith", vec![column]);
+        let config = test_config(&["--split-threshold", "0.3"]);
+        let decide_after = |last_output: &str| {
+            let mut state = DaemonState {
+                last_output: Some(last_output.into()),
+                ..DaemonState::default()
+            };
+            let mut ipc = MockIpc::new(squarish.clone());
+            let split = update_split_direction(&mut ipc, &config, &mut state, Default::default());
+            (split.unwrap(), state.last_output)
+        };
+        assert_eq!(decide_after("DP-1"), (None, Some("DP-1".into())));
+        assert_eq!(decide_after("HDMI-A-1"), (Some("splith"), Some("DP-1".into())));
+    }
+
+    #[test]
+    fn conservative_mode_only_acts_on_new_windows() {
+        let config = test_config(&["--conservative"]);
+        let mut state = DaemonState::default();
+        let windows = vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        let focus = event("focus", window(10, 960, 1080));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), None);
+        assert!(ipc.commands.is_empty());
+
+        let new = event("new", window(10, 960, 1080));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &new), Some("splitv"));
+        assert!(ipc.commands.iter().any(|c| c == "[con_id=10] splitv"), "{:?}", ipc.commands);
+    }
+
+    /// Two outputs side by side, each with one workspace of `windows`
+    fn two_outputs(left: Vec<Value>, right: Vec<Value>) -> Node {
+        let output = |id: i64, name: &str, x: i32, windows: Vec<Value>| {
+            let rect = json!({ "x": x, "y": 0, "width": 1920, "height": 1080 });
+            json!({
+                "id": id,
+                "type": "output",
+                "name": name,
+                "rect": rect,
+                "focus": [id + 1],
+                "nodes": [{
+                    "id": id + 1,
+                    "type": "workspace",
+                    "name": (id / 2).to_string(),
+                    "num": id / 2,
+                    "layout": "splith",
+                    "rect": rect,
+                    "focus": focus_of(&windows),
+                    "nodes": windows,
+                }],
+            })
+        };
+        let outputs = [output(2, "DP-1", 0, left), output(4, "HDMI-A-1", 1920, right)];
+        let focus = focus_of(&outputs);
+        let root = json!({ "id": 1, "type": "root", "focus": focus, "nodes": outputs });
+        fallback::minimal_tree(&root).unwrap()
+    }
+
+    #[test]
+    fn the_workspace_filter_follows_the_focused_node() {
+        let tree = || {
+            two_outputs(vec![window(10, 960, 1080)], vec![focused(window(20, 1600, 600))])
+        };
+        let config = test_config(&["--workspace", "2"]);
+        assert!(node_workspace_allowed(&tree(), &config, 20));
+        assert!(!node_workspace_allowed(&tree(), &config, 10));
+
+        // The focused window sits on workspace 2, whatever else is visible
+        assert_eq!(decide(tree(), &["--workspace", "2"]).0, Some("splith"));
+        assert_eq!(decide(tree(), &["--workspace", "1"]), (None, vec![]));
+    }
+
+    #[test]
+    fn every_output_keeps_its_own_master() {
+        let firefox = |id| with_app_id(window(id, 960, 1080), "firefox");
+        let config = test_config(&["--master-app", "firefox=0.6"]);
+        let mut state = DaemonState::default();
+        let column = || container(30, "splitv", 960, 1080, vec![firefox(11), window(12, 960, 540)]);
+        let tree = two_outputs(
+            vec![firefox(10), column()],
+            vec![window(20, 960, 1080), firefox(21)],
+        );
+        assert_eq!(output_masters(&tree, &config, &mut state), [(10, 0.6), (21, 0.6)]);
+        assert_eq!(state.output_masters["DP-1"].window, 10);
+        assert_eq!(state.output_masters["HDMI-A-1"].window, 21);
+
+        // A master chosen before stays while it qualifies, per output
+        state.output_masters.get_mut("DP-1").unwrap().window = 11;
+        assert_eq!(output_masters(&tree, &config, &mut state), [(11, 0.6), (21, 0.6)]);
+
+        // An output without a master app has no master
+        let tree = two_outputs(vec![firefox(10), column()], vec![window(20, 960, 1080)]);
+        assert_eq!(output_masters(&tree, &config, &mut state), [(11, 0.6)]);
+        assert!(!state.output_masters.contains_key("HDMI-A-1"));
+    }
+
+    #[test]
+    fn closing_a_master_follows_the_close_action() {
+        let firefox = |id| with_app_id(window(id, 960, 1080), "firefox");
+        // The master 10 has just closed, leaving 11 and 12 in the workspace
+        let remaining = vec![focused(window(11, 960, 1080)), window(12, 960, 1080)];
+        let after_close = tree((1920, 1080), "splith", remaining);
+        let closed = event("close", firefox(10));
+        let close_with = |args: &[&str]| {
+            let config = test_config(args);
+            let mut state = DaemonState::default();
+            let before = tree((1920, 1080), "splith", vec![firefox(10), window(11, 960, 1080)]);
+            output_masters(&before, &config, &mut state);
+            let mut ipc = MockIpc::new(after_close.clone());
+            handle_window_event(&mut ipc, &config, &mut state, &closed);
+            state
+        };
+
+        let promoted = close_with(&["--master-app", "firefox=0.6"]);
+        assert_eq!(promoted.promoted.get(&11), Some(&0.6));
+        assert_eq!(promoted.output_masters["DP-1"].window, 11);
+
+        let kept = close_with(&["--master-app", "firefox=0.6", "--on-master-close", "keep"]);
+        assert!(kept.promoted.is_empty());
+        assert!(kept.output_masters.values().all(|slot| slot.window != 11));
+
+        // The last window of its container leaves nobody to promote
+        let mut state = DaemonState::default();
+        let config = test_config(&["--master-app", "firefox=0.6"]);
+        let pair = tree((1920, 1080), "splith", vec![firefox(10), firefox(11)]);
+        output_masters(&pair, &config, &mut state);
+        let lone = tree((1920, 1080), "splith", vec![focused(window(11, 1920, 1080))]);
+        promote_master_successor(&lone, &mut state, 10);
+        assert!(state.promoted.is_empty());
+        assert!(state.output_masters.is_empty());
+    }
+
+    #[test]
+    fn masters_resized_earlier_in_a_batch_are_sized_again() {
+        let windows = vec![
+            focused(sized(with_app_id(window(10, 1152, 1080), "firefox"), 0.6)),
+            sized(window(11, 768, 1080), 0.4),
+        ];
+        let tree = tree((1920, 1080), "splith", windows);
+        let config = test_config(&["--master-app", "firefox=0.6"]);
+        // Already at its share, as far as the tree knows
+        assert!(master_stack_commands(&config, &tree, 10, 0.6).is_empty());
+        // But not once the batch evened out its container
+        let evened = HashSet::from([3]);
+        assert_eq!(
+            master_stack_commands_after(&config, &tree, 10, 0.6, &evened),
+            ["[con_id=10] resize set width 60 ppt"]
+        );
+        // Other containers keep the skip
+        let elsewhere = HashSet::from([30]);
+        assert!(master_stack_commands_after(&config, &tree, 10, 0.6, &elsewhere).is_empty());
+    }
+
+    #[test]
+    fn tidy_balances_split_containers_then_sizes_masters() {
+        let config = test_config(&["--master-app", "firefox=0.6"]);
+        let mut state = DaemonState::default();
+        let tabs = vec![window(12, 640, 1050), window(13, 640, 1050)];
+        let windows = vec![
+            focused(sized(with_app_id(window(10, 320, 1080), "firefox"), 0.2)),
+            sized(window(11, 960, 1080), 0.5),
+            sized(container(30, "tabbed", 640, 1080, tabs), 0.3),
+        ];
+        let row = tree((1920, 1080), "splith", windows);
+        let mut ipc = MockIpc::new(row.clone());
+        let reply = tidy_workspace(&mut ipc, &row, &config, &mut state).unwrap();
+        assert_eq!(reply, "ok balanced 1 containers, sized 1 masters");
+        assert_eq!(ipc.commands.len(), 1);
+
+        let commands: Vec<&str> = ipc.commands[0].split("; ").collect();
+        let master = master_stack_commands(&config, &row, 10, 0.6);
+        assert!(!master.is_empty());
+        let (balance, sizing) = commands.split_at(commands.len() - master.len());
+        let even = |id| format!("[con_id={}] resize set width 33 ppt", id);
+        assert_eq!(balance, [even(10), even(11), even(30)]);
+        assert_eq!(sizing, master);
+        // Nothing is resized inside the tabbed container
+        assert!(!ipc.commands[0].contains("con_id=12"));
+
+        // Nor when balancing is emulated for a window in it
+        let mut emulated = test_config(&[]);
+        emulated.features = Features::new(IpcFlavor::Sway, Some((1, 4)));
+        let mut ipc = MockIpc::new(row);
+        balance_siblings(&mut ipc, &emulated, &mut state, 12).unwrap();
+        assert!(ipc.commands.is_empty());
+
+        // A master already at its share is evened out too, so it is sized back
+        let windows = vec![
+            focused(sized(with_app_id(window(10, 1152, 1080), "firefox"), 0.6)),
+            sized(window(11, 768, 1080), 0.4),
+        ];
+        let at_share = tree((1920, 1080), "splith", windows);
+        let mut ipc = MockIpc::new(at_share.clone());
+        let reply = tidy_workspace(&mut ipc, &at_share, &config, &mut state).unwrap();
+        assert_eq!(reply, "ok balanced 1 containers, sized 1 masters");
+        assert!(ipc.commands[0].ends_with("[con_id=10] resize set width 60 ppt"));
+
+        // Without masters none are reported as sized
+        let mut ipc = MockIpc::new(at_share.clone());
+        let reply = tidy_workspace(&mut ipc, &at_share, &test_config(&[]), &mut state);
+        assert_eq!(reply.unwrap(), "ok balanced 1 containers, sized 0 masters");
+    }
+
+    #[test]
+    fn churn_backs_off_and_catches_up_after() {
+        let config = test_config(&["--churn-threshold", "2", "--churn-window-ms", "1000"]);
+        let mut state = DaemonState::default();
+        let now = Instant::now();
+        assert!(!record_churn(&config, &mut state, now));
+        assert!(!record_churn(&config, &mut state, now));
+        assert!(record_churn(&config, &mut state, now));
+        state.churn_balance_pending = true;
+
+        let windows = vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        maybe_end_churn(&mut ipc, &config, &mut state).unwrap();
+        assert!(state.churning);
+        assert!(ipc.commands.is_empty());
+
+        // Once the events fall out of the window, the skipped balance runs
+        let long_ago = now - Duration::from_millis(1500);
+        state.churn_events.iter_mut().for_each(|at| *at = long_ago);
+        maybe_end_churn(&mut ipc, &config, &mut state).unwrap();
+        assert!(!state.churning);
+        assert!(!state.churn_balance_pending);
+        assert_eq!(ipc.commands, ["[con_id=10] balance"]);
+
+        // Without a threshold churn is never tracked
+        let mut state = DaemonState::default();
+        let config = test_config(&[]);
+        assert!((0..10).all(|_| !record_churn(&config, &mut state, now)));
+    }
+
+    #[test]
+    fn frozen_containers_keep_their_split() {
+        let wide = || focused(window(11, 1600, 600));
+        let column = |child| container(20, "splitv", 1600, 600, vec![child]);
+        let decide_in = |workspace| decide(tree((1920, 1080), "splith", vec![workspace]), &[]);
+        assert_eq!(decide_in(column(wide())).0, Some("splith"));
+        assert_eq!(decide_in(marked(column(wide()), FREEZE_MARK)), (None, vec![]));
+        assert_eq!(decide_in(column(marked(wide(), FREEZE_MARK))), (None, vec![]));
+
+        // Only the window's own container counts, not one further up
+        let nested = container(30, "splith", 1600, 600, vec![column(wide())]);
+        let grandparent = tree((1920, 1080), "splith", vec![marked(nested, FREEZE_MARK)]);
+        let window = grandparent.find_as_ref(|n| n.id == 11).unwrap();
+        assert!(!is_frozen(&grandparent, window));
+    }
+
+    #[test]
+    fn marked_windows_opt_out_of_autotiling() {
+        let wide = || focused(window(11, 1600, 600));
+        let decide_on = |node| decide(tree((1920, 1080), "splith", vec![node]), &[]);
+        assert_eq!(decide_on(wide()).0, Some("splith"));
+        assert_eq!(decide_on(marked(wide(), NO_AUTOTILE_MARK)), (None, vec![]));
+        // Some other mark changes nothing
+        assert_eq!(decide_on(marked(wide(), "editor")).0, Some("splith"));
+
+        // A new window that arrives marked is left alone as well
+        let config = test_config(&[]);
+        let mut state = DaemonState::default();
+        let tree = tree((1920, 1080), "splith", vec![marked(wide(), NO_AUTOTILE_MARK)]);
+        let mut ipc = MockIpc::new(tree);
+        let new = event("new", marked(window(11, 1600, 600), NO_AUTOTILE_MARK));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &new), None);
+        assert!(ipc.commands.iter().all(|c| !c.contains("] split")), "{:?}", ipc.commands);
+    }
+
+    #[test]
+    fn master_sizing_follows_the_configured_triggers() {
+        let defaults = test_config(&[]);
+        assert!(is_master_trigger(&defaults, WindowChange::Close));
+        assert!(!is_master_trigger(&defaults, WindowChange::Move));
+        assert!(!is_master_trigger(&defaults, WindowChange::Floating));
+
+        let windows = vec![
+            focused(with_app_id(window(10, 960, 1080), "firefox")),
+            window(11, 960, 1080),
+        ];
+        let layout = tree((1920, 1080), "splith", windows);
+        let moved = event("move", window(11, 960, 1080));
+        let resizes_on_move = |triggers: &str| {
+            let args = ["--master-app", "firefox=0.6", "--master-triggers", triggers];
+            let config = test_config(&args);
+            let mut state = DaemonState::default();
+            let mut ipc = MockIpc::new(layout.clone());
+            handle_window_event(&mut ipc, &config, &mut state, &moved);
+            ipc.commands.iter().any(|c| c.contains("resize set"))
+        };
+        assert!(!resizes_on_move("focus,new,close"));
+        assert!(resizes_on_move("move"));
+        assert!(resizes_on_move("new,move"));
+    }
+
+    #[test]
+    fn only_the_first_window_of_an_app_per_workspace_is_master() {
+        let firefox = |id| with_app_id(window(id, 640, 1080), "firefox");
+        let tree = two_outputs(vec![firefox(12), firefox(13)], vec![firefox(11), firefox(14)]);
+        let master = |args: &[&str], id| {
+            let node = tree.find_as_ref(|n| n.id == id).unwrap();
+            master_percent_for(&tree, node, &test_config(args))
+        };
+        let first_only = ["--master-app", "firefox=0.6", "--master-first-window"];
+        // 11 is older, but on the other workspace
+        assert_eq!(master(&first_only, 12), Some(0.6));
+        assert_eq!(master(&first_only, 13), None);
+        assert_eq!(master(&first_only, 11), Some(0.6));
+        assert_eq!(master(&first_only, 14), None);
+        assert_eq!(master(&first_only[..2], 13), Some(0.6));
+    }
+
+    #[test]
+    fn new_masters_move_to_the_front_following_the_policy() {
+        let firefox = |id| with_app_id(window(id, 640, 1080), "firefox");
+        let opened = |existing: Value| {
+            let windows = vec![existing, window(11, 640, 1080), focused(firefox(12))];
+            tree((1920, 1080), "splith", windows)
+        };
+        let promote = |policy: &str, tree: Node| {
+            let config = test_config(&["--master-app", "firefox=0.6", "--master-on-open", policy]);
+            let mut state = DaemonState::default();
+            let mut ipc = MockIpc::new(tree);
+            promote_on_open(&mut ipc, &config, &mut state, 12).unwrap();
+            (ipc.commands, state.output_masters.get("DP-1").map(|slot| slot.window))
+        };
+
+        // Swapped to the front and sized in a single message
+        let (commands, master) = promote("swap", opened(firefox(10)));
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].starts_with("[con_id=12] swap container with con_id 10; "));
+        assert!(commands[0].contains("resize set"), "{:?}", commands);
+        assert_eq!(master, Some(12));
+
+        // An existing master stays under `keep`, a plain first window doesn't count
+        assert_eq!(promote("keep", opened(firefox(10))), (vec![], None));
+        let (commands, master) = promote("keep", opened(window(10, 640, 1080)));
+        assert!(commands[0].starts_with("[con_id=12] swap container with con_id 10"));
+        assert_eq!(master, Some(12));
+    }
+
+    #[test]
+    fn a_config_on_stdin_reaches_the_daemon_config() {
+        let argv = ["autotiling-rs", "--config", "-", "--master-percent", "0.55"];
+        let matches = Cli::command().get_matches_from(argv);
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        let path = cli.config.as_deref().unwrap();
+        let stdin = "enable_balance = false\nmaster_percent = 65\n[master_apps]\nEmacs = 0.6\n";
+        let file = FileConfig::load_or_read(path, stdin.as_bytes()).unwrap();
+        let config = build_config(&cli, &matches, file);
+        assert!(!config.enable_balance);
+        // The flag wins over the file
+        assert_eq!(config.master_percent, 0.55);
+        assert_eq!(config.master_apps["emacs"], 0.6);
+
+        let err = FileConfig::load_or_read(path, "master_precent = 65".as_bytes()).unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(err.starts_with("Failed to load config from stdin"), "{}", err);
+        assert!(err.contains("unknown field `master_precent`"), "{}", err);
+    }
+
+    #[test]
+    fn split_expressions_come_from_the_file_unless_given() {
+        let file = || {
+            FileConfig::from_reader(r#"split_expr = "splitv if count > 2 else keep""#.as_bytes())
+                .unwrap()
+        };
+        let from_file = test_config_with(&[], file()).split_expr.unwrap();
+        assert_eq!(from_file.to_string(), "splitv if count > 2 else keep");
+        let flag = ["--split-expr", "splith"];
+        assert_eq!(test_config_with(&flag, file()).split_expr.unwrap().to_string(), "splith");
+
+        let bad = FileConfig::from_reader(r#"split_expr = "splitv if""#.as_bytes());
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn command_limits_count_each_kind_separately() {
+        let config = test_config(&["--max-splits-per-sec", "2"]);
+        let mut state = DaemonState::default();
+        assert!(command_allowed(&config, &mut state, CommandKind::Split));
+        assert!(command_allowed(&config, &mut state, CommandKind::Split));
+        assert!(!command_allowed(&config, &mut state, CommandKind::Split));
+        // Balances have no limit of their own and don't use up the splits'
+        for _ in 0..5 {
+            assert!(command_allowed(&config, &mut state, CommandKind::Balance));
+        }
+
+        let config = test_config(&["--max-splits-per-sec", "2", "--max-balances-per-sec", "1"]);
+        assert!(command_allowed(&config, &mut state, CommandKind::Balance));
+        assert!(!command_allowed(&config, &mut state, CommandKind::Balance));
+        assert!(!command_allowed(&config, &mut state, CommandKind::Split));
+
+        // Commands older than the window no longer count
+        let past = Instant::now() - 2 * COMMAND_LIMIT_WINDOW;
+        for sent in state.commands_sent.values_mut() {
+            sent.iter_mut().for_each(|at| *at = past);
+        }
+        assert!(command_allowed(&config, &mut state, CommandKind::Split));
+        assert!(command_allowed(&config, &mut state, CommandKind::Balance));
+    }
+
+    #[test]
+    fn the_first_window_layout_applies_only_to_the_first_window() {
+        let config = test_config(&["--first-window-layout", "splitv"]);
+        let mut state = DaemonState::default();
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", vec![window(10, 1920, 1080)]));
+        assert!(apply_first_window_layout(&mut ipc, &config, &mut state, 10).unwrap());
+        assert_eq!(ipc.commands, ["[con_id=10] layout splitv"]);
+
+        // The second window on the workspace is split as usual
+        let windows = vec![window(10, 960, 1080), focused(window(11, 960, 1080))];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splitv", windows));
+        assert!(!apply_first_window_layout(&mut ipc, &config, &mut state, 11).unwrap());
+        assert!(ipc.commands.is_empty());
+        let new = event("new", window(11, 960, 1080));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &new), Some("splitv"));
+        assert!(ipc.commands.iter().all(|c| !c.contains("layout")), "{:?}", ipc.commands);
+
+        // Without the flag nothing is issued, even for the first window
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", vec![window(10, 1920, 1080)]));
+        assert!(!apply_first_window_layout(&mut ipc, &test_config(&[]), &mut state, 10).unwrap());
+        assert!(ipc.commands.is_empty());
+    }
+
+    #[test]
+    fn a_forced_split_is_used_once() {
+        let config = test_config(&[]);
+        let tall = || tree((1920, 1080), "splith", vec![focused(window(10, 960, 1080))]);
+        let mut state = DaemonState::default();
+        let mut ipc = MockIpc::new(tall());
+        let command = ControlCommand::NextSplit(Some("splith"));
+        assert_eq!(
+            handle_control_command(&mut ipc, &config, &mut state, command).unwrap(),
+            "ok next split splith"
+        );
+        assert_eq!(ipc.commands, ["[con_id=10] splith"]);
+        assert_eq!(state.forced_split, None);
+        // The next decision is automatic again
+        let focus = event("focus", window(10, 960, 1080));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), Some("splitv"));
+
+        // Left unused, a focus change drops it
+        state.forced_split = Some("splith");
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &focus), Some("splitv"));
+        assert_eq!(state.forced_split, None);
+
+        // While a new window may still use it
+        state.forced_split = Some("splith");
+        let mut ipc = MockIpc::new(tall());
+        let new = event("new", window(10, 960, 1080));
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &new), Some("splith"));
+        assert_eq!(state.forced_split, None);
+
+        // A throttled decision sends nothing, so the forced split waits
+        let config = test_config(&["--max-splits-per-sec", "1"]);
+        let mut state = DaemonState::default();
+        assert!(command_allowed(&config, &mut state, CommandKind::Split));
+        state.forced_split = Some("splith");
+        let (split, commands) = decide_with(&mut state, tall(), &config);
+        assert_eq!((split, commands), (None, vec![]));
+        assert_eq!(state.forced_split, Some("splith"));
+        state.commands_sent.clear();
+        assert_eq!(decide_with(&mut state, tall(), &config).0, Some("splith"));
+        assert_eq!(state.forced_split, None);
+    }
+
+    #[test]
+    fn a_new_window_costs_one_message() {
+        let args = ["--new-window-side", "before", "--new-window-ratio", "0.6"];
+        let row = || {
+            let windows = vec![window(10, 960, 1080), focused(window(11, 960, 1080))];
+            MockIpc::new(tree((1920, 1080), "splith", windows))
+        };
+        let new = event("new", window(11, 960, 1080));
+        let resize = "[con_id=11] resize set width 60 ppt";
+
+        let config = test_config(&[&args[..], &["--balance", "false"]].concat());
+        let mut state = DaemonState::default();
+        let mut ipc = row();
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &new), Some("splitv"));
+        assert_eq!(
+            ipc.commands,
+            [format!("[con_id=11] move left; [con_id=11] splitv; {}", resize)]
+        );
+
+        // A failing command is only logged; the decision stands
+        let mut ipc = row();
+        ipc.unknown.push("ppt");
+        assert_eq!(handle_window_event(&mut ipc, &config, &mut state, &new), Some("splitv"));
+        assert_eq!(ipc.commands.len(), 1);
+
+        // With balancing the sizing waits for the balance, then goes out at once
+        let config = test_config(&args);
+        let mut state = DaemonState::default();
+        let mut ipc = row();
+        handle_window_event(&mut ipc, &config, &mut state, &new);
+        assert_eq!(ipc.commands, ["[con_id=11] move left; [con_id=11] splitv"]);
+        for _ in 0..2 {
+            state.pending_balance.as_mut().unwrap().due = Instant::now();
+            maybe_run_pending_balance(&mut ipc, &config, &mut state).unwrap();
+        }
+        assert_eq!(ipc.commands[1..], ["[con_id=11] balance", resize]);
+    }
+
+    #[test]
+    fn new_windows_open_on_the_configured_side() {
+        let row = || {
+            let windows = vec![window(10, 960, 1080), focused(window(11, 960, 1080))];
+            tree((1920, 1080), "splith", windows)
+        };
+        let placed = |args: &[&str], tree: Node, id| {
+            let config = test_config(args);
+            let mut ipc = MockIpc::new(tree);
+            place_new_window(&mut ipc, &config, id).unwrap();
+            ipc.commands
+        };
+        // sway already opens new windows after the focused one
+        assert!(placed(&[], row(), 11).is_empty());
+        assert!(placed(&["--new-window-side", "after"], row(), 11).is_empty());
+        assert_eq!(placed(&["--new-window-side", "before"], row(), 11), ["[con_id=11] move left"]);
+
+        let windows = vec![window(10, 1920, 540), window(11, 1920, 540)];
+        let column = tree((1920, 1080), "splitv", windows);
+        assert_eq!(placed(&["--new-window-side", "before"], column, 11), ["[con_id=11] move up"]);
+        // The first child can't move further without leaving its container
+        assert!(placed(&["--new-window-side", "before"], row(), 10).is_empty());
+    }
+
+    fn marked(mut node: Value, mark: &str) -> Value {
+        node["marks"] = json!([mark]);
+        node
+    }
+
+    #[test]
+    fn resweep_corrects_only_drifted_splits() {
+        let tall = |id| window(id, 480, 1080);
+        let column = |id, layout, child| container(id, layout, 480, 1080, vec![child]);
+        let windows = vec![
+            // Drifted: a tall window in a horizontal split
+            column(20, "splith", focused(tall(11))),
+            // Already right
+            column(21, "splitv", tall(12)),
+            // Frozen and opted out, so left as they are
+            marked(column(22, "splith", tall(13)), FREEZE_MARK),
+            column(23, "splith", marked(tall(14), NO_AUTOTILE_MARK)),
+        ];
+        let tree = tree((1920, 1080), "splith", windows);
+        let config = test_config(&[]);
+        assert_eq!(
+            resweep_corrections(&tree, &config),
+            [SplitCorrection {
+                con_id: 11,
+                from: "splith",
+                to: "splitv"
+            }]
+        );
+        // Workspaces outside --workspace are never swept
+        assert!(resweep_corrections(&tree, &test_config(&["-w", "2"])).is_empty());
+
+        let mut state = DaemonState::default();
+        let mut ipc = MockIpc::new(tree.clone());
+        apply_split_corrections(&mut ipc, &config, &mut state, &tree, "resweep").unwrap();
+        assert_eq!(ipc.commands, ["[con_id=11] splitv"]);
+    }
+
+    #[test]
+    fn explain_reports_the_decision_without_commands() {
+        let config = test_config(&[]);
+        let tree = tree((1920, 1080), "splith", vec![focused(window(10, 1600, 600))]);
+        let mut ipc = MockIpc::new(tree);
+        let explanation = explain_decision(&mut ipc, &config).unwrap();
+        assert_eq!(explanation.con_id, 10);
+        assert_eq!(explanation.parent_layout, Some(NodeLayout::SplitH));
+        assert_eq!(explanation.decision, Some("splith"));
+        assert!(ipc.commands.is_empty());
+        let json: Value = serde_json::from_str(&explanation.to_json()).unwrap();
+        assert_eq!(json["decision"], "splith");
+        assert_eq!(json["parent_layout"], "splith");
+    }
+
+    #[test]
+    fn old_sway_emulates_balance() {
+        assert!(Features::new(IpcFlavor::Sway, Some((1, 9))).native_balance);
+        assert!(Features::new(IpcFlavor::Sway, None).native_balance);
+        assert!(!Features::new(IpcFlavor::Sway, Some((1, 4))).native_balance);
+        assert!(!Features::new(IpcFlavor::I3, Some((4, 23))).native_balance);
+
+        let mut config = test_config(&[]);
+        config.features = Features::new(IpcFlavor::Sway, Some((1, 4)));
+        let mut state = DaemonState::default();
+        let windows = vec![focused(window(10, 1280, 1080)), window(11, 640, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        balance_siblings(&mut ipc, &config, &mut state, 10).unwrap();
+        assert!(!ipc.commands.is_empty());
+        assert!(ipc.commands.iter().all(|c| c.contains("resize set")), "{:?}", ipc.commands);
+    }
+
+    #[test]
+    fn masters_match_app_id_or_class() {
+        let native = |mut w: Value| {
+            w["app_id"] = "Foot".into();
+            w
+        };
+        let x11 = |mut w: Value| {
+            w["window_properties"] = json!({ "class": "Firefox" });
+            w
+        };
+        let windows = vec![native(focused(window(10, 960, 1080))), x11(window(11, 960, 1080))];
+        let tree = tree((1920, 1080), "splith", windows);
+        let config = test_config(&["--master-app", "foot=0.55,firefox"]);
+        let share = |id| {
+            let node = tree.find_as_ref(|n| n.id == id).unwrap();
+            master_percent_for(&tree, node, &config)
+        };
+        assert_eq!(share(10), Some(0.55));
+        assert_eq!(share(11), Some(0.6));
+
+        let (split, commands) = decide(tree.clone(), &["--exclude-app", "foot"]);
+        assert_eq!(split, None);
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn master_apps_match_regardless_of_case_and_spacing() {
+        assert_eq!(normalize_app_name("  FireFox "), "firefox");
+        let windows = vec![
+            focused(with_app_id(window(10, 960, 1080), "firefox")),
+            {
+                let mut x11 = window(11, 960, 1080);
+                x11["window_properties"] = json!({ "class": " Steam" });
+                x11
+            },
+        ];
+        let tree = tree((1920, 1080), "splith", windows);
+        let share = |config: &AutoTileConfig, id| {
+            let node = tree.find_as_ref(|n| n.id == id).unwrap();
+            master_percent_for(&tree, node, config)
+        };
+
+        let config = test_config(&["--master-app", " Firefox ,STEAM=0.65"]);
+        assert_eq!(share(&config, 10), Some(0.6));
+        assert_eq!(share(&config, 11), Some(0.65));
+
+        // Keys of the configuration file are normalized the same way
+        let file = FileConfig::from_reader("[master_apps]\n\"Firefox \" = 0.55\n".as_bytes());
+        let config = test_config_with(&[], file.unwrap());
+        assert_eq!(share(&config, 10), Some(0.55));
+        assert_eq!(share(&config, 11), None);
+    }
+
+    #[test]
+    fn master_count_splits_the_master_region() {
+        let app = |mut w: Value, app: &str| {
+            w["app_id"] = app.into();
+            w
+        };
+        let windows = vec![
+            focused(app(window(10, 640, 1080), "firefox")),
+            app(window(11, 640, 1080), "firefox"),
+            app(window(12, 640, 1080), "foot"),
+        ];
+        let tree = tree((1920, 1080), "splith", windows);
+        let resizes = |args: &[&str]| {
+            let config = test_config(args);
+            let mut ipc = MockIpc::new(tree.clone());
+            apply_master_stack_layout(&mut ipc, &config, &tree, 10, 0.6).unwrap();
+            ipc.commands
+        };
+        assert_eq!(resizes(&["--master-app", "firefox"]), ["[con_id=10] resize set width 60 ppt"]);
+        assert_eq!(
+            resizes(&["--master-app", "firefox", "--master-count", "2"]),
+            ["[con_id=10] resize set width 30 ppt; [con_id=11] resize set width 30 ppt"]
+        );
+    }
+
+    #[test]
+    fn master_is_sized_along_the_split_axis() {
+        let config = test_config(&[]);
+        let windows = vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        let tree = tree((1920, 1080), "splith", windows);
+        let mut ipc = MockIpc::new(tree.clone());
+        apply_master_stack_layout(&mut ipc, &config, &tree, 10, 0.6).unwrap();
+        assert_eq!(ipc.commands, ["[con_id=10] resize set width 60 ppt"]);
+    }
+
+    #[test]
+    fn masters_are_resized_when_the_stack_changes() {
+        let config = test_config(&["--master-app", "firefox"]);
+        let master = |share: f64| {
+            let mut w = window(10, 960, 1080);
+            w["app_id"] = "firefox".into();
+            w["percent"] = share.into();
+            w
+        };
+        let resizes = |share: f64| {
+            // A stack window kept focus, as after closing another one
+            let windows = vec![master(share), focused(window(11, 960, 540)), window(12, 960, 540)];
+            let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+            let mut state = DaemonState::default();
+            reapply_master_layouts(&mut ipc, &config, &mut state).unwrap();
+            ipc.commands
+        };
+        assert_eq!(resizes(0.34), ["[con_id=10] resize set width 60 ppt"]);
+        assert!(resizes(0.6).is_empty());
+    }
+
+    #[test]
+    fn masters_keep_their_share_while_the_stack_has_focus() {
+        let config = test_config(&["--master-app", "firefox"]);
+        let windows = vec![
+            sized(with_app_id(window(10, 960, 1080), "firefox"), 0.5),
+            focused(sized(window(11, 960, 1080), 0.5)),
+        ];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        let mut state = DaemonState::default();
+        let master_resizes = |commands: &[String]| {
+            commands.iter().filter(|c| c.contains("[con_id=10] resize")).count()
+        };
+
+        // Focusing the stack leaves the master alone
+        handle_window_event(&mut ipc, &config, &mut state, &event("focus", window(11, 960, 1080)));
+        assert_eq!(master_resizes(&ipc.commands), 0, "{:?}", ipc.commands);
+
+        // Opening or closing stack windows sizes the master again
+        for change in ["new", "close"] {
+            ipc.commands.clear();
+            handle_window_event(&mut ipc, &config, &mut state, &event(change, window(12, 0, 0)));
+            assert!(ipc.commands.iter().any(|c| c == "[con_id=10] resize set width 60 ppt"));
+        }
+    }
+
+    #[test]
+    fn smart_gaps_follow_the_tiled_window_count() {
+        let config = test_config(&["--smart-gaps", "10"]);
+        let mut state = DaemonState::default();
+        let mut gaps = |windows: Vec<Value>| {
+            let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+            apply_smart_gaps(&mut ipc, &config, &mut state).unwrap();
+            ipc.commands
+        };
+        let one = || vec![focused(window(10, 1920, 1080))];
+        let two = || vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        assert_eq!(gaps(one()), ["[con_id=10] gaps inner current set 0"]);
+        assert!(gaps(one()).is_empty());
+        assert_eq!(gaps(two()), ["[con_id=10] gaps inner current set 10"]);
+        assert!(gaps(two()).is_empty());
+    }
+
+    #[test]
+    fn promote_and_demote_toggle_master_sizing() {
+        let config = test_config(&[]);
+        let mut state = DaemonState::default();
+        let windows = vec![focused(window(10, 960, 1080)), window(11, 960, 1080)];
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", windows));
+        let mut send = |ipc: &mut MockIpc, command| {
+            handle_control_command(ipc, &config, &mut state, command).unwrap()
+        };
+
+        assert_eq!(send(&mut ipc, ControlCommand::Promote), "ok promoted 10");
+        assert_eq!(ipc.commands, ["[con_id=10] resize set width 60 ppt"]);
+
+        ipc.commands.clear();
+        assert_eq!(send(&mut ipc, ControlCommand::Demote), "ok demoted 10");
+        assert_eq!(
+            ipc.commands,
+            ["[con_id=10] resize set width 50 ppt; [con_id=11] resize set width 50 ppt"]
+        );
+
+        ipc.commands.clear();
+        assert_eq!(send(&mut ipc, ControlCommand::Demote), "ok 10 was not promoted");
+        assert!(ipc.commands.is_empty());
+        assert!(state.promoted.is_empty());
+    }
+
+    #[test]
+    fn stats_report_counters_as_json() {
+        let config = test_config(&["--ipc-flavor", "sway"]);
+        let mut state = DaemonState::default();
+        state.stats.events = 3;
+        state.stats.record_split("splitv");
+        let mut ipc = MockIpc::new(tree((1920, 1080), "splith", vec![window(10, 1920, 1080)]));
+        let reply =
+            handle_control_command(&mut ipc, &config, &mut state, ControlCommand::Stats).unwrap();
+        let stats: Value = serde_json::from_str(reply.strip_prefix("ok ").unwrap()).unwrap();
+        assert_eq!(stats["events"], 3);
+        assert_eq!(stats["splits_v"], 1);
+        assert_eq!(stats["flavor"], "sway");
+        assert!(ipc.commands.is_empty());
+        // Answered without a look at the tree
+        assert_eq!(ipc.reads, 0);
+    }
+
+    #[test]
+    fn log_fields_keep_their_types_in_json() {
+        let pairs: &[(&str, log::kv::Value)] = &[
+            ("to_layout", log::kv::Value::from("splitv")),
+            ("aspect_ratio", log::kv::Value::from(0.5)),
+            ("depth", log::kv::Value::from(2)),
+        ];
+        let args = format_args!("decided");
+        let record = log::Record::builder().args(args).key_values(&pairs).build();
+        let mut fields = JsonFields(serde_json::Map::new());
+        record.key_values().visit(&mut fields).unwrap();
+        assert_eq!(
+            Value::Object(fields.0),
+            json!({ "to_layout": "splitv", "aspect_ratio": 0.5, "depth": 2 })
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! systemd service notifications.
+//!
+//! Under a `Type=notify` unit, systemd passes a datagram socket in
+//! `$NOTIFY_SOCKET` and waits for `READY=1` on it. With `WatchdogSec=` set it
+//! also exports `WATCHDOG_USEC` and expects `WATCHDOG=1` pings more often
+//! than that. Outside of systemd the variables are unset and nothing is sent.
+
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+/// Send `state` (e.g. `READY=1`) to systemd. A no-op when not started by it.
+pub fn notify(state: &str) -> Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) if !path.is_empty() => path,
+        _ => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound().context("Failed to create notify socket")?;
+    // A leading '@' names a socket in the abstract namespace
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        let addr =
+            SocketAddr::from_abstract_name(name).context("Invalid abstract NOTIFY_SOCKET")?;
+        socket.send_to_addr(state.as_bytes(), &addr)
+    } else {
+        socket.send_to(state.as_bytes(), &path)
+    }
+    .with_context(|| format!("Failed to notify systemd of {}", state))?;
+    Ok(())
+}
+
+/// Keeps the systemd watchdog fed
+#[derive(Debug)]
+pub struct Watchdog {
+    interval: Duration,
+    last_ping: Instant,
+}
+
+impl Watchdog {
+    /// The watchdog systemd asked for, if any. Pings go out at half its
+    /// timeout, so one late wakeup doesn't get the service killed.
+    pub fn from_env() -> Option<Self> {
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        // The variables may have been inherited by a child of the real service
+        if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|p| p.parse::<u32>().ok()) {
+            if pid != std::process::id() {
+                return None;
+            }
+        }
+        if usec == 0 {
+            return None;
+        }
+        Some(Watchdog {
+            interval: Duration::from_micros(usec) / 2,
+            last_ping: Instant::now(),
+        })
+    }
+
+    /// Longest the event loop may sleep without missing a ping
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Ping systemd if the last ping is an interval ago
+    pub fn maybe_ping(&mut self) -> Result<()> {
+        if self.last_ping.elapsed() < self.interval {
+            return Ok(());
+        }
+        self.last_ping = Instant::now();
+        notify("WATCHDOG=1")
+    }
+}
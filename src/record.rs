@@ -0,0 +1,170 @@
+//! Session recording: one JSON line per handled event so that real sessions
+//! can be replayed against the decision logic later.
+//!
+//! Each line has the shape
+//! `{"tree": <Node>, "event": <WindowEvent>, "decision": "splith" | null}`,
+//! where `tree` is the tree as it was *before* the event was handled.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+#[cfg(test)]
+use serde::Deserialize;
+use serde_json::json;
+use swayipc::{Node, WindowEvent};
+
+/// Rotate the recording once it grows past this many bytes
+const MAX_RECORD_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Appends `{tree, event, decision}` records to a file, keeping one rotated copy
+pub struct SessionRecorder {
+    path: PathBuf,
+    file: File,
+    written: u64,
+}
+
+impl SessionRecorder {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open recording {}", path.display()))?;
+        let written = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(SessionRecorder {
+            path: path.to_path_buf(),
+            file,
+            written,
+        })
+    }
+
+    /// Write one record, rotating to `<path>.1` first if the file is full
+    pub fn record(
+        &mut self,
+        tree: &Node,
+        event: &WindowEvent,
+        decision: Option<&str>,
+    ) -> Result<()> {
+        let mut line = record_line(tree, event, decision)?;
+        line.push('\n');
+
+        if self.written + line.len() as u64 > MAX_RECORD_BYTES {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .context("Failed to write recording")?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, &rotated).context("Failed to rotate recording")?;
+        self.file = File::create(&self.path).context("Failed to reopen recording")?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// Serialize a single record without the trailing newline
+pub fn record_line(tree: &Node, event: &WindowEvent, decision: Option<&str>) -> Result<String> {
+    let record = json!({
+        "tree": tree,
+        "event": event,
+        "decision": decision,
+    });
+    serde_json::to_string(&record).context("Failed to serialize record")
+}
+
+/// One record read back from a recording
+#[cfg(test)]
+#[derive(Debug, Deserialize)]
+pub struct Record {
+    pub tree: Node,
+    pub event: WindowEvent,
+    pub decision: Option<String>,
+}
+
+/// Parse a recording back into its records, for replaying it in tests
+#[cfg(test)]
+pub fn parse_records(text: &str) -> Result<Vec<Record>> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).with_context(|| format!("Invalid record on line {}", i + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fallback::minimal_tree;
+    use swayipc::WindowChange;
+
+    fn sample() -> (Node, WindowEvent) {
+        let window = json!({ "id": 10, "type": "con", "app_id": "foot" });
+        let tree = minimal_tree(&json!({ "id": 1, "type": "root", "nodes": [window] })).unwrap();
+        let event = json!({ "change": "new", "container": minimal_tree(&window).unwrap() });
+        (tree, serde_json::from_value(event).unwrap())
+    }
+
+    /// A fresh path in the temporary directory
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("autotiling-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn records_read_back_as_written() {
+        let path = temp_path("roundtrip.jsonl");
+        let (tree, event) = sample();
+        let mut recorder = SessionRecorder::open(&path).unwrap();
+        recorder.record(&tree, &event, Some("splitv")).unwrap();
+        recorder.record(&tree, &event, None).unwrap();
+
+        let records = parse_records(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].tree.nodes[0].id, 10);
+        assert_eq!(records[0].tree.nodes[0].app_id.as_deref(), Some("foot"));
+        assert_eq!(records[0].event.change, WindowChange::New);
+        assert_eq!(records[0].event.container.id, 10);
+        assert_eq!(records[0].decision.as_deref(), Some("splitv"));
+        assert_eq!(records[1].decision, None);
+        assert!(parse_records("{\"tree\": 1}").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recording_rotates_past_the_size_limit() {
+        let path = temp_path("rotate.jsonl");
+        let (tree, event) = sample();
+        let line_len = record_line(&tree, &event, None).unwrap().len() as u64 + 1;
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+
+        // A record that exactly fills the file still goes in
+        let mut recorder = SessionRecorder::open(&path).unwrap();
+        recorder.written = MAX_RECORD_BYTES - line_len;
+        recorder.record(&tree, &event, None).unwrap();
+        assert_eq!(recorder.written, MAX_RECORD_BYTES);
+        assert!(!Path::new(&rotated).exists());
+
+        // One byte more and the file is rotated first
+        let split_len = record_line(&tree, &event, Some("splith")).unwrap().len() as u64 + 1;
+        recorder.written = MAX_RECORD_BYTES - split_len + 1;
+        recorder.record(&tree, &event, Some("splith")).unwrap();
+        assert_eq!(recorder.written, split_len);
+        let kept = parse_records(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].decision.as_deref(), Some("splith"));
+        assert_eq!(parse_records(&fs::read_to_string(&rotated).unwrap()).unwrap().len(), 1);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+//! Window rules.
+//!
+//! Assignment rules send new windows of an app to a fixed workspace, optionally
+//! with a fixed layout there, like i3's `assign`. They are written
+//! `<app>=<workspace>[:<layout>]`, e.g. `firefox=2` or `slack=chat:tabbed`,
+//! where `<app>` is matched against class and app_id.
+//!
+//! Match rules combine several conditions with AND and decide which windows
+//! are masters or excluded from autotiling, see `MatchRule`.
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use swayipc::{Node, NodeType};
+
+use crate::{normalize_app_name, window_app_names};
+
+/// Layouts an assignment may put on the target workspace
+const ASSIGN_LAYOUTS: &[&str] = &["splith", "splitv", "tabbed", "stacking"];
+
+/// Move windows of `app` to `workspace`, optionally setting `layout` there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssignRule {
+    pub app: String,
+    pub workspace: String,
+    pub layout: Option<String>,
+}
+
+impl FromStr for AssignRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (app, target) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <app>=<workspace>[:<layout>], got '{}'", s))?;
+        let (workspace, layout) = match target.split_once(':') {
+            Some((ws, layout)) => (ws, Some(layout.trim())),
+            None => (target, None),
+        };
+
+        let app = normalize_app_name(app);
+        let workspace = workspace.trim();
+        if app.is_empty() || workspace.is_empty() {
+            return Err(format!("app and workspace must not be empty in '{}'", s));
+        }
+        if let Some(layout) = layout {
+            if !ASSIGN_LAYOUTS.contains(&layout) {
+                return Err(format!(
+                    "unknown layout '{}', expected one of {}",
+                    layout,
+                    ASSIGN_LAYOUTS.join(", ")
+                ));
+            }
+        }
+
+        Ok(AssignRule {
+            app,
+            workspace: workspace.to_string(),
+            layout: layout.map(str::to_string),
+        })
+    }
+}
+
+/// First rule matching the window's class or app_id
+pub fn find_assignment<'a>(node: &Node, rules: &'a [AssignRule]) -> Option<&'a AssignRule> {
+    let names = window_app_names(node);
+    rules.iter().find(|rule| names.contains(&rule.app))
+}
+
+/// Commands that carry out `rule` for the window `con_id`
+pub fn assignment_commands(rule: &AssignRule, con_id: i64) -> Vec<String> {
+    let mut commands = vec![format!(
+        "[con_id={}] move container to workspace {}",
+        con_id, rule.workspace
+    )];
+    if let Some(layout) = &rule.layout {
+        commands.push(format!("[con_id={}] layout {}", con_id, layout));
+    }
+    commands
+}
+
+/// One condition of a `MatchRule`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// Exact X11 class, case-insensitive
+    Class(String),
+    /// Exact Wayland app_id, case-insensitive
+    AppId(String),
+    /// Title contains this text, case-insensitive
+    Title(String),
+    /// Name of the output the window is on
+    Output(String),
+    /// Workspace name (or number) the window is on
+    Workspace(String),
+}
+
+/// Conditions that must all hold for a window, written as comma separated
+/// `key=value` pairs, e.g. `class=firefox,output=DP-1,title=YouTube`.
+/// Keys are `class`, `app_id`, `title`, `output` and `workspace`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MatchRule {
+    pub conditions: Vec<Condition>,
+}
+
+impl FromStr for MatchRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let conditions = s
+            .split(',')
+            .map(|part| {
+                let (key, value) = part
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected key=value, got '{}'", part.trim()))?;
+                let value = value.trim();
+                if value.is_empty() {
+                    return Err(format!("empty value for '{}'", key.trim()));
+                }
+                match key.trim() {
+                    "class" => Ok(Condition::Class(normalize_app_name(value))),
+                    "app_id" => Ok(Condition::AppId(normalize_app_name(value))),
+                    "title" => Ok(Condition::Title(value.to_lowercase())),
+                    "output" => Ok(Condition::Output(value.to_string())),
+                    "workspace" => Ok(Condition::Workspace(value.to_string())),
+                    other => Err(format!(
+                        "unknown key '{}', expected class, app_id, title, output or workspace",
+                        other
+                    )),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(MatchRule { conditions })
+    }
+}
+
+impl TryFrom<String> for MatchRule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Where a window lives, as needed to evaluate a `MatchRule`
+#[derive(Debug, Clone, Copy)]
+pub struct WindowContext<'a> {
+    pub window: &'a Node,
+    pub output: Option<&'a Node>,
+    pub workspace: Option<&'a Node>,
+}
+
+impl<'a> WindowContext<'a> {
+    /// Locate `window` in `tree` and record its output and workspace
+    pub fn locate(tree: &'a Node, window: &'a Node) -> Self {
+        let path = crate::ancestors(tree, window.id);
+        let find = |node_type| path.iter().rev().find(|n| n.node_type == node_type).copied();
+        WindowContext {
+            window,
+            output: find(NodeType::Output),
+            workspace: find(NodeType::Workspace),
+        }
+    }
+}
+
+impl MatchRule {
+    /// Whether every condition holds for the window
+    pub fn matches(&self, ctx: &WindowContext) -> bool {
+        self.conditions.iter().all(|cond| condition_matches(cond, ctx))
+    }
+}
+
+fn condition_matches(cond: &Condition, ctx: &WindowContext) -> bool {
+    let window = ctx.window;
+    match cond {
+        Condition::Class(class) => window
+            .window_properties
+            .as_ref()
+            .and_then(|p| p.class.as_deref())
+            .is_some_and(|c| normalize_app_name(c) == *class),
+        Condition::AppId(app_id) => window
+            .app_id
+            .as_deref()
+            .is_some_and(|a| normalize_app_name(a) == *app_id),
+        Condition::Title(text) => window
+            .name
+            .as_deref()
+            .is_some_and(|title| title.to_lowercase().contains(text.as_str())),
+        Condition::Output(name) => ctx
+            .output
+            .and_then(|o| o.name.as_deref())
+            .is_some_and(|o| o == name),
+        Condition::Workspace(name) => ctx.workspace.is_some_and(|ws| {
+            ws.name.as_deref() == Some(name.as_str())
+                || ws.num.is_some_and(|num| num.to_string() == *name)
+        }),
+    }
+}
+
+/// Whether any of `rules` matches the window
+pub fn any_rule_matches(rules: &[MatchRule], ctx: &WindowContext) -> bool {
+    rules.iter().any(|rule| rule.matches(ctx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fallback::minimal_tree;
+    use serde_json::json;
+
+    /// A firefox window titled "YouTube" on workspace 2 of DP-1
+    fn firefox() -> Node {
+        let window = json!({
+            "id": 10,
+            "type": "con",
+            "name": "YouTube - Mozilla Firefox",
+            "window_properties": { "class": "Firefox" },
+        });
+        let workspace = json!({
+            "id": 3,
+            "type": "workspace",
+            "name": "2",
+            "num": 2,
+            "nodes": [window],
+        });
+        let output = json!({ "id": 2, "type": "output", "name": "DP-1", "nodes": [workspace] });
+        minimal_tree(&json!({ "id": 1, "type": "root", "nodes": [output] })).unwrap()
+    }
+
+    fn matches(rule: &str) -> bool {
+        let tree = firefox();
+        let window = tree.find_as_ref(|n| n.id == 10).unwrap();
+        rule.parse::<MatchRule>().unwrap().matches(&WindowContext::locate(&tree, window))
+    }
+
+    #[test]
+    fn match_rules_parse_conditions() {
+        let rule: MatchRule = " class = Firefox ,title=YouTube,workspace=2".parse().unwrap();
+        assert_eq!(
+            rule.conditions,
+            [
+                Condition::Class("firefox".into()),
+                Condition::Title("youtube".into()),
+                Condition::Workspace("2".into()),
+            ]
+        );
+        for malformed in ["class", "class=", "colour=red", "class=firefox,"] {
+            assert!(malformed.parse::<MatchRule>().is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn match_rules_need_every_condition() {
+        assert!(matches("class=firefox"));
+        assert!(matches("title=youtube"));
+        assert!(matches("class=firefox,output=DP-1,workspace=2"));
+        assert!(!matches("app_id=firefox"));
+        assert!(!matches("class=firefox,output=HDMI-A-1"));
+        assert!(!matches("class=firefox,title=youtube,workspace=3"));
+    }
+
+    #[test]
+    fn assignments_parse_app_workspace_and_layout() {
+        let rule: AssignRule = " Slack = chat : tabbed".parse().unwrap();
+        assert_eq!(rule.app, "slack");
+        assert_eq!(rule.workspace, "chat");
+        assert_eq!(rule.layout.as_deref(), Some("tabbed"));
+        let rule: AssignRule = "firefox=2".parse().unwrap();
+        assert_eq!((rule.workspace.as_str(), rule.layout), ("2", None));
+
+        for malformed in ["firefox", "=2", "firefox=", "firefox=:tabbed", "firefox=2:grid"] {
+            assert!(malformed.parse::<AssignRule>().is_err(), "{}", malformed);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Session statistics, summarized in one line when the daemon exits and
+//! available at any time through the `stats` control command.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{Level, Log, Metadata, Record};
+use serde_json::json;
+
+/// Errors logged so far, counted by `CountingLogger`
+static ERRORS_LOGGED: AtomicU64 = AtomicU64::new(0);
+
+/// Wraps the real logger to count every error it is handed
+pub struct CountingLogger<L> {
+    pub inner: L,
+}
+
+impl<L: Log> Log for CountingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Error {
+            ERRORS_LOGGED.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// What the daemon did during this session
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    started: Instant,
+    pub events: u64,
+    /// Events looked at but not acted on: paused, churning, crowded or
+    /// coalesced into a later focus event
+    pub skipped: u64,
+    pub splits_h: u64,
+    pub splits_v: u64,
+    pub balances: u64,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        SessionStats {
+            started: Instant::now(),
+            events: 0,
+            skipped: 0,
+            splits_h: 0,
+            splits_v: 0,
+            balances: 0,
+        }
+    }
+}
+
+impl SessionStats {
+    /// Count a split command by its direction
+    pub fn record_split(&mut self, layout: &str) {
+        match layout {
+            "splith" => self.splits_h += 1,
+            "splitv" => self.splits_v += 1,
+            _ => {}
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The counters as a JSON object, for the `stats` control command
+    pub fn to_json(&self, flavor: &str) -> String {
+        json!({
+            "events": self.events,
+            "skipped": self.skipped,
+            "splits": self.splits_h + self.splits_v,
+            "splits_h": self.splits_h,
+            "splits_v": self.splits_v,
+            "balances": self.balances,
+            "errors": ERRORS_LOGGED.load(Ordering::Relaxed),
+            "flavor": flavor,
+            "uptime_secs": self.uptime().as_secs(),
+        })
+        .to_string()
+    }
+
+    /// The one-line summary logged on exit
+    pub fn summary(&self) -> String {
+        format!(
+            "Session summary: {} events ({} skipped), {} splits ({} splith, {} splitv), \
+             {} balances, {} errors, up {}s",
+            self.events,
+            self.skipped,
+            self.splits_h + self.splits_v,
+            self.splits_h,
+            self.splits_v,
+            self.balances,
+            ERRORS_LOGGED.load(Ordering::Relaxed),
+            self.uptime().as_secs()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_counts_splits_by_direction() {
+        let mut stats = SessionStats {
+            events: 12,
+            skipped: 3,
+            balances: 2,
+            ..SessionStats::default()
+        };
+        stats.record_split("splith");
+        stats.record_split("splitv");
+        stats.record_split("splitv");
+        stats.record_split("tabbed");
+        // No test installs the counting logger, so no errors are counted
+        assert_eq!(
+            stats.summary(),
+            "Session summary: 12 events (3 skipped), 3 splits (1 splith, 2 splitv), \
+             2 balances, 0 errors, up 0s"
+        );
+    }
+}